@@ -0,0 +1,108 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// Files uploaded for use in builds, e.g. as file overlay contents, stored
+/// under their SHA-256 so identical uploads are kept once.
+pub struct BlobStore {
+    root: PathBuf,
+}
+
+/// A stored blob, as returned to the client that uploaded it.
+#[derive(Debug, Clone, Serialize)]
+pub struct BlobInfo {
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl BlobStore {
+    /// Opens the store in `root`, creating it if needed.
+    pub fn open(root: &Path) -> Result<Self> {
+        std::fs::create_dir_all(root)
+            .with_context(|| format!("Failed to create blob directory {}", root.display()))?;
+        Ok(Self { root: root.to_path_buf() })
+    }
+
+    /// Stores `data`, returning its hash and size.
+    pub async fn put(&self, data: &[u8]) -> Result<BlobInfo> {
+        let sha256 = hex::encode(Sha256::digest(data));
+        let destination = self.path_of(&sha256);
+        if fs::try_exists(&destination).await? {
+            // Uploading again restarts the blob's retention period.
+            let path = destination.clone();
+            tokio::task::spawn_blocking(move || {
+                std::fs::File::options().append(true).open(&path)?.set_modified(SystemTime::now())
+            })
+            .await?
+            .with_context(|| format!("Failed to refresh {}", destination.display()))?;
+            return Ok(BlobInfo { sha256, size: data.len() as u64 });
+        }
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        // Write next to the destination first so a build never reads a
+        // partially written blob.
+        let partial = destination.with_extension(format!("{}.partial", uuid::Uuid::new_v4()));
+        let mut file = fs::File::create(&partial).await
+            .with_context(|| format!("Failed to create {}", partial.display()))?;
+        file.write_all(data).await?;
+        file.sync_all().await?;
+        fs::rename(&partial, &destination).await?;
+
+        Ok(BlobInfo { sha256, size: data.len() as u64 })
+    }
+
+    /// Location of the blob `sha256`, if it has been uploaded.
+    pub async fn get(&self, sha256: &str) -> Result<Option<PathBuf>> {
+        if !is_sha256(sha256) {
+            return Ok(None);
+        }
+        let path = self.path_of(sha256);
+        Ok(fs::try_exists(&path).await?.then_some(path))
+    }
+
+    /// Removes the blobs last uploaded more than `max_age` ago, except those
+    /// in `keep`, along with abandoned partial uploads. Returns how many
+    /// files were removed.
+    pub async fn remove_older_than(&self, max_age: Duration, keep: HashSet<String>) -> Result<usize> {
+        let root = self.root.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut removed = 0;
+            for dir in std::fs::read_dir(&root)? {
+                let dir = dir?;
+                if !dir.file_type()?.is_dir() {
+                    continue;
+                }
+                for entry in std::fs::read_dir(dir.path())? {
+                    let entry = entry?;
+                    if keep.contains(&*entry.file_name().to_string_lossy()) {
+                        continue;
+                    }
+                    let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+                    if age > max_age {
+                        std::fs::remove_file(entry.path())
+                            .with_context(|| format!("Failed to remove {}", entry.path().display()))?;
+                        removed += 1;
+                    }
+                }
+            }
+            Ok(removed)
+        })
+        .await?
+    }
+
+    fn path_of(&self, sha256: &str) -> PathBuf {
+        self.root.join(&sha256[..2]).join(sha256)
+    }
+}
+
+/// Whether `value` is a lowercase hex-encoded SHA-256.
+fn is_sha256(value: &str) -> bool {
+    value.len() == 64 && value.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
@@ -0,0 +1,384 @@
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::process::Command as AsyncCommand;
+
+/// GRUB's BIOS platform files on the build host (`grub-pc-bin` on Debian).
+const GRUB_I386_PC_DIR: &str = "/usr/lib/grub/i386-pc";
+
+/// Modules available to the BIOS core image; it has to fit in memory below
+/// 1 MiB, so unlike the EFI image it does not carry every module.
+const BIOS_INSTALL_MODULES: &str = "linux normal iso9660 biosdisk memdisk tar ls configfile \
+    search search_label search_fs_file search_fs_uuid part_msdos part_gpt";
+
+/// Modules preloaded into the BIOS core image.
+const BIOS_PRELOAD_MODULES: &str = "linux normal iso9660 biosdisk search configfile";
+
+/// Modules available to the BIOS core image of disk images, which reads
+/// the kernel from an ext4 root partition.
+const DISK_BIOS_INSTALL_MODULES: &str = "linux normal ext2 biosdisk memdisk tar configfile \
+    search search_fs_uuid part_gpt serial terminal";
+
+/// Modules preloaded into the BIOS core image of disk images.
+const DISK_BIOS_PRELOAD_MODULES: &str = "linux normal ext2 biosdisk search part_gpt configfile";
+
+/// Where GRUB's boot sector keeps the first sector of the core image.
+const BOOT_SECTOR_KERNEL_SECTOR: usize = 0x5c;
+
+/// Jump in GRUB's boot sector that skips the workaround for BIOSes passing
+/// the wrong boot drive; disks replace it with two NOPs.
+const BOOT_SECTOR_DRIVE_CHECK: usize = 0x66;
+
+/// Bytes of the MBR before its disk signature and partition table.
+const MBR_CODE_SIZE: usize = 440;
+
+/// Segment the core image after its first sector is loaded to.
+const CORE_IMAGE_SEGMENT: u16 = 0x820;
+
+/// Paths of the boot files inside the ISO, relative to its root.
+pub const ISO_KERNEL_PATH: &str = "boot/vmlinuz";
+pub const ISO_INITRD_PATH: &str = "boot/initrd.img";
+pub const ISO_BIOS_IMAGE_PATH: &str = "boot/grub/i386-pc/eltorito.img";
+pub const ISO_EFI_IMAGE_PATH: &str = "boot/grub/efi.img";
+
+/// A kernel image installed in a root filesystem.
+#[derive(Debug, Clone)]
+pub struct KernelImage {
+    /// What follows `vmlinuz-` in the file name, usually the kernel release
+    /// (`linux` on Arch).
+    pub version: String,
+    /// Location relative to the root filesystem.
+    pub path: PathBuf,
+}
+
+/// Finds the newest kernel installed in `chroot_dir`.
+///
+/// Looks for `boot/vmlinuz-*` first and falls back to
+/// `usr/lib/modules/<release>/vmlinuz`, where kernel-install based distros
+/// keep the image when nothing copied it to `/boot`.
+pub async fn find_kernel(chroot_dir: &Path) -> Result<KernelImage> {
+    let mut kernels = Vec::new();
+
+    for name in list_dir(&chroot_dir.join("boot")).await? {
+        if let Some(version) = name.strip_prefix("vmlinuz-") {
+            kernels.push(KernelImage {
+                version: version.to_string(),
+                path: Path::new("boot").join(&name),
+            });
+        }
+    }
+
+    if kernels.is_empty() {
+        for release in list_dir(&chroot_dir.join("usr/lib/modules")).await? {
+            let path = Path::new("usr/lib/modules").join(&release).join("vmlinuz");
+            if chroot_dir.join(&path).is_file() {
+                kernels.push(KernelImage { version: release, path });
+            }
+        }
+    }
+
+    kernels
+        .into_iter()
+        .max_by(|a, b| compare_versions(&a.version, &b.version))
+        .ok_or_else(|| anyhow::anyhow!("No kernel found in the root filesystem"))
+}
+
+/// Finds the initramfs belonging to `kernel`, relative to `chroot_dir`.
+pub fn find_initrd(chroot_dir: &Path, kernel: &KernelImage) -> Result<PathBuf> {
+    let candidates = [
+        format!("boot/initrd.img-{}", kernel.version),
+        format!("boot/initramfs-{}.img", kernel.version),
+        format!("boot/initrd-{}", kernel.version),
+    ];
+
+    candidates
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| chroot_dir.join(path).is_file())
+        .ok_or_else(|| anyhow::anyhow!("No initramfs found for kernel {}", kernel.version))
+}
+
+/// File names in `dir`, or nothing if it does not exist.
+async fn list_dir(dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(names),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(name) = entry.file_name().to_str() {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// Compares kernel versions so that e.g. `6.10.0` sorts after `6.9.0`.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    version_key(a).cmp(&version_key(b))
+}
+
+/// Splits a version into runs of digits, each paired with the text that follows it.
+fn version_key(version: &str) -> Vec<(u64, String)> {
+    let mut key = Vec::new();
+    let mut rest = version;
+
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let number = rest[..digits].parse().unwrap_or(0);
+        rest = &rest[digits..];
+        let text = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        key.push((number, rest[..text].to_string()));
+        rest = &rest[text..];
+    }
+
+    key
+}
+
+/// ISO 9660 volume id for an image called `name`: at most 32 upper-case
+/// letters, digits and underscores. Boot loaders find the medium by this label.
+pub fn volume_id(name: &str) -> String {
+    let id: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .take(32)
+        .collect();
+
+    if id.trim_matches('_').is_empty() {
+        "LINUX_LIVE".to_string()
+    } else {
+        id
+    }
+}
+
+/// Menu shown by GRUB on the ISO in both BIOS and UEFI mode. GRUB and the
+/// kernel also talk to the first serial port, which boot tests watch.
+pub fn grub_config(title: &str, kernel_args: &str) -> String {
+    format!(
+        r#"set timeout=10
+set default=0
+
+insmod all_video
+serial --unit=0 --speed=115200
+terminal_input console serial
+terminal_output console serial
+
+menuentry "{title}" {{
+    linux /{kernel} {kernel_args} console=tty0 console=ttyS0,115200
+    initrd /{initrd}
+}}
+"#,
+        title = title.replace('"', "'"),
+        kernel = ISO_KERNEL_PATH,
+        kernel_args = kernel_args,
+        initrd = ISO_INITRD_PATH,
+    )
+}
+
+/// Menu embedded in the GRUB images of disk images. It finds the root
+/// partition by its filesystem UUID and boots the kernel installed there.
+pub fn disk_grub_config(title: &str, root_uuid: &str, kernel: &Path, initrd: &Path) -> String {
+    format!(
+        r#"set timeout=3
+set default=0
+
+search --no-floppy --fs-uuid --set=root {root_uuid}
+serial --unit=0 --speed=115200
+terminal_input console serial
+terminal_output console serial
+
+menuentry "{title}" {{
+    linux /{kernel} root=UUID={root_uuid} rw quiet console=tty0 console=ttyS0,115200
+    initrd /{initrd}
+}}
+"#,
+        title = title.replace('"', "'"),
+        root_uuid = root_uuid,
+        kernel = kernel.display(),
+        initrd = initrd.display(),
+    )
+}
+
+/// Config embedded in the standalone GRUB images. It locates the ISO by its
+/// volume id and hands over to the menu in `boot/grub/grub.cfg`.
+pub fn embedded_grub_config(volume_id: &str) -> String {
+    format!(
+        "search --no-floppy --set=root --label {}\nset prefix=($root)/boot/grub\nconfigfile ($root)/boot/grub/grub.cfg\n",
+        volume_id,
+    )
+}
+
+/// Builds the GRUB core image used for El Torito BIOS boot.
+pub fn bios_core_command(embedded_config: &Path, output: &Path) -> AsyncCommand {
+    let mut cmd = AsyncCommand::new("grub-mkstandalone");
+    cmd.args(["--format=i386-pc", "--locales=", "--fonts="])
+        .arg(format!("--install-modules={}", BIOS_INSTALL_MODULES))
+        .arg(format!("--modules={}", BIOS_PRELOAD_MODULES))
+        .arg("--output").arg(output)
+        .arg(format!("boot/grub/grub.cfg={}", embedded_config.display()));
+    cmd
+}
+
+/// Builds the GRUB core image embedded in the BIOS boot partition of a
+/// disk image.
+pub fn disk_bios_core_command(embedded_config: &Path, output: &Path) -> AsyncCommand {
+    let mut cmd = AsyncCommand::new("grub-mkstandalone");
+    cmd.args(["--format=i386-pc", "--locales=", "--fonts="])
+        .arg(format!("--install-modules={}", DISK_BIOS_INSTALL_MODULES))
+        .arg(format!("--modules={}", DISK_BIOS_PRELOAD_MODULES))
+        .arg("--output").arg(output)
+        .arg(format!("boot/grub/grub.cfg={}", embedded_config.display()));
+    cmd
+}
+
+/// Makes the GPT disk image `disk` boot in BIOS mode, as `grub-bios-setup`
+/// does: `core_image` goes into the BIOS boot partition starting at sector
+/// `partition_start`, and GRUB's boot sector, pointed at it, into the MBR.
+pub async fn install_bios_boot(disk: &Path, core_image: &Path, partition_start: u64, partition_sectors: u64) -> Result<()> {
+    let boot_sector_path = Path::new(GRUB_I386_PC_DIR).join("boot.img");
+    let mut boot_sector = fs::read(&boot_sector_path).await
+        .with_context(|| format!("Failed to read {}; is grub-pc-bin installed?", boot_sector_path.display()))?;
+    let mut core = fs::read(core_image).await?;
+    if boot_sector.len() < MBR_CODE_SIZE || core.len() < 512 {
+        return Err(anyhow::anyhow!("GRUB boot images are truncated"));
+    }
+
+    let core_sectors = core.len().div_ceil(512) as u64;
+    if core_sectors > partition_sectors {
+        return Err(anyhow::anyhow!(
+            "GRUB core image ({} sectors) does not fit the BIOS boot partition ({} sectors)",
+            core_sectors, partition_sectors,
+        ));
+    }
+    core.resize(core_sectors as usize * 512, 0);
+
+    // The first sector of the core image loads the rest from the block
+    // list at its end.
+    let block_list = 512 - 12;
+    core[block_list..block_list + 8].copy_from_slice(&(partition_start + 1).to_le_bytes());
+    core[block_list + 8..block_list + 10].copy_from_slice(&((core_sectors - 1) as u16).to_le_bytes());
+    core[block_list + 10..block_list + 12].copy_from_slice(&CORE_IMAGE_SEGMENT.to_le_bytes());
+
+    boot_sector[BOOT_SECTOR_KERNEL_SECTOR..BOOT_SECTOR_KERNEL_SECTOR + 8]
+        .copy_from_slice(&partition_start.to_le_bytes());
+    boot_sector[BOOT_SECTOR_DRIVE_CHECK] = 0x90;
+    boot_sector[BOOT_SECTOR_DRIVE_CHECK + 1] = 0x90;
+
+    let mut file = fs::OpenOptions::new().write(true).open(disk).await
+        .with_context(|| format!("Failed to open {}", disk.display()))?;
+    // Only the boot code: the partition table written by sfdisk stays.
+    file.write_all(&boot_sector[..MBR_CODE_SIZE]).await?;
+    file.seek(std::io::SeekFrom::Start(partition_start * 512)).await?;
+    file.write_all(&core).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// iPXE script of a netboot bundle. It boots the kernel and initramfs next
+/// to it; `kernel_args` may refer to `${base-url}`, the bundle's URL.
+pub fn ipxe_script(title: &str, kernel_args: &str) -> String {
+    format!(
+        "#!ipxe\n\
+         # {title}\n\
+         # Serve the bundle over HTTP and chain this script, or set base-url\n\
+         # to the URL of the bundle's directory, ending in /, beforehand.\n\
+         isset ${{base-url}} || set base-url ${{cwduri}}\n\
+         kernel ${{base-url}}vmlinuz initrd=initrd.img {kernel_args} console=tty0 console=ttyS0,115200\n\
+         initrd ${{base-url}}initrd.img\n\
+         boot\n",
+        title = title.replace('\n', " "),
+        kernel_args = kernel_args,
+    )
+}
+
+/// Prepends GRUB's CD boot sector to `core_image`, producing the El Torito
+/// boot image at `output`.
+pub async fn write_bios_image(core_image: &Path, output: &Path) -> Result<()> {
+    let cdboot_path = Path::new(GRUB_I386_PC_DIR).join("cdboot.img");
+    let mut image = fs::read(&cdboot_path).await
+        .with_context(|| format!("Failed to read {}; is grub-pc-bin installed?", cdboot_path.display()))?;
+    image.extend(fs::read(core_image).await?);
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::write(output, image).await
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+    Ok(())
+}
+
+/// Builds the standalone GRUB EFI binary.
+pub fn efi_binary_command(embedded_config: &Path, output: &Path) -> AsyncCommand {
+    let mut cmd = AsyncCommand::new("grub-mkstandalone");
+    cmd.args(["--format=x86_64-efi", "--locales=", "--fonts="])
+        .arg("--output").arg(output)
+        .arg(format!("boot/grub/grub.cfg={}", embedded_config.display()));
+    cmd
+}
+
+/// Commands that create the FAT image at `output` holding `efi_binary` as
+/// the removable-media boot loader `EFI/BOOT/BOOTX64.EFI`.
+///
+/// In reproducible builds the volume id and dates are fixed and the binary
+/// keeps its (clamped) modification time.
+pub async fn efi_image_commands(efi_binary: &Path, output: &Path, reproducible: bool) -> Result<Vec<AsyncCommand>> {
+    // Leave room for the FAT structures on top of the binary itself.
+    let binary_size = fs::metadata(efi_binary).await?.len();
+    let size_kib = binary_size / 1024 + 1024;
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let mut mkfs = AsyncCommand::new("mkfs.vfat");
+    if reproducible {
+        mkfs.arg("--invariant");
+    }
+    mkfs.arg("-C").arg(output).arg(size_kib.to_string());
+
+    let mut mmd = AsyncCommand::new("mmd");
+    mmd.arg("-i").arg(output).args(["::/EFI", "::/EFI/BOOT"]);
+
+    let mut mcopy = AsyncCommand::new("mcopy");
+    if reproducible {
+        mcopy.arg("-m");
+    }
+    mcopy.arg("-i").arg(output).arg(efi_binary).arg("::/EFI/BOOT/BOOTX64.EFI");
+
+    Ok(vec![mkfs, mmd, mcopy])
+}
+
+/// Creates a hybrid ISO from `iso_dir` that boots via El Torito in BIOS and
+/// UEFI mode, and from a USB stick through its MBR and EFI system partition.
+///
+/// With `source_date_epoch` the volume dates are pinned to it; xorriso
+/// takes file dates and the GPT GUIDs from `SOURCE_DATE_EPOCH` in the
+/// environment.
+pub fn xorriso_command(volume_id: &str, iso_dir: &Path, output: &Path, source_date_epoch: Option<u64>) -> AsyncCommand {
+    let mut cmd = AsyncCommand::new("xorriso");
+    cmd.args(["-as", "mkisofs", "-iso-level", "3", "-full-iso9660-filenames"])
+        .args(["-volid", volume_id])
+        .args(["-appid", "Linux ISO Creator"])
+        .args(["-publisher", "Linux ISO Creator"])
+        .args(["-preparer", "Linux ISO Creator"])
+        .args(["-eltorito-boot", ISO_BIOS_IMAGE_PATH])
+        .args(["-no-emul-boot", "-boot-load-size", "4", "-boot-info-table"])
+        .args(["--eltorito-catalog", "boot/grub/boot.cat"])
+        .arg("--grub2-boot-info")
+        .arg("--grub2-mbr").arg(Path::new(GRUB_I386_PC_DIR).join("boot_hybrid.img"))
+        .arg("-eltorito-alt-boot")
+        .args(["-e", ISO_EFI_IMAGE_PATH, "-no-emul-boot"])
+        .args(["-append_partition", "2", "0xef"]).arg(iso_dir.join(ISO_EFI_IMAGE_PATH));
+
+    if let Some(date) = source_date_epoch.and_then(|epoch| DateTime::from_timestamp(epoch as i64, 0)) {
+        // 16 digits: the date and time down to hundredths of a second.
+        cmd.arg(format!("--modification-date={}", date.format("%Y%m%d%H%M%S00")));
+    }
+
+    cmd.arg("-output").arg(output).arg(iso_dir);
+    cmd
+}
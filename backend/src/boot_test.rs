@@ -0,0 +1,157 @@
+use crate::models::*;
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::process::Command as AsyncCommand;
+
+/// Where distro packages install the OVMF firmware, in order of preference.
+const OVMF_PATHS: &[&str] = &[
+    "/usr/share/OVMF/OVMF_CODE_4M.fd",
+    "/usr/share/OVMF/OVMF_CODE.fd",
+    "/usr/share/ovmf/OVMF.fd",
+    "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+    "/usr/share/edk2/x64/OVMF_CODE.fd",
+    "/usr/share/qemu/ovmf-x86_64.bin",
+];
+
+/// Serial console output kept per test; earlier output is dropped.
+const MAX_TRANSCRIPT_BYTES: usize = 64 * 1024;
+
+/// Smallest guest QEMU is given, in MiB.
+const MIN_MEMORY_MB: u32 = 128;
+
+/// Most a build may ask for its boot tests, set by the server.
+#[derive(Debug, Clone, Copy)]
+pub struct BootTestLimits {
+    pub max_timeout_secs: u64,
+    pub max_memory_mb: u32,
+}
+
+/// Checks the boot test settings of a build against the server's `limits`
+/// before it is queued.
+pub fn validate(config: &BootTestConfig, limits: &BootTestLimits) -> Result<()> {
+    if config.success_pattern.is_empty() {
+        return Err(anyhow::anyhow!("Boot test success pattern is empty"));
+    }
+    if config.timeout_secs == 0 || config.timeout_secs > limits.max_timeout_secs {
+        return Err(anyhow::anyhow!(
+            "Boot test timeout must be from 1 to {} seconds", limits.max_timeout_secs,
+        ));
+    }
+    if !(MIN_MEMORY_MB..=limits.max_memory_mb).contains(&config.memory_mb) {
+        return Err(anyhow::anyhow!(
+            "Boot test memory must be from {} to {} MiB", MIN_MEMORY_MB, limits.max_memory_mb,
+        ));
+    }
+    Ok(())
+}
+
+/// Boots `iso_path` in QEMU under `firmware` and waits for the success
+/// pattern on the serial console.
+///
+/// QEMU runs with TCG, so no KVM access is needed, and is killed as soon as
+/// the pattern appears, the timeout expires or the future is dropped.
+pub async fn run_boot_test(iso_path: &Path, firmware: BootFirmware, config: &BootTestConfig) -> BootTestResult {
+    let started = Instant::now();
+    let mut transcript = Vec::new();
+    let outcome = boot(iso_path, firmware, config, &mut transcript).await;
+
+    BootTestResult {
+        firmware,
+        passed: outcome.is_ok(),
+        duration_secs: started.elapsed().as_secs(),
+        error: outcome.err().map(|e| format!("{:#}", e)),
+        transcript: String::from_utf8_lossy(&transcript).into_owned(),
+    }
+}
+
+async fn boot(iso_path: &Path, firmware: BootFirmware, config: &BootTestConfig, transcript: &mut Vec<u8>) -> Result<()> {
+    let pattern = config.success_pattern.as_bytes();
+    if pattern.is_empty() {
+        return Err(anyhow::anyhow!("Boot test success pattern is empty"));
+    }
+
+    let mut cmd = qemu_command(iso_path, firmware, config)?;
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let mut child = cmd.spawn().context("Failed to run qemu-system-x86_64")?;
+    let mut stdout = child.stdout.take().context("QEMU stdout not captured")?;
+    let stderr_task = child.stderr.take().map(|mut pipe| {
+        tokio::spawn(async move {
+            let mut stderr = String::new();
+            let _ = pipe.read_to_string(&mut stderr).await;
+            stderr
+        })
+    });
+
+    let watch = async {
+        let mut buf = [0u8; 4096];
+        loop {
+            let read = stdout.read(&mut buf).await?;
+            if read == 0 {
+                return Err(anyhow::anyhow!("QEMU exited before {:?} appeared", config.success_pattern));
+            }
+
+            // Only the new bytes, plus enough of the old ones for a match
+            // split across reads, need to be searched.
+            let search_from = transcript.len().saturating_sub(pattern.len() - 1);
+            transcript.extend_from_slice(&buf[..read]);
+            if transcript[search_from..].windows(pattern.len()).any(|window| window == pattern) {
+                return Ok(());
+            }
+
+            if transcript.len() > MAX_TRANSCRIPT_BYTES {
+                let excess = transcript.len() - MAX_TRANSCRIPT_BYTES;
+                transcript.drain(..excess);
+            }
+        }
+    };
+
+    let result = match tokio::time::timeout(Duration::from_secs(config.timeout_secs), watch).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow::anyhow!(
+            "Timed out after {}s waiting for {:?}", config.timeout_secs, config.success_pattern,
+        )),
+    };
+
+    let _ = child.kill().await;
+
+    if let Err(e) = result {
+        // QEMU's own errors, e.g. an unreadable firmware file, explain early exits.
+        if let Some(task) = stderr_task {
+            if let Ok(stderr) = task.await {
+                if !stderr.trim().is_empty() {
+                    return Err(e.context(stderr.trim().to_string()));
+                }
+            }
+        }
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+fn qemu_command(iso_path: &Path, firmware: BootFirmware, config: &BootTestConfig) -> Result<AsyncCommand> {
+    let mut cmd = AsyncCommand::new("qemu-system-x86_64");
+    cmd.args(["-accel", "tcg", "-cpu", "max", "-smp", "2"])
+        .arg("-m").arg(config.memory_mb.to_string())
+        .args(["-nodefaults", "-display", "none", "-serial", "stdio", "-no-reboot"])
+        .arg("-cdrom").arg(iso_path)
+        .args(["-boot", "d"]);
+
+    // SeaBIOS is QEMU's built-in default firmware.
+    if firmware == BootFirmware::Uefi {
+        let ovmf = OVMF_PATHS
+            .iter()
+            .find(|path| Path::new(path).is_file())
+            .ok_or_else(|| anyhow::anyhow!("OVMF firmware not found; is the ovmf package installed?"))?;
+        cmd.arg("-drive").arg(format!("if=pflash,format=raw,readonly=on,file={}", ovmf));
+    }
+
+    Ok(cmd)
+}
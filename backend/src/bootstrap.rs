@@ -0,0 +1,532 @@
+use crate::boot::KernelImage;
+use crate::models::*;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::process::Command as AsyncCommand;
+
+/// Package manager found inside a bootstrapped root filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageManager {
+    Apt,
+    Pacman,
+    Dnf,
+}
+
+impl PackageManager {
+    /// Path of the package manager binary relative to the root filesystem.
+    pub fn binary(&self) -> &'static str {
+        match self {
+            PackageManager::Apt => "usr/bin/apt-get",
+            PackageManager::Pacman => "usr/bin/pacman",
+            PackageManager::Dnf => "usr/bin/dnf",
+        }
+    }
+}
+
+impl std::fmt::Display for PackageManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            PackageManager::Apt => "apt",
+            PackageManager::Pacman => "pacman",
+            PackageManager::Dnf => "dnf",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Where a base system and its packages are fetched from, resolved from the
+/// distro template and any overrides in the `IsoConfig`.
+#[derive(Debug, Clone)]
+pub struct PackageSource {
+    pub release: String,
+    pub mirror: String,
+    pub components: Vec<String>,
+}
+
+impl PackageSource {
+    pub fn resolve(config: &IsoConfig, bootstrapper: &dyn Bootstrapper) -> Result<Self> {
+        let distro = &config.distro;
+        
+        let release = if distro.release.is_empty() {
+            bootstrapper.default_release()
+                .map(str::to_string)
+                .ok_or_else(|| anyhow::anyhow!("No release set for distro {}", distro.id))?
+        } else {
+            distro.release.clone()
+        };
+        
+        let mirror = config.mirror.clone()
+            .or_else(|| distro.mirror.clone())
+            .unwrap_or_else(|| bootstrapper.default_mirror().to_string());
+        let mirror = mirror.trim_end_matches('/').to_string();
+        
+        let mut components: Vec<String> = if distro.components.is_empty() {
+            bootstrapper.default_components().iter().map(|c| c.to_string()).collect()
+        } else {
+            distro.components.clone()
+        };
+        for component in &config.extra_components {
+            if !components.contains(component) {
+                components.push(component.clone());
+            }
+        }
+        
+        Ok(Self { release, mirror, components })
+    }
+}
+
+/// Everything needed to bootstrap a base system.
+#[derive(Debug, Default)]
+pub struct BootstrapPlan {
+    /// Files written before the commands run, as absolute paths on the host.
+    pub host_files: Vec<(PathBuf, String)>,
+    pub commands: Vec<AsyncCommand>,
+    /// Files written into the root filesystem once the commands succeeded,
+    /// relative to its root, so later package installs use the same source.
+    pub chroot_files: Vec<(PathBuf, String)>,
+}
+
+/// How a distro boots from the live ISO.
+#[derive(Debug, Clone, Copy)]
+pub struct LiveBoot {
+    /// Packages providing a kernel and an initramfs that can find the
+    /// squashfs on the ISO.
+    pub packages: &'static [&'static str],
+    /// Location of the squashfs image, relative to the root of the ISO.
+    pub squashfs_path: &'static str,
+    /// Kernel command line; `{label}` is replaced with the ISO volume id.
+    pub kernel_args: &'static str,
+    /// Kernel command line for network boot; `{base}` is replaced with the
+    /// URL the netboot bundle is served from, ending in `/`, `{squashfs}`
+    /// with `squashfs_path`, and `{iso}` with the ISO's file name for
+    /// initramfs that can only boot an ISO.
+    pub netboot_args: &'static str,
+}
+
+impl LiveBoot {
+    pub fn kernel_args(&self, volume_id: &str) -> String {
+        self.kernel_args.replace("{label}", volume_id)
+    }
+
+    pub fn netboot_args(&self, base_url: &str, iso_name: &str) -> String {
+        self.netboot_args
+            .replace("{base}", base_url)
+            .replace("{squashfs}", self.squashfs_path)
+            .replace("{iso}", iso_name)
+    }
+}
+
+/// A backend that knows how to create and populate a root filesystem for a
+/// family of distributions.
+///
+/// Implementations only describe the commands to run; `IsoBuilder` executes
+/// them so their output ends up in the job log.
+pub trait Bootstrapper: Send + Sync {
+    /// Human-readable backend name, used in log messages.
+    fn name(&self) -> &'static str;
+
+    /// Package manager this backend installs into the root filesystem.
+    fn package_manager(&self) -> PackageManager;
+
+    /// Mirror used when neither the template nor the config set one.
+    fn default_mirror(&self) -> &str;
+
+    /// Components used when the template does not list any.
+    fn default_components(&self) -> &[&'static str];
+
+    /// Release used when the template does not set one, for rolling distros.
+    fn default_release(&self) -> Option<&str> {
+        None
+    }
+
+    /// Describes how to create a minimal base system in `chroot_dir`.
+    /// `build_dir` may hold host-side files such as package manager configs.
+    fn bootstrap(&self, source: &PackageSource, build_dir: &Path, chroot_dir: &Path) -> Result<BootstrapPlan>;
+
+    /// Checks that the package manager is actually present in `chroot_dir`.
+    fn detect(&self, chroot_dir: &Path) -> bool {
+        chroot_dir.join(self.package_manager().binary()).exists()
+    }
+
+    /// Command run once before installing packages, e.g. to refresh indexes.
+    fn refresh_command(&self, _chroot_dir: &Path) -> Option<AsyncCommand> {
+        None
+    }
+
+    /// Command that installs `packages` inside `chroot_dir`.
+    fn install_command(&self, chroot_dir: &Path, packages: &[String]) -> AsyncCommand;
+
+    /// Kernel, live-boot packages and ISO layout for this distro family.
+    fn live_boot(&self) -> LiveBoot;
+
+    /// Command that rebuilds the initramfs of `kernel` with live-boot
+    /// support, for distros where installing the packages does not.
+    fn initramfs_command(&self, _chroot_dir: &Path, _kernel: &KernelImage) -> Option<AsyncCommand> {
+        None
+    }
+}
+
+/// Runs `args` inside `chroot_dir` via the host `chroot` binary.
+pub fn chroot_command(chroot_dir: &Path, args: &[&str]) -> AsyncCommand {
+    let mut cmd = AsyncCommand::new("chroot");
+    cmd.arg(chroot_dir).args(args);
+    cmd
+}
+
+/// Debian and Ubuntu via `debootstrap`.
+pub struct Debootstrap {
+    pub default_mirror: &'static str,
+    pub default_components: &'static [&'static str],
+    pub live_boot: LiveBoot,
+}
+
+impl Bootstrapper for Debootstrap {
+    fn name(&self) -> &'static str {
+        "debootstrap"
+    }
+
+    fn package_manager(&self) -> PackageManager {
+        PackageManager::Apt
+    }
+
+    fn default_mirror(&self) -> &str {
+        self.default_mirror
+    }
+
+    fn default_components(&self) -> &[&'static str] {
+        self.default_components
+    }
+
+    fn bootstrap(&self, source: &PackageSource, _build_dir: &Path, chroot_dir: &Path) -> Result<BootstrapPlan> {
+        let mut cmd = AsyncCommand::new("debootstrap");
+        cmd.args(["--arch=amd64", "--variant=minbase"])
+            .arg(format!("--components={}", source.components.join(",")))
+            .arg(&source.release)
+            .arg(chroot_dir)
+            .arg(&source.mirror);
+        
+        let sources_list = format!(
+            "deb {} {} {}\n", source.mirror, source.release, source.components.join(" "),
+        );
+        
+        Ok(BootstrapPlan {
+            host_files: Vec::new(),
+            commands: vec![cmd],
+            chroot_files: vec![(PathBuf::from("etc/apt/sources.list"), sources_list)],
+        })
+    }
+
+    fn refresh_command(&self, chroot_dir: &Path) -> Option<AsyncCommand> {
+        let mut cmd = chroot_command(chroot_dir, &["apt-get", "update"]);
+        cmd.env("DEBIAN_FRONTEND", "noninteractive");
+        Some(cmd)
+    }
+
+    fn install_command(&self, chroot_dir: &Path, packages: &[String]) -> AsyncCommand {
+        let mut cmd = chroot_command(chroot_dir, &["apt-get", "install", "-y"]);
+        cmd.args(packages).env("DEBIAN_FRONTEND", "noninteractive");
+        cmd
+    }
+
+    fn live_boot(&self) -> LiveBoot {
+        // live-boot and casper hook into update-initramfs, which their
+        // installation triggers, so no extra initramfs step is needed.
+        self.live_boot
+    }
+}
+
+/// Arch Linux via `pacstrap`.
+pub struct Pacstrap;
+
+impl Bootstrapper for Pacstrap {
+    fn name(&self) -> &'static str {
+        "pacstrap"
+    }
+
+    fn package_manager(&self) -> PackageManager {
+        PackageManager::Pacman
+    }
+
+    fn default_mirror(&self) -> &str {
+        "https://geo.mirror.pkgbuild.com"
+    }
+
+    fn default_components(&self) -> &[&'static str] {
+        &["core", "extra"]
+    }
+
+    fn default_release(&self) -> Option<&str> {
+        Some("latest")
+    }
+
+    fn bootstrap(&self, source: &PackageSource, build_dir: &Path, chroot_dir: &Path) -> Result<BootstrapPlan> {
+        let server = format!("Server = {}/$repo/os/$arch\n", source.mirror);
+        
+        // pacstrap reads repositories from the host's pacman.conf, so hand it
+        // one that only knows about the selected mirror and repositories.
+        let mut pacman_conf = String::from("[options]\nArchitecture = auto\nSigLevel = Required DatabaseOptional\n");
+        for repo in &source.components {
+            pacman_conf.push_str(&format!("\n[{}]\n{}", repo, server));
+        }
+        let pacman_conf_path = build_dir.join("pacman.conf");
+        
+        let mut cmd = AsyncCommand::new("pacstrap");
+        cmd.arg("-C").arg(&pacman_conf_path)
+            .args(["-M", "-K"])
+            .arg(chroot_dir)
+            .arg("base");
+        
+        Ok(BootstrapPlan {
+            host_files: vec![(pacman_conf_path, pacman_conf)],
+            commands: vec![cmd],
+            chroot_files: vec![(PathBuf::from("etc/pacman.d/mirrorlist"), server)],
+        })
+    }
+
+    fn install_command(&self, chroot_dir: &Path, packages: &[String]) -> AsyncCommand {
+        let mut cmd = chroot_command(chroot_dir, &["pacman", "-Sy", "--noconfirm", "--needed"]);
+        cmd.args(packages);
+        cmd
+    }
+
+    fn live_boot(&self) -> LiveBoot {
+        LiveBoot {
+            packages: &["linux", "mkinitcpio", "mkinitcpio-archiso"],
+            squashfs_path: "arch/x86_64/airootfs.sfs",
+            kernel_args: "archisobasedir=arch archisolabel={label}",
+            netboot_args: "archisobasedir=arch archiso_http_srv={base} ip=dhcp",
+        }
+    }
+
+    fn initramfs_command(&self, chroot_dir: &Path, kernel: &KernelImage) -> Option<AsyncCommand> {
+        // The default preset is host-specific and lacks the archiso hooks,
+        // including those that fetch the root filesystem over HTTP.
+        let kernel_path = format!("/{}", kernel.path.display());
+        let initramfs_path = format!("/boot/initramfs-{}.img", kernel.version);
+        Some(chroot_command(chroot_dir, &[
+            "mkinitcpio", "-k", &kernel_path, "-g", &initramfs_path, "-S", "autodetect",
+            "-A", "archiso,archiso_pxe_common,archiso_pxe_http",
+        ]))
+    }
+}
+
+/// Fedora and Enterprise Linux distributions via `dnf --installroot`.
+///
+/// `repo_url` and `gpg_key` are templates in which `{mirror}`, `{release}`
+/// and `{component}` are substituted.
+pub struct DnfInstallroot {
+    pub default_mirror: &'static str,
+    pub default_components: &'static [&'static str],
+    pub release_package: &'static str,
+    pub repo_url: &'static str,
+    pub gpg_key: &'static str,
+    /// Major releases whose key is named differently, with their key template.
+    pub release_gpg_keys: &'static [(&'static str, &'static str)],
+}
+
+impl DnfInstallroot {
+    /// Key template for `source`'s release, e.g. `8` or `8.10`.
+    fn gpg_key(&self, source: &PackageSource) -> &'static str {
+        let major = source.release.split('.').next().unwrap_or_default();
+        self.release_gpg_keys.iter()
+            .find(|(release, _)| *release == major)
+            .map_or(self.gpg_key, |(_, key)| key)
+    }
+
+    fn expand(template: &str, source: &PackageSource, component: &str) -> String {
+        template
+            .replace("{mirror}", &source.mirror)
+            .replace("{release}", &source.release)
+            .replace("{component}", component)
+    }
+}
+
+/// Prefix of the repository ids generated for dnf-based builds.
+const DNF_REPO_PREFIX: &str = "iso-builder";
+
+impl Bootstrapper for DnfInstallroot {
+    fn name(&self) -> &'static str {
+        "dnf"
+    }
+
+    fn package_manager(&self) -> PackageManager {
+        PackageManager::Dnf
+    }
+
+    fn default_mirror(&self) -> &str {
+        self.default_mirror
+    }
+
+    fn default_components(&self) -> &[&'static str] {
+        self.default_components
+    }
+
+    fn bootstrap(&self, source: &PackageSource, _build_dir: &Path, chroot_dir: &Path) -> Result<BootstrapPlan> {
+        let mut cmd = AsyncCommand::new("dnf");
+        cmd.args(["install", "-y"])
+            .arg(format!("--releasever={}", source.release))
+            .arg("--installroot")
+            .arg(chroot_dir)
+            .arg("--disablerepo=*");
+        for component in &source.components {
+            let repo_id = format!("{}-{}", DNF_REPO_PREFIX, component.to_lowercase());
+            cmd.arg(format!("--repofrompath={},{}", repo_id, Self::expand(self.repo_url, source, component)))
+                .arg(format!("--enablerepo={}", repo_id));
+        }
+        // The release's signing keys only exist once the release package is
+        // installed, so the bootstrap itself cannot verify signatures. The
+        // repo file written afterwards turns checks back on.
+        cmd.arg("--nogpgcheck")
+            .args([self.release_package, "coreutils", "dnf"]);
+        
+        let mut repo_file = String::new();
+        for component in &source.components {
+            repo_file.push_str(&format!(
+                "[{}-{}]\nname={} {}\nbaseurl={}\nenabled=1\ngpgcheck=1\ngpgkey={}\n\n",
+                DNF_REPO_PREFIX,
+                component.to_lowercase(),
+                component,
+                source.release,
+                Self::expand(self.repo_url, source, component),
+                Self::expand(self.gpg_key(source), source, component),
+            ));
+        }
+        
+        Ok(BootstrapPlan {
+            host_files: Vec::new(),
+            commands: vec![cmd],
+            chroot_files: vec![(PathBuf::from(format!("etc/yum.repos.d/{}.repo", DNF_REPO_PREFIX)), repo_file)],
+        })
+    }
+
+    fn install_command(&self, chroot_dir: &Path, packages: &[String]) -> AsyncCommand {
+        let mut cmd = chroot_command(chroot_dir, &["dnf", "install", "-y", "--disablerepo=*"]);
+        cmd.arg(format!("--enablerepo={}-*", DNF_REPO_PREFIX))
+            .args(packages);
+        cmd
+    }
+
+    fn live_boot(&self) -> LiveBoot {
+        LiveBoot {
+            packages: &["kernel", "dracut-live", "systemd"],
+            squashfs_path: "LiveOS/squashfs.img",
+            kernel_args: "root=live:CDLABEL={label} rd.live.image quiet",
+            netboot_args: "root=live:{base}{squashfs} rd.live.image ip=dhcp quiet",
+        }
+    }
+
+    fn initramfs_command(&self, chroot_dir: &Path, kernel: &KernelImage) -> Option<AsyncCommand> {
+        // The initramfs generated on install is host-only and cannot find
+        // a live root, so rebuild it generically with dmsquash-live, plus
+        // livenet for network boot.
+        let initramfs_path = format!("/boot/initramfs-{}.img", kernel.version);
+        Some(chroot_command(chroot_dir, &[
+            "dracut", "--force", "--no-hostonly", "--add", "dmsquash-live livenet", &initramfs_path, &kernel.version,
+        ]))
+    }
+}
+
+/// Chooses a `Bootstrapper` for a distro template.
+///
+/// Backends registered for a specific template id win over the backend
+/// registered for the template's category.
+#[derive(Default)]
+pub struct BootstrapRegistry {
+    by_template: HashMap<String, Arc<dyn Bootstrapper>>,
+    by_category: HashMap<DistroCategory, Arc<dyn Bootstrapper>>,
+}
+
+impl BootstrapRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry with the backends for every built-in distro category.
+    pub fn with_defaults() -> Self {
+        let mut registry = Self::new();
+        registry.register_category(DistroCategory::Ubuntu, Arc::new(Debootstrap {
+            default_mirror: "http://archive.ubuntu.com/ubuntu",
+            default_components: &["main", "restricted", "universe"],
+            live_boot: LiveBoot {
+                packages: &["linux-generic", "casper", "systemd-sysv"],
+                squashfs_path: "casper/filesystem.squashfs",
+                kernel_args: "boot=casper quiet splash",
+                // casper only fetches whole ISOs over the network.
+                netboot_args: "boot=casper ip=dhcp url={base}{iso} quiet",
+            },
+        }));
+        registry.register_category(DistroCategory::Debian, Arc::new(Debootstrap {
+            default_mirror: "http://deb.debian.org/debian",
+            default_components: &["main"],
+            live_boot: LiveBoot {
+                packages: &["linux-image-amd64", "live-boot", "systemd-sysv"],
+                squashfs_path: "live/filesystem.squashfs",
+                kernel_args: "boot=live components quiet splash",
+                netboot_args: "boot=live components ip=dhcp fetch={base}{squashfs} quiet",
+            },
+        }));
+        registry.register_category(DistroCategory::Arch, Arc::new(Pacstrap));
+        registry.register_category(DistroCategory::Fedora, Arc::new(DnfInstallroot {
+            default_mirror: "https://dl.fedoraproject.org/pub/fedora/linux",
+            default_components: &["Everything"],
+            release_package: "fedora-release",
+            repo_url: "{mirror}/releases/{release}/{component}/x86_64/os/",
+            gpg_key: "file:///etc/pki/rpm-gpg/RPM-GPG-KEY-fedora-{release}-primary",
+            release_gpg_keys: &[],
+        }));
+        registry.register_category(DistroCategory::Rocky, Arc::new(DnfInstallroot {
+            default_mirror: "https://dl.rockylinux.org/pub/rocky",
+            default_components: &["BaseOS", "AppStream"],
+            release_package: "rocky-release",
+            repo_url: "{mirror}/{release}/{component}/x86_64/os/",
+            gpg_key: "file:///etc/pki/rpm-gpg/RPM-GPG-KEY-Rocky-{release}",
+            // Rocky 8 predates the per-release key names.
+            release_gpg_keys: &[("8", "file:///etc/pki/rpm-gpg/RPM-GPG-KEY-rockyofficial")],
+        }));
+        registry
+    }
+
+    pub fn register_template(&mut self, template_id: impl Into<String>, backend: Arc<dyn Bootstrapper>) {
+        self.by_template.insert(template_id.into(), backend);
+    }
+
+    pub fn register_category(&mut self, category: DistroCategory, backend: Arc<dyn Bootstrapper>) {
+        self.by_category.insert(category, backend);
+    }
+
+    pub fn for_distro(&self, distro: &DistroTemplate) -> Result<Arc<dyn Bootstrapper>> {
+        self.by_template
+            .get(&distro.id)
+            .or_else(|| self.by_category.get(&distro.category))
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No bootstrap backend registered for distro {}", distro.id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distro(id: &str) -> DistroTemplate {
+        serde_json::from_value(serde_json::json!({
+            "id": id, "name": "Arch Linux", "description": "", "icon": "",
+            "category": "Arch", "default_packages": [], "desktop_environment": "",
+            "base_image": "archlinux:latest",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn template_backend_wins_over_category_backend() {
+        let category: Arc<dyn Bootstrapper> = Arc::new(Pacstrap);
+        let template: Arc<dyn Bootstrapper> = Arc::new(Pacstrap);
+        let mut registry = BootstrapRegistry::new();
+        registry.register_category(DistroCategory::Arch, category.clone());
+        registry.register_template("arch-custom", template.clone());
+
+        assert!(Arc::ptr_eq(&registry.for_distro(&distro("arch-custom")).unwrap(), &template));
+        assert!(Arc::ptr_eq(&registry.for_distro(&distro("arch")).unwrap(), &category));
+    }
+}
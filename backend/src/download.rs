@@ -0,0 +1,200 @@
+use crate::artifacts::ArtifactStore;
+use crate::models::BuildArtifact;
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use tracing::error;
+
+/// A byte range of an artifact; `end` is inclusive, as in `Content-Range`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RangeRequest {
+    Full,
+    Partial(ByteRange),
+    Unsatisfiable,
+}
+
+/// Interprets a `Range` header against an artifact of `size` bytes.
+///
+/// Only a single byte range is supported; anything else is answered with
+/// the full artifact, which RFC 9110 allows.
+fn parse_range(value: &str, size: u64) -> RangeRequest {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeRequest::Full;
+    };
+
+    if start.is_empty() {
+        // Suffix range: the last `end` bytes.
+        return match end.parse::<u64>() {
+            Ok(0) => RangeRequest::Unsatisfiable,
+            Ok(_) if size == 0 => RangeRequest::Unsatisfiable,
+            Ok(suffix) => RangeRequest::Partial(ByteRange {
+                start: size.saturating_sub(suffix),
+                end: size - 1,
+            }),
+            Err(_) => RangeRequest::Full,
+        };
+    }
+
+    let Ok(start) = start.parse::<u64>() else {
+        return RangeRequest::Full;
+    };
+    let end = if end.is_empty() {
+        u64::MAX
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) if end >= start => end,
+            _ => return RangeRequest::Full,
+        }
+    };
+
+    if start >= size {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Partial(ByteRange {
+        start,
+        end: end.min(size - 1),
+    })
+}
+
+/// Serves `artifact` from `store` as a download named `display_name`.
+///
+/// Supports `Range` and `If-Range` so interrupted downloads can resume,
+/// `If-None-Match` against a strong ETag derived from the artifact's SHA-256,
+/// and `HEAD` requests, which get the same headers without a body.
+pub async fn serve_artifact(
+    store: &dyn ArtifactStore,
+    artifact: &BuildArtifact,
+    display_name: &str,
+    content_type: &str,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let size = artifact.size;
+    let etag = (!artifact.sha256.is_empty()).then(|| format!("\"{}\"", artifact.sha256));
+    let header_value = |name: header::HeaderName| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let (Some(etag), Some(if_none_match)) = (&etag, header_value(header::IF_NONE_MATCH)) {
+        let matches = if_none_match
+            .split(',')
+            .map(|candidate| candidate.trim().trim_start_matches("W/"))
+            .any(|candidate| candidate == "*" || candidate == etag);
+        if matches {
+            return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag.clone())]).into_response());
+        }
+    }
+
+    // A range only applies to the representation the client already has
+    // part of; If-Range dates are not supported, so those get the full file.
+    let range_applies = match header_value(header::IF_RANGE) {
+        Some(if_range) => etag.as_deref() == Some(if_range.trim()),
+        None => true,
+    };
+    let range = match header_value(header::RANGE) {
+        Some(range) if range_applies => parse_range(range, size),
+        _ => RangeRequest::Full,
+    };
+
+    let mut response = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_DISPOSITION, content_disposition(&artifact.name, display_name));
+    if let Some(etag) = &etag {
+        response = response.header(header::ETAG, etag);
+    }
+
+    let (status, offset, len) = match range {
+        RangeRequest::Full => (StatusCode::OK, 0, size),
+        RangeRequest::Partial(ByteRange { start, end }) => {
+            response = response.header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size));
+            (StatusCode::PARTIAL_CONTENT, start, end - start + 1)
+        }
+        RangeRequest::Unsatisfiable => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+            ).into_response());
+        }
+    };
+    response = response
+        .status(status)
+        .header(header::CONTENT_LENGTH, len.to_string());
+
+    let body = if method == Method::HEAD {
+        Body::empty()
+    } else {
+        let stream = store.read_range(&artifact.key, offset, len).await.map_err(|e| {
+            error!("Failed to read artifact {}: {:#}", artifact.key, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read artifact".to_string())
+        })?;
+        Body::from_stream(stream)
+    };
+
+    response.body(body).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// `attachment` disposition naming the file `display_name`, with `fallback`
+/// for clients that do not understand RFC 5987 encoded names.
+fn content_disposition(fallback: &str, display_name: &str) -> String {
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback.replace(['"', '\\'], "_"),
+        utf8_percent_encode(display_name, NON_ALPHANUMERIC),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(start: u64, end: u64) -> RangeRequest {
+        RangeRequest::Partial(ByteRange { start, end })
+    }
+
+    #[test]
+    fn parses_bounded_and_open_ranges() {
+        assert_eq!(parse_range("bytes=0-499", 1000), partial(0, 499));
+        assert_eq!(parse_range("bytes=500-", 1000), partial(500, 999));
+        assert_eq!(parse_range(" bytes=10-10 ", 1000), partial(10, 10));
+    }
+
+    #[test]
+    fn clamps_ranges_to_the_artifact() {
+        assert_eq!(parse_range("bytes=900-2000", 1000), partial(900, 999));
+        assert_eq!(parse_range("bytes=-2000", 1000), partial(0, 999));
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(parse_range("bytes=-100", 1000), partial(900, 999));
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-1", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn rejects_ranges_past_the_end() {
+        assert_eq!(parse_range("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn serves_everything_for_unsupported_ranges() {
+        for value in ["items=0-1", "bytes=0-1,5-6", "bytes=5-1", "bytes=a-b", "bytes=5", "bytes=-x"] {
+            assert_eq!(parse_range(value, 1000), RangeRequest::Full, "{}", value);
+        }
+    }
+}
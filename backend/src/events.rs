@@ -0,0 +1,56 @@
+use crate::models::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// Number of events a slow subscriber may fall behind before it has to
+/// resync from the job store.
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Per-job broadcast channels that `IsoBuilder` publishes build events to.
+///
+/// Channels are created lazily by the first subscriber or publisher and are
+/// dropped once the job reaches a terminal state, which closes every
+/// subscriber's stream.
+#[derive(Clone, Default)]
+pub struct JobEvents {
+    channels: Arc<Mutex<HashMap<Uuid, broadcast::Sender<WebSocketMessage>>>>,
+}
+
+impl JobEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self, job_id: Uuid) -> broadcast::Receiver<WebSocketMessage> {
+        let mut channels = self.channels.lock().unwrap();
+        channels
+            .entry(job_id)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    pub fn publish(&self, job_id: Uuid, type_: MessageType, data: serde_json::Value) {
+        let channels = self.channels.lock().unwrap();
+        if let Some(sender) = channels.get(&job_id) {
+            // Having no subscribers is fine; late joiners replay from the job store.
+            let _ = sender.send(WebSocketMessage { job_id, type_, data });
+        }
+    }
+
+    /// Drops the job's channel so subscribers see the end of the stream.
+    pub fn close(&self, job_id: Uuid) {
+        self.channels.lock().unwrap().remove(&job_id);
+    }
+}
+
+/// Builds the `LogMessage` payload for the log entry at `index` in `BuildJob.logs`.
+pub fn log_payload(index: usize, log: &BuildLog) -> serde_json::Value {
+    serde_json::json!({
+        "index": index,
+        "timestamp": log.timestamp,
+        "level": log.level,
+        "message": log.message,
+    })
+}
@@ -0,0 +1,361 @@
+use crate::bootstrap::{chroot_command, PackageManager};
+use crate::models::{DistroCategory, KeyboardConfig, SystemConfig, UserConfig};
+use crate::ssh;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tokio::fs;
+use tokio::process::Command as AsyncCommand;
+
+/// Sudoers drop-in granting the configured users sudo.
+const SUDOERS_FILE: &str = "etc/sudoers.d/90-iso-builder-users";
+
+/// Checks `system` before a build is queued.
+pub fn validate(system: &SystemConfig) -> Result<()> {
+    if let Some(hostname) = &system.hostname {
+        validate_hostname(hostname)?;
+    }
+    if let Some(locale) = &system.locale {
+        if !is_valid_locale(locale) {
+            return Err(anyhow::anyhow!("Invalid locale {:?}, expected e.g. en_US.UTF-8", locale));
+        }
+    }
+    if let Some(timezone) = &system.timezone {
+        let valid = !timezone.is_empty()
+            && timezone.split('/').all(|part| {
+                !part.is_empty()
+                    && part != "."
+                    && part != ".."
+                    && part.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+'))
+            });
+        if !valid {
+            return Err(anyhow::anyhow!("Invalid timezone {:?}, expected e.g. Europe/Berlin", timezone));
+        }
+    }
+    if let Some(keyboard) = &system.keyboard {
+        for value in std::iter::once(&keyboard.layout).chain(keyboard.variant.as_ref()) {
+            let valid = !value.is_empty()
+                && value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'));
+            if !valid {
+                return Err(anyhow::anyhow!("Invalid keyboard layout or variant {:?}", value));
+            }
+        }
+    }
+
+    if let Some(policy) = &system.ssh {
+        ssh::validate_policy(policy)?;
+    }
+
+    let mut names = HashSet::new();
+    for user in &system.users {
+        validate_user(user)?;
+        if !names.insert(&user.name) {
+            return Err(anyhow::anyhow!("User {} is listed more than once", user.name));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `locale` looks like a locale name, e.g. `de`, `pt_BR` or
+/// `sr_RS.UTF-8@latin`.
+pub fn is_valid_locale(locale: &str) -> bool {
+    locale.len() <= 64
+        && locale.split(['_', '.', '@']).next().is_some_and(|language| {
+            (2..=3).contains(&language.len()) && language.bytes().all(|b| b.is_ascii_lowercase())
+        })
+        && locale.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '@' | '-'))
+}
+
+fn validate_hostname(hostname: &str) -> Result<()> {
+    let valid = !hostname.is_empty()
+        && hostname.len() <= 253
+        && hostname.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !valid {
+        return Err(anyhow::anyhow!("Invalid hostname {:?}", hostname));
+    }
+    Ok(())
+}
+
+fn validate_user(user: &UserConfig) -> Result<()> {
+    if !is_valid_name(&user.name) {
+        return Err(anyhow::anyhow!("Invalid user name {:?}", user.name));
+    }
+    if user.name == "root" {
+        return Err(anyhow::anyhow!("The root account cannot be configured as a user"));
+    }
+    if let Some(full_name) = &user.full_name {
+        if full_name.contains([':', ',', '\n']) {
+            return Err(anyhow::anyhow!("Full name of {} may not contain ':', ',' or newlines", user.name));
+        }
+    }
+    if let Some(hash) = &user.password_hash {
+        // Modular crypt format: $id$[params$]salt$hash
+        let valid = hash.starts_with('$')
+            && hash.split('$').count() >= 4
+            && !hash.contains(|c: char| c == ':' || c.is_whitespace() || c.is_control());
+        if !valid {
+            return Err(anyhow::anyhow!(
+                "Password of {} must be a crypt(3) hash such as the output of `mkpasswd -m sha-512`",
+                user.name,
+            ));
+        }
+    }
+    if let Some(shell) = &user.shell {
+        if !shell.starts_with('/') || shell.contains(|c: char| c == ':' || c.is_whitespace()) {
+            return Err(anyhow::anyhow!("Shell of {} must be an absolute path", user.name));
+        }
+    }
+    for group in &user.groups {
+        if !is_valid_name(group) {
+            return Err(anyhow::anyhow!("Invalid group {:?} for {}", group, user.name));
+        }
+    }
+    for (index, key) in user.authorized_keys.iter().enumerate() {
+        ssh::validate_public_key(key.trim())
+            .with_context(|| format!("Invalid SSH key {} for {}", index + 1, user.name))?;
+    }
+    Ok(())
+}
+
+/// Whether `name` is a portable user or group name.
+fn is_valid_name(name: &str) -> bool {
+    let name = name.strip_suffix('$').unwrap_or(name);
+    name.len() <= 32
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '_' | '-'))
+}
+
+/// Packages the settings in `system` need beyond the base system.
+pub fn required_packages(system: &SystemConfig, package_manager: PackageManager) -> Vec<String> {
+    let mut packages = Vec::new();
+    if system.timezone.is_some() {
+        packages.push("tzdata".to_string());
+    }
+    if let Some(locale) = &system.locale {
+        match package_manager {
+            PackageManager::Apt => packages.push("locales".to_string()),
+            // Fedora and Rocky ship locale data per language instead of
+            // generating it.
+            PackageManager::Dnf => packages.push(format!("glibc-langpack-{}", language(locale))),
+            PackageManager::Pacman => {}
+        }
+    }
+    if system.users.iter().any(|user| user.sudo) {
+        packages.push("sudo".to_string());
+    }
+    if system.ssh_policy().is_some() {
+        packages.push(ssh::server_package(package_manager).to_string());
+    }
+    packages
+}
+
+/// Writes the configuration files for `system` into the image.
+pub async fn write_files(chroot_dir: &Path, system: &SystemConfig, package_manager: PackageManager) -> Result<()> {
+    if let Some(hostname) = &system.hostname {
+        write_hostname(chroot_dir, hostname).await?;
+    }
+
+    if let Some(timezone) = &system.timezone {
+        let zoneinfo = format!("usr/share/zoneinfo/{}", timezone);
+        if !chroot_dir.join(&zoneinfo).is_file() {
+            return Err(anyhow::anyhow!("Timezone {} is not in the image's tz database", timezone));
+        }
+        let localtime = chroot_dir.join("etc/localtime");
+        if fs::symlink_metadata(&localtime).await.is_ok() {
+            fs::remove_file(&localtime).await?;
+        }
+        fs::symlink(format!("../{}", zoneinfo), &localtime).await?;
+        if package_manager == PackageManager::Apt {
+            fs::write(chroot_dir.join("etc/timezone"), format!("{}\n", timezone)).await?;
+        }
+    }
+
+    if let Some(locale) = &system.locale {
+        let config = format!("LANG={}\n", locale);
+        fs::write(chroot_dir.join("etc/locale.conf"), &config).await?;
+        if package_manager == PackageManager::Apt {
+            fs::write(chroot_dir.join("etc/default/locale"), &config).await?;
+        }
+        if package_manager != PackageManager::Dnf {
+            enable_in_locale_gen(chroot_dir, locale).await?;
+        }
+    }
+
+    if let Some(keyboard) = &system.keyboard {
+        write_keyboard(chroot_dir, keyboard, package_manager).await?;
+    }
+
+    let sudoers = chroot_dir.join(SUDOERS_FILE);
+    let sudo_users: Vec<&UserConfig> = system.users.iter().filter(|user| user.sudo).collect();
+    if !sudo_users.is_empty() {
+        fs::create_dir_all(sudoers.parent().unwrap()).await?;
+        let rules: String = sudo_users.iter()
+            .map(|user| format!("{} ALL=(ALL:ALL) ALL\n", user.name))
+            .collect();
+        fs::write(&sudoers, rules).await?;
+        // sudo ignores drop-ins that are writable by anyone but root.
+        fs::set_permissions(&sudoers, std::fs::Permissions::from_mode(0o440)).await?;
+    }
+    Ok(())
+}
+
+async fn write_hostname(chroot_dir: &Path, hostname: &str) -> Result<()> {
+    fs::write(chroot_dir.join("etc/hostname"), format!("{}\n", hostname)).await?;
+
+    let hosts_path = chroot_dir.join("etc/hosts");
+    let existing = match fs::read_to_string(&hosts_path).await {
+        Ok(hosts) => hosts,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            "127.0.0.1\tlocalhost\n::1\t\tlocalhost ip6-localhost ip6-loopback\n".to_string()
+        }
+        Err(e) => return Err(e).context("Failed to read /etc/hosts"),
+    };
+    // 127.0.1.1 resolves the machine's own name, as the Debian installer sets it up.
+    let short_name = hostname.split('.').next().unwrap_or(hostname);
+    let mut hosts: String = existing.lines()
+        .filter(|line| !line.trim_start().starts_with("127.0.1.1"))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    if short_name == hostname {
+        hosts.push_str(&format!("127.0.1.1\t{}\n", hostname));
+    } else {
+        hosts.push_str(&format!("127.0.1.1\t{} {}\n", hostname, short_name));
+    }
+    fs::write(&hosts_path, hosts).await?;
+    Ok(())
+}
+
+/// Uncomments `locale` in /etc/locale.gen, adding it when it is not listed.
+async fn enable_in_locale_gen(chroot_dir: &Path, locale: &str) -> Result<()> {
+    let path = chroot_dir.join("etc/locale.gen");
+    let existing = match fs::read_to_string(&path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context("Failed to read /etc/locale.gen"),
+    };
+    let entry = format!("{} {}", locale, charset(locale));
+
+    let mut found = false;
+    let mut contents: String = existing.lines()
+        .map(|line| {
+            let uncommented = line.trim_start_matches('#').trim();
+            if uncommented == entry {
+                found = true;
+                format!("{}\n", entry)
+            } else {
+                format!("{}\n", line)
+            }
+        })
+        .collect();
+    if !found {
+        contents.push_str(&entry);
+        contents.push('\n');
+    }
+    fs::write(&path, contents).await?;
+    Ok(())
+}
+
+async fn write_keyboard(chroot_dir: &Path, keyboard: &KeyboardConfig, package_manager: PackageManager) -> Result<()> {
+    let variant = keyboard.variant.as_deref().unwrap_or("");
+
+    let keymap = match &keyboard.variant {
+        Some(variant) => format!("{}-{}", keyboard.layout, variant),
+        None => keyboard.layout.clone(),
+    };
+    fs::write(chroot_dir.join("etc/vconsole.conf"), format!("KEYMAP={}\n", keymap)).await?;
+
+    let xorg_dir = chroot_dir.join("etc/X11/xorg.conf.d");
+    fs::create_dir_all(&xorg_dir).await?;
+    fs::write(xorg_dir.join("00-keyboard.conf"), format!(
+        "Section \"InputClass\"\n\
+         \tIdentifier \"system-keyboard\"\n\
+         \tMatchIsKeyboard \"on\"\n\
+         \tOption \"XkbLayout\" \"{}\"\n\
+         \tOption \"XkbVariant\" \"{}\"\n\
+         EndSection\n",
+        keyboard.layout, variant,
+    )).await?;
+
+    if package_manager == PackageManager::Apt {
+        // Read by console-setup and by the installer.
+        fs::create_dir_all(chroot_dir.join("etc/default")).await?;
+        fs::write(chroot_dir.join("etc/default/keyboard"), format!(
+            "XKBMODEL=\"pc105\"\nXKBLAYOUT=\"{}\"\nXKBVARIANT=\"{}\"\nXKBOPTIONS=\"\"\nBACKSPACE=\"guess\"\n",
+            keyboard.layout, variant,
+        )).await?;
+    }
+    Ok(())
+}
+
+/// Commands that finish applying `system` inside the image, run after
+/// `write_files`: locale generation and user creation.
+pub fn commands(
+    chroot_dir: &Path,
+    system: &SystemConfig,
+    package_manager: PackageManager,
+    category: &DistroCategory,
+) -> Vec<AsyncCommand> {
+    let mut commands = Vec::new();
+
+    if let Some(locale) = &system.locale {
+        match (package_manager, category) {
+            // Ubuntu's locale-gen ignores /etc/locale.gen and takes the
+            // locales to generate as arguments.
+            (PackageManager::Apt, DistroCategory::Ubuntu) => {
+                commands.push(chroot_command(chroot_dir, &["locale-gen", locale.as_str()]));
+            }
+            (PackageManager::Apt | PackageManager::Pacman, _) => {
+                commands.push(chroot_command(chroot_dir, &["locale-gen"]));
+            }
+            (PackageManager::Dnf, _) => {}
+        }
+    }
+
+    for user in &system.users {
+        let mut args = vec!["useradd".to_string(), "--create-home".to_string()];
+        if let Some(full_name) = &user.full_name {
+            args.extend(["--comment".to_string(), full_name.clone()]);
+        }
+        if let Some(shell) = &user.shell {
+            args.extend(["--shell".to_string(), shell.clone()]);
+        }
+        if !user.groups.is_empty() {
+            args.extend(["--groups".to_string(), user.groups.join(",")]);
+        }
+        if let Some(hash) = &user.password_hash {
+            args.extend(["--password".to_string(), hash.clone()]);
+        }
+        args.push(user.name.clone());
+
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        commands.push(chroot_command(chroot_dir, &args));
+    }
+    commands
+}
+
+/// Language part of `locale`, e.g. `en` for `en_US.UTF-8`.
+fn language(locale: &str) -> &str {
+    locale.split(['_', '.', '@']).next().unwrap_or(locale)
+}
+
+/// Character set of `locale` as /etc/locale.gen spells it.
+fn charset(locale: &str) -> String {
+    match locale.split_once('.') {
+        Some((_, charset)) => {
+            let charset = charset.split('@').next().unwrap_or(charset);
+            match charset.to_ascii_lowercase().as_str() {
+                "utf8" | "utf-8" => "UTF-8".to_string(),
+                _ => charset.to_string(),
+            }
+        }
+        // glibc's default for locales named without a character set.
+        None => "ISO-8859-1".to_string(),
+    }
+}
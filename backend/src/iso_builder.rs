@@ -1,193 +1,358 @@
+use crate::artifacts::{artifact_file_name, artifact_key, sha256_file, ArtifactStore, DOWNLOAD_URL_TTL};
+use crate::blobs::BlobStore;
+use crate::boot;
+use crate::boot_test;
+use crate::bootstrap::{BootstrapRegistry, Bootstrapper, PackageSource};
+use crate::events::{log_payload, JobEvents};
+use crate::identity;
+use crate::minimize;
 use crate::models::*;
-use crate::AppState;
+use crate::outputs::{self, DiskIds, DiskLayout};
+use crate::overlays;
+use crate::reproducible::{self, BuildManifest};
+use crate::sandbox::{self, ScriptSandbox};
+use crate::sbom::Sbom;
+use crate::scripts::{self, PlannedScript};
+use crate::squashfs;
+use crate::vulnerability::{self, ScanTarget};
+use crate::signing::ManifestSigner;
+use crate::size_breakdown::SizeBreakdown;
+use crate::ssh;
+use crate::storage::JobStore;
 use anyhow::{Context, Result};
-use chrono::Utc;
-use std::process::Command;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use tokio::fs;
 use tokio::process::Command as AsyncCommand;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 use tracing::{info, warn, error};
 
+#[derive(Clone)]
 pub struct IsoBuilder {
     work_dir: PathBuf,
+    jobs: JobStore,
+    events: JobEvents,
+    bootstrappers: Arc<BootstrapRegistry>,
+    artifacts: Arc<dyn ArtifactStore>,
+    signer: Arc<ManifestSigner>,
+    /// Directory of offline vulnerability databases, if configured.
+    vulnerability_db: Option<PathBuf>,
+    /// Container tool custom scripts run in.
+    script_sandbox: ScriptSandbox,
+    /// Uploaded files that file overlays refer to.
+    blobs: Arc<BlobStore>,
+    /// `SOURCE_DATE_EPOCH` of running reproducible builds, passed to every
+    /// command they run.
+    source_date_epochs: Arc<Mutex<HashMap<Uuid, u64>>>,
+    /// Command output lines each running build has logged so far.
+    logged_lines: Arc<Mutex<HashMap<Uuid, usize>>>,
 }
 
+/// Outcome of `IsoBuilder::build_root_filesystem`.
+struct RootFilesystem {
+    /// Reports to upload with the image.
+    reports: Vec<(ArtifactKind, String, PathBuf)>,
+    /// Bytes minimal-footprint mode removed, when enabled.
+    bytes_saved: Option<u64>,
+}
+
+/// Paths listed in a reproducibility report before the list is cut short.
+const MAX_REPORTED_DIFFERENCES: usize = 100;
+
+/// Returned by a build whose cancellation token was triggered.
+#[derive(Debug, thiserror::Error)]
+#[error("Build cancelled")]
+pub struct BuildCancelled;
+
 impl IsoBuilder {
-    pub fn new() -> Self {
+    pub fn new(
+        jobs: JobStore,
+        events: JobEvents,
+        artifacts: Arc<dyn ArtifactStore>,
+        signer: Arc<ManifestSigner>,
+        vulnerability_db: Option<PathBuf>,
+        script_sandbox: ScriptSandbox,
+        blobs: Arc<BlobStore>,
+    ) -> Self {
         Self {
             work_dir: PathBuf::from("/tmp/iso-builder"),
+            jobs,
+            events,
+            bootstrappers: Arc::new(BootstrapRegistry::with_defaults()),
+            artifacts,
+            signer,
+            vulnerability_db,
+            script_sandbox,
+            blobs,
+            source_date_epochs: Arc::new(Mutex::new(HashMap::new())),
+            logged_lines: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Builds `job_id` to completion and records the outcome on the job.
+    /// Cancelling `cancel` stops the build and marks it `Cancelled`.
+    pub async fn run_build(&self, job_id: Uuid, config: IsoConfig, cancel: CancellationToken) {
+        match self.build_iso(job_id, config, cancel).await {
+            Ok(()) => {}
+            Err(e) if e.is::<BuildCancelled>() => {
+                info!("Build cancelled for job {}", job_id);
+                self.mark_cancelled(job_id).await;
+            }
+            Err(e) => {
+                error!("Build failed for job {}: {}", job_id, e);
+                self.mark_failed(job_id, &e.to_string()).await;
+            }
         }
     }
 
-    pub async fn build_iso(&self, job_id: Uuid, config: IsoConfig) -> Result<()> {
+    pub async fn build_iso(&self, job_id: Uuid, config: IsoConfig, cancel: CancellationToken) -> Result<()> {
         info!("Starting ISO build for job {}: {}", job_id, config.name);
         
         let temp_dir = TempDir::new()?;
-        let build_dir = temp_dir.path();
+        if let Some(epoch) = reproducible::source_date_epoch(&config) {
+            self.append_log(job_id, LogLevel::Info, format!("Building reproducibly with SOURCE_DATE_EPOCH={}", epoch)).await;
+            self.source_date_epochs.lock().unwrap().insert(job_id, epoch);
+        }
         
+        // Dropping the stage future on cancellation kills any running command
+        // along with its process group.
+        let result = tokio::select! {
+            result = self.run_stages(job_id, &config, temp_dir.path()) => result,
+            _ = cancel.cancelled() => Err(BuildCancelled.into()),
+        };
+        
+        // Nothing may still be mounted inside the build directory when the
+        // TempDir is removed, or the host's /proc or /dev would be wiped.
+        unmount_all_under(temp_dir.path()).await;
+        self.source_date_epochs.lock().unwrap().remove(&job_id);
+        self.logged_lines.lock().unwrap().remove(&job_id);
+        if let Err(e) = temp_dir.close() {
+            warn!("Failed to remove build directory for job {}: {}", job_id, e);
+        }
+        
+        result
+    }
+
+    async fn run_stages(&self, job_id: Uuid, config: &IsoConfig, build_dir: &Path) -> Result<()> {
         // Update job status to Building
         self.update_job_status(job_id, BuildStatus::Building, 0, "Starting build process").await?;
         
-        // Step 1: Prepare base system
-        self.prepare_base_system(&config, build_dir).await?;
-        self.update_job_status(job_id, BuildStatus::Building, 20, "Base system prepared").await?;
+        // Steps 1-3: Build, record and strip the root filesystem
+        let root_filesystem = self.build_root_filesystem(job_id, config, build_dir, true).await?;
+        let mut reports = root_filesystem.reports;
         
-        // Step 2: Install packages
-        self.install_packages(&config, build_dir).await?;
-        self.update_job_status(job_id, BuildStatus::Building, 40, "Packages installed").await?;
+        // Step 4: Create ISO
+        let (iso_path, mut size_report) = self.create_iso_image(job_id, config, build_dir).await?;
+        size_report.bytes_saved = root_filesystem.bytes_saved;
+        self.record_size_report(job_id, size_report).await?;
         
-        // Step 3: Apply customizations
-        self.apply_customizations(&config, build_dir).await?;
-        self.update_job_status(job_id, BuildStatus::Building, 60, "Customizations applied").await?;
+        // Step 4a: Optionally rebuild from scratch and compare
+        if let Some(epoch) = reproducible::source_date_epoch(config) {
+            if config.reproducible.as_ref().is_some_and(|settings| settings.verify) {
+                self.update_job_status(job_id, BuildStatus::Packaging, 65, "ISO image created, rebuilding to verify reproducibility").await?;
+                self.verify_reproducible(job_id, config, epoch, build_dir, &iso_path).await?;
+            }
+        }
         
-        // Step 4: Create ISO
-        let iso_path = self.create_iso_image(&config, build_dir).await?;
-        self.update_job_status(job_id, BuildStatus::Packaging, 80, "ISO image created").await?;
+        // Step 4b: Build the other requested images from the same tree
+        if !config.output_formats.is_empty() {
+            self.update_job_status(job_id, BuildStatus::Packaging, 67, "ISO image created, building other output formats").await?;
+            reports.extend(self.create_output_formats(job_id, config, build_dir, &iso_path).await?);
+        }
+        
+        // Step 4c: Optionally boot the ISO before shipping it
+        if let Some(boot_test) = &config.boot_test {
+            self.update_job_status(job_id, BuildStatus::Packaging, 70, "ISO image created, running boot tests").await?;
+            self.run_boot_tests(job_id, boot_test, &iso_path).await?;
+        }
+        self.update_job_status(job_id, BuildStatus::Uploading, 80, "ISO image created").await?;
         
         // Step 5: Upload to storage
-        let download_url = self.upload_iso(job_id, &iso_path).await?;
+        let artifact = self.store_artifact(job_id, ArtifactKind::Iso, artifact_file_name(&config.name, "iso"), &iso_path).await?;
+        for (kind, name, path) in reports {
+            self.store_artifact(job_id, kind, name, &path).await?;
+        }
+        self.publish_build_manifest(job_id, config, build_dir).await?;
+        self.publish_checksums(job_id, build_dir).await?;
+        self.set_download_url(job_id, &artifact).await?;
         self.update_job_status(job_id, BuildStatus::Uploading, 90, "ISO uploaded").await?;
         
         // Step 6: Complete
         self.update_job_status(job_id, BuildStatus::Completed, 100, "Build completed successfully").await?;
-        self.set_download_url(job_id, download_url).await?;
         
         info!("ISO build completed for job {}", job_id);
         Ok(())
     }
 
-    async fn prepare_base_system(&self, config: &IsoConfig, build_dir: &Path) -> Result<()> {
+    /// Builds the root filesystem in `build_dir`: bootstraps it, runs the
+    /// pre-install scripts, installs packages, applies customizations and
+    /// minimizes it. The build and the reproducibility rebuild both use it,
+    /// so they always run the same stages.
+    ///
+    /// With `record`, the job's progress is updated and the SBOM,
+    /// vulnerability and size reports are written before and after the
+    /// image is minimized; the rebuild skips them.
+    async fn build_root_filesystem(
+        &self,
+        job_id: Uuid,
+        config: &IsoConfig,
+        build_dir: &Path,
+        record: bool,
+    ) -> Result<RootFilesystem> {
+        // Step 1: Prepare base system
+        self.prepare_base_system(job_id, config, build_dir).await?;
+        if record {
+            self.update_job_status(job_id, BuildStatus::Building, 20, "Base system prepared").await?;
+        }
+        self.run_custom_scripts(job_id, config, build_dir, ScriptPhase::PreInstall).await?;
+        
+        // Step 2: Install packages
+        self.install_packages(job_id, config, build_dir).await?;
+        if record {
+            self.update_job_status(job_id, BuildStatus::Building, 40, "Packages installed").await?;
+        }
+        
+        // Step 3: Apply customizations
+        self.apply_customizations(job_id, config, build_dir).await?;
+        
+        // Step 3b: Record what ended up in the image
+        let mut sbom = None;
+        let mut reports = Vec::new();
+        if record {
+            self.update_job_status(job_id, BuildStatus::Packaging, 60, "Customizations applied").await?;
+            let (generated, files) = self.generate_sbom(job_id, config, build_dir).await?;
+            reports = files;
+            if let Some(scan) = &config.vulnerability_scan {
+                self.update_job_status(job_id, BuildStatus::Packaging, 62, "SBOM generated, scanning for vulnerabilities").await?;
+                reports.push(self.scan_vulnerabilities(job_id, config, scan, &generated, build_dir).await?);
+            }
+            sbom = Some(generated);
+        }
+        
+        // Step 3c: Strip the image once the SBOM no longer needs the caches
+        let mut bytes_saved = None;
+        if let Some(minimize) = &config.minimize {
+            bytes_saved = Some(self.minimize_rootfs(job_id, config, minimize, build_dir).await?);
+        }
+        if let Some(sbom) = &sbom {
+            reports.push(self.write_size_breakdown(job_id, config, sbom, build_dir).await?);
+        }
+        
+        Ok(RootFilesystem { reports, bytes_saved })
+    }
+
+    async fn prepare_base_system(&self, job_id: Uuid, config: &IsoConfig, build_dir: &Path) -> Result<()> {
         info!("Preparing base system for {}", config.distro.name);
         
         let chroot_dir = build_dir.join("chroot");
         fs::create_dir_all(&chroot_dir).await?;
         
-        // Use debootstrap for Ubuntu/Debian
-        match config.distro.category {
-            DistroCategory::Ubuntu | DistroCategory::Debian => {
-                let mut cmd = AsyncCommand::new("debootstrap");
-                cmd.args(&[
-                    "--arch=amd64",
-                    "--variant=minbase",
-                    &config.distro.id.split('-').next().unwrap_or("ubuntu"),
-                    chroot_dir.to_str().unwrap(),
-                    "http://archive.ubuntu.com/ubuntu/",
-                ]);
-                
-                let output = cmd.output().await
-                    .context("Failed to run debootstrap")?;
-                
-                if !output.status.success() {
-                    return Err(anyhow::anyhow!("debootstrap failed: {}", String::from_utf8_lossy(&output.stderr)));
-                }
-            }
-            DistroCategory::Arch => {
-                // Use pacstrap for Arch
-                let mut cmd = AsyncCommand::new("pacstrap");
-                cmd.args(&["-K", chroot_dir.to_str().unwrap(), "base"]);
-                
-                let output = cmd.output().await
-                    .context("Failed to run pacstrap")?;
-                
-                if !output.status.success() {
-                    return Err(anyhow::anyhow!("pacstrap failed: {}", String::from_utf8_lossy(&output.stderr)));
-                }
-            }
-            DistroCategory::Fedora => {
-                // Use dnf for Fedora
-                let mut cmd = AsyncCommand::new("dnf");
-                cmd.args(&[
-                    "install",
-                    "-y",
-                    "--releasever=39",
-                    "--installroot",
-                    chroot_dir.to_str().unwrap(),
-                    "fedora-release",
-                    "coreutils",
-                ]);
-                
-                let output = cmd.output().await
-                    .context("Failed to run dnf")?;
-                
-                if !output.status.success() {
-                    return Err(anyhow::anyhow!("dnf failed: {}", String::from_utf8_lossy(&output.stderr)));
-                }
-            }
-            DistroCategory::Custom => {
-                return Err(anyhow::anyhow!("Custom distros not yet supported"));
+        let bootstrapper = self.bootstrappers.for_distro(&config.distro)?;
+        let source = PackageSource::resolve(config, bootstrapper.as_ref())?;
+        self.append_log(job_id, LogLevel::Info, format!(
+            "Bootstrapping {} {} from {} ({}) with {}",
+            config.distro.name, source.release, source.mirror, source.components.join(", "), bootstrapper.name(),
+        )).await;
+        
+        let plan = bootstrapper.bootstrap(&source, build_dir, &chroot_dir)?;
+        for (path, contents) in &plan.host_files {
+            fs::write(path, contents).await
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+        for cmd in plan.commands {
+            self.run_command(job_id, "base-system", cmd).await?;
+        }
+        for (path, contents) in &plan.chroot_files {
+            let path = chroot_dir.join(path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await?;
             }
+            fs::write(&path, contents).await
+                .with_context(|| format!("Failed to write {}", path.display()))?;
         }
         
         Ok(())
     }
 
-    async fn install_packages(&self, config: &IsoConfig, build_dir: &Path) -> Result<()> {
+    async fn install_packages(&self, job_id: Uuid, config: &IsoConfig, build_dir: &Path) -> Result<()> {
         info!("Installing packages for {}", config.name);
         
         let chroot_dir = build_dir.join("chroot");
+        let bootstrapper = self.bootstrappers.for_distro(&config.distro)?;
         
-        // Install desktop environment
-        if let Some(de) = &config.desktop_environment {
-            self.install_package_in_chroot(&chroot_dir, de).await?;
+        if !bootstrapper.detect(&chroot_dir) {
+            return Err(anyhow::anyhow!(
+                "{} not found in the root filesystem after bootstrap", bootstrapper.package_manager(),
+            ));
         }
         
-        // Install additional packages
-        for package in &config.packages {
-            self.install_package_in_chroot(&chroot_dir, package).await?;
+        if let Some(minimize) = &config.minimize {
+            let languages = minimize::kept_languages(config, minimize);
+            minimize::install_rules(&chroot_dir, bootstrapper.package_manager(), &languages).await
+                .context("Failed to configure the package manager for a minimal image")?;
         }
         
-        Ok(())
-    }
-
-    async fn install_package_in_chroot(&self, chroot_dir: &Path, package: &str) -> Result<()> {
-        let package_manager = self.detect_package_manager(chroot_dir).await?;
+        if let Some(cmd) = bootstrapper.refresh_command(&chroot_dir) {
+            self.run_command(job_id, "packages", cmd).await?;
+        }
         
-        let mut cmd = AsyncCommand::new("chroot");
-        cmd.arg(chroot_dir.to_str().unwrap());
+        // Unlike the user's packages, the ISO cannot boot without these.
+        let live_packages: Vec<String> = bootstrapper.live_boot().packages
+            .iter()
+            .map(|package| package.to_string())
+            .collect();
+        let cmd = bootstrapper.install_command(&chroot_dir, &live_packages);
+        self.run_command(job_id, "packages", cmd).await
+            .context("Failed to install the kernel and live-boot packages")?;
         
-        match package_manager {
-            "apt" => {
-                cmd.args(&["apt-get", "install", "-y", package]);
-            }
-            "pacman" => {
-                cmd.args(&["pacman", "-S", "--noconfirm", package]);
-            }
-            "dnf" => {
-                cmd.args(&["dnf", "install", "-y", package]);
-            }
-            _ => {
-                return Err(anyhow::anyhow!("Unsupported package manager: {}", package_manager));
-            }
+        // Needed for the hostname, locale and user settings applied later
+        let system_packages = identity::required_packages(&config.system, bootstrapper.package_manager());
+        if !system_packages.is_empty() {
+            let cmd = bootstrapper.install_command(&chroot_dir, &system_packages);
+            self.run_command(job_id, "packages", cmd).await
+                .context("Failed to install packages for the system settings")?;
         }
         
-        let output = cmd.output().await
-            .context(format!("Failed to install package: {}", package))?;
+        // Install desktop environment
+        if let Some(de) = &config.desktop_environment {
+            self.install_package_in_chroot(job_id, bootstrapper.as_ref(), &chroot_dir, de).await?;
+        }
         
-        if !output.status.success() {
-            warn!("Failed to install package {}: {}", package, String::from_utf8_lossy(&output.stderr));
+        // Install additional packages
+        for package in &config.packages {
+            self.install_package_in_chroot(job_id, bootstrapper.as_ref(), &chroot_dir, package).await?;
         }
         
         Ok(())
     }
 
-    async fn detect_package_manager(&self, chroot_dir: &Path) -> Result<String> {
-        // Check for package managers in the chroot
-        let managers = vec!["apt-get", "pacman", "dnf", "yum"];
+    async fn install_package_in_chroot(
+        &self,
+        job_id: Uuid,
+        bootstrapper: &dyn Bootstrapper,
+        chroot_dir: &Path,
+        package: &str,
+    ) -> Result<()> {
+        let cmd = bootstrapper.install_command(chroot_dir, &[package.to_string()]);
         
-        for manager in managers {
-            let manager_path = chroot_dir.join("usr/bin").join(manager);
-            if manager_path.exists() {
-                return Ok(manager.to_string());
-            }
+        if let Err(e) = self.run_command(job_id, "packages", cmd).await {
+            warn!("Failed to install package {}: {}", package, e);
+            self.append_log(job_id, LogLevel::Warning, format!("Failed to install package {}: {}", package, e)).await;
         }
         
-        Err(anyhow::anyhow!("No supported package manager found"))
+        Ok(())
     }
 
-    async fn apply_customizations(&self, config: &IsoConfig, build_dir: &Path) -> Result<()> {
+    async fn apply_customizations(&self, job_id: Uuid, config: &IsoConfig, build_dir: &Path) -> Result<()> {
         info!("Applying customizations for {}", config.name);
         
         let chroot_dir = build_dir.join("chroot");
@@ -195,11 +360,64 @@ impl IsoBuilder {
         // Apply theme customizations
         self.apply_theme_customizations(&config.theme, &chroot_dir).await?;
         
+        // Apply hostname, locale, timezone, keyboard and users
+        self.apply_system_config(job_id, config, &chroot_dir).await?;
+        
+        // Write file overlays
+        if !config.files.is_empty() {
+            let variables = overlays::template_variables(job_id, config);
+            let written = overlays::apply(&chroot_dir, &config.files, &self.blobs, &variables).await
+                .context("Failed to write file overlays")?;
+            self.append_log(job_id, LogLevel::Info, format!(
+                "Wrote {} file(s) into the image: {}", written.len(), written.join(", "),
+            )).await;
+        }
+        
         // Run custom scripts
-        for script in &config.custom_scripts {
-            self.run_custom_script(&chroot_dir, script).await?;
+        self.run_custom_scripts(job_id, config, build_dir, ScriptPhase::PostInstall).await?;
+        
+        // Leave first-boot scripts to the installed system
+        let first_boot = scripts::planned(&config.custom_scripts, ScriptPhase::FirstBoot);
+        if !first_boot.is_empty() {
+            let units = scripts::install_first_boot(&chroot_dir, &first_boot).await
+                .context("Failed to install first-boot scripts")?;
+            self.append_log(job_id, LogLevel::Info, format!(
+                "Installed {} first-boot script(s): {}", units.len(), units.join(", "),
+            )).await;
+        }
+        
+        Ok(())
+    }
+
+    async fn apply_system_config(&self, job_id: Uuid, config: &IsoConfig, chroot_dir: &Path) -> Result<()> {
+        let system = &config.system;
+        let package_manager = self.bootstrappers.for_distro(&config.distro)?.package_manager();
+        
+        identity::write_files(chroot_dir, system, package_manager).await
+            .context("Failed to write system settings")?;
+        for cmd in identity::commands(chroot_dir, system, package_manager, &config.distro.category) {
+            self.run_command(job_id, "system", cmd).await?;
+        }
+        
+        if let Some(policy) = system.ssh_policy() {
+            ssh::write_authorized_keys(chroot_dir, &system.users).await
+                .context("Failed to write authorized SSH keys")?;
+            let enable = ssh::configure_server(chroot_dir, &policy, package_manager).await
+                .context("Failed to configure sshd")?;
+            self.run_command(job_id, "system", enable).await?;
+            self.append_log(job_id, LogLevel::Info, format!(
+                "Enabled sshd with password authentication {}",
+                if policy.password_authentication { "on" } else { "off" },
+            )).await;
         }
         
+        if let Some(hostname) = &system.hostname {
+            self.append_log(job_id, LogLevel::Info, format!("Set hostname to {}", hostname)).await;
+        }
+        if !system.users.is_empty() {
+            let names: Vec<&str> = system.users.iter().map(|user| user.name.as_str()).collect();
+            self.append_log(job_id, LogLevel::Info, format!("Created users: {}", names.join(", "))).await;
+        }
         Ok(())
     }
 
@@ -231,151 +449,989 @@ impl IsoBuilder {
         Ok(())
     }
 
-    async fn run_custom_script(&self, chroot_dir: &Path, script: &str) -> Result<()> {
-        let script_file = chroot_dir.join("tmp/custom-script.sh");
+    /// Runs the custom scripts of `phase` in order.
+    async fn run_custom_scripts(&self, job_id: Uuid, config: &IsoConfig, build_dir: &Path, phase: ScriptPhase) -> Result<()> {
+        let planned = scripts::planned(&config.custom_scripts, phase);
+        if !planned.is_empty() {
+            info!("Running {} {} script(s) for {}", planned.len(), phase, config.name);
+        }
+        for script in &planned {
+            self.run_custom_script(job_id, config, build_dir, phase, script).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs a custom script against the root filesystem inside the
+    /// configured sandbox, with its output in the job log.
+    async fn run_custom_script(
+        &self,
+        job_id: Uuid,
+        config: &IsoConfig,
+        build_dir: &Path,
+        phase: ScriptPhase,
+        planned: &PlannedScript<'_>,
+    ) -> Result<()> {
+        let limits = &config.script_sandbox;
+        let label = &planned.label;
+        
+        // Keep the script outside the root filesystem so it never ends up
+        // in the image; the sandbox mounts it read-only.
+        let scripts_dir = build_dir.join("scripts").join(phase.to_string());
+        fs::create_dir_all(&scripts_dir).await?;
+        let script_file = scripts_dir.join(&planned.file_name);
         
-        // Write script to file
         let mut file = fs::File::create(&script_file).await?;
-        file.write_all(script.as_bytes()).await?;
+        file.write_all(planned.script.content.as_bytes()).await?;
         file.flush().await?;
-        
-        // Make script executable
         fs::set_permissions(&script_file, std::fs::Permissions::from_mode(0o755)).await?;
         
-        // Run script in chroot
-        let mut cmd = AsyncCommand::new("chroot");
-        cmd.arg(chroot_dir.to_str().unwrap())
-           .arg("/tmp/custom-script.sh");
+        let argv = planned.argv(sandbox::SCRIPT_MOUNT_PATH);
+        let mut env = Vec::new();
+        if let Some(epoch) = self.source_date_epochs.lock().unwrap().get(&job_id) {
+            env.push(("SOURCE_DATE_EPOCH".to_string(), epoch.to_string()));
+        }
         
-        let output = cmd.output().await
-            .context("Failed to run custom script")?;
+        let chroot_dir = build_dir.join("chroot");
+        sandbox::prepare_root(self.script_sandbox, &chroot_dir).await?;
+        let sandboxed = sandbox::script_command(
+            self.script_sandbox,
+            &chroot_dir,
+            &script_file,
+            &argv,
+            &env,
+            limits,
+            &format!("iso-builder-script-{}-{}-{}", job_id, phase, planned.file_name),
+        );
         
-        if !output.status.success() {
-            warn!("Custom script failed: {}", String::from_utf8_lossy(&output.stderr));
-        }
+        info!("Running {} {} for job {} in {:?}", phase, label, job_id, self.script_sandbox);
+        let timeout = Duration::from_secs(limits.timeout_secs);
+        let result = match tokio::time::timeout(
+            timeout,
+            self.run_labeled_command(job_id, "custom-script", label, sandboxed.command),
+        ).await {
+            Ok(result) => result,
+            Err(_) => {
+                sandbox::kill_unit(&sandboxed.unit).await;
+                Err(anyhow::anyhow!("{} timed out after {}s", label, limits.timeout_secs))
+            }
+        };
         
-        // Clean up script
         fs::remove_file(&script_file).await?;
+        sandbox::restore_root(&chroot_dir).await?;
+        
+        if let Err(e) = result {
+            if limits.fail_on_error {
+                return Err(e.context(format!("Custom {} failed", label)));
+            }
+            warn!("Custom {} failed: {}", label, e);
+            self.append_log(job_id, LogLevel::Warning, format!("Custom {} failed: {}", label, e)).await;
+        }
         
         Ok(())
     }
 
-    async fn create_iso_image(&self, config: &IsoConfig, build_dir: &Path) -> Result<PathBuf> {
+    /// Writes SPDX and CycloneDX SBOMs of the packages installed in the root
+    /// filesystem and records a summary on the job. Returns the SBOM and the
+    /// files to upload along with the image.
+    async fn generate_sbom(&self, job_id: Uuid, config: &IsoConfig, build_dir: &Path) -> Result<(Sbom, Vec<(ArtifactKind, String, PathBuf)>)> {
+        let bootstrapper = self.bootstrappers.for_distro(&config.distro)?;
+        let sbom = Sbom::collect(&build_dir.join("chroot"), bootstrapper.package_manager(), &config.distro).await
+            .context("Failed to read the installed packages for the SBOM")?;
+        let summary = sbom.summary();
+        
+        // Reproducible builds date their SBOMs like every other file.
+        let created = reproducible::source_date_epoch(config)
+            .and_then(|epoch| DateTime::from_timestamp(epoch as i64, 0))
+            .unwrap_or_else(Utc::now);
+        
+        let mut files = Vec::new();
+        for (kind, extension, document) in [
+            (ArtifactKind::SpdxSbom, "spdx.json", sbom.spdx_json(job_id, config, created)),
+            (ArtifactKind::CycloneDxSbom, "cdx.json", sbom.cyclonedx_json(job_id, config, created)),
+        ] {
+            let name = artifact_file_name(&config.name, extension);
+            let path = build_dir.join(&name);
+            fs::write(&path, serde_json::to_vec_pretty(&document)?).await?;
+            files.push((kind, name, path));
+        }
+        
+        let message = format!(
+            "SBOM lists {} {} packages ({} without a known license, {} without a checksum)",
+            summary.package_count, summary.package_manager, summary.packages_without_license, summary.packages_without_checksum,
+        );
+        self.jobs.update(job_id, |job| {
+            self.push_log(job, LogLevel::Info, message);
+            job.sbom = Some(summary);
+        }).await?;
+        
+        Ok((sbom, files))
+    }
+
+    /// Removes documentation, unused translations, package caches and logs
+    /// from the root filesystem, returning the bytes freed.
+    async fn minimize_rootfs(&self, job_id: Uuid, config: &IsoConfig, minimize: &MinimizeConfig, build_dir: &Path) -> Result<u64> {
+        let bootstrapper = self.bootstrappers.for_distro(&config.distro)?;
+        let languages = minimize::kept_languages(config, minimize);
+        let kept = if languages.is_empty() {
+            "none".to_string()
+        } else {
+            languages.iter().cloned().collect::<Vec<_>>().join(", ")
+        };
+        let freed = minimize::prune(&build_dir.join("chroot"), bootstrapper.package_manager(), languages).await
+            .context("Failed to minimize the root filesystem")?;
+        
+        self.append_log(job_id, LogLevel::Info, format!(
+            "Removed {:.1} MiB of documentation, translations, caches and logs (translations kept: {})",
+            freed as f64 / (1024.0 * 1024.0), kept,
+        )).await;
+        Ok(freed)
+    }
+
+    /// Writes the JSON breakdown of what takes up space in the root
+    /// filesystem and logs the largest packages.
+    async fn write_size_breakdown(
+        &self,
+        job_id: Uuid,
+        config: &IsoConfig,
+        sbom: &Sbom,
+        build_dir: &Path,
+    ) -> Result<(ArtifactKind, String, PathBuf)> {
+        let breakdown = SizeBreakdown::collect(&build_dir.join("chroot"), sbom).await
+            .context("Failed to measure the root filesystem")?;
+        
+        let name = artifact_file_name(&config.name, "sizes.json");
+        let path = build_dir.join(&name);
+        fs::write(&path, serde_json::to_vec_pretty(&breakdown)?).await?;
+        
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+        let largest: Vec<String> = breakdown.largest_packages(5)
+            .map(|(name, size)| format!("{} ({:.1} MiB)", name, mib(size)))
+            .collect();
+        let mut message = format!("Root filesystem holds {:.1} MiB", mib(breakdown.total_size));
+        if !largest.is_empty() {
+            message.push_str(&format!("; largest packages: {}", largest.join(", ")));
+        }
+        self.append_log(job_id, LogLevel::Info, message).await;
+        
+        Ok((ArtifactKind::SizeBreakdown, name, path))
+    }
+
+    /// Checks the SBOM's packages against the offline vulnerability
+    /// databases and records a summary on the job. Returns the report to
+    /// upload, or fails the build if a finding reaches `scan.fail_on`.
+    async fn scan_vulnerabilities(
+        &self,
+        job_id: Uuid,
+        config: &IsoConfig,
+        scan: &VulnerabilityScanConfig,
+        sbom: &Sbom,
+        build_dir: &Path,
+    ) -> Result<(ArtifactKind, String, PathBuf)> {
+        let database_dir = self.vulnerability_db.clone()
+            .ok_or_else(|| anyhow::anyhow!("Vulnerability scan requested, but no vulnerability database directory is configured"))?;
+        let bootstrapper = self.bootstrappers.for_distro(&config.distro)?;
+        let target = ScanTarget {
+            package_manager: bootstrapper.package_manager(),
+            category: config.distro.category.clone(),
+            release: PackageSource::resolve(config, bootstrapper.as_ref())?.release,
+        };
+        
+        // Databases can be large; the scan would hold up other builds' tasks.
+        let packages = sbom.clone();
+        let report = tokio::task::spawn_blocking(move || vulnerability::scan(&database_dir, &packages, &target)).await??;
+        let summary = report.summary.clone();
+        
+        let name = artifact_file_name(&config.name, "vulnerabilities.json");
+        let path = build_dir.join(&name);
+        fs::write(&path, serde_json::to_vec_pretty(&report)?).await?;
+        
+        let counts: Vec<String> = summary.by_severity.iter().rev()
+            .map(|(severity, count)| format!("{} {}", count, severity))
+            .collect();
+        let message = format!(
+            "Found {} vulnerabilities in {} databases{}",
+            summary.total,
+            summary.databases,
+            if counts.is_empty() { String::new() } else { format!(": {}", counts.join(", ")) },
+        );
+        self.jobs.update(job_id, |job| {
+            self.push_log(job, LogLevel::Info, message);
+            job.vulnerabilities = Some(summary.clone());
+        }).await?;
+        
+        if let Some(threshold) = scan.fail_on {
+            let blocking: Vec<_> = report.findings.iter()
+                .filter(|finding| finding.severity >= threshold)
+                .collect();
+            if !blocking.is_empty() {
+                for finding in blocking.iter().take(20) {
+                    self.append_log(job_id, LogLevel::Error, format!(
+                        "{} ({}) in {} {}{}",
+                        finding.id,
+                        finding.severity,
+                        finding.package,
+                        finding.installed_version,
+                        finding.fixed_version.as_ref().map(|fixed| format!(", fixed in {}", fixed)).unwrap_or_default(),
+                    )).await;
+                }
+                // The report explains the failure, so it is kept even though
+                // the image is not.
+                self.store_artifact(job_id, ArtifactKind::VulnerabilityReport, name, &path).await?;
+                return Err(anyhow::anyhow!(
+                    "{} vulnerabilities of {} severity or higher found", blocking.len(), threshold,
+                ));
+            }
+        }
+        
+        Ok((ArtifactKind::VulnerabilityReport, name, path))
+    }
+
+    async fn create_iso_image(&self, job_id: Uuid, config: &IsoConfig, build_dir: &Path) -> Result<(PathBuf, SizeReport)> {
         info!("Creating ISO image for {}", config.name);
         
         let chroot_dir = build_dir.join("chroot");
         let iso_dir = build_dir.join("iso");
         fs::create_dir_all(&iso_dir).await?;
         
-        // Create live system files
-        self.create_live_system(&chroot_dir, &iso_dir).await?;
+        let bootstrapper = self.bootstrappers.for_distro(&config.distro)?;
+        let live_boot = bootstrapper.live_boot();
+        let volume_id = boot::volume_id(&config.name);
         
-        // Create squashfs
-        let squashfs_path = iso_dir.join("filesystem.squashfs");
-        let mut cmd = AsyncCommand::new("mksquashfs");
-        cmd.args(&[
-            chroot_dir.to_str().unwrap(),
-            squashfs_path.to_str().unwrap(),
-            "-e", "boot",
-        ]);
+        let source_date_epoch = reproducible::source_date_epoch(config);
         
-        let output = cmd.output().await
-            .context("Failed to create squashfs")?;
+        // Create live system files
+        self.create_live_system(job_id, config, bootstrapper.as_ref(), &volume_id, build_dir).await?;
+        if source_date_epoch.is_some() {
+            reproducible::remove_volatile_files(&chroot_dir).await?;
+        }
         
-        if !output.status.success() {
-            return Err(anyhow::anyhow!("mksquashfs failed: {}", String::from_utf8_lossy(&output.stderr)));
+        // Create squashfs; the kernel and initramfs were copied out of /boot
+        let squashfs_path = iso_dir.join(live_boot.squashfs_path);
+        if let Some(parent) = squashfs_path.parent() {
+            fs::create_dir_all(parent).await?;
         }
+        let rootfs_size = squashfs::uncompressed_size(&chroot_dir, &["boot"]).await?;
+        let cmd = squashfs::command(&chroot_dir, &squashfs_path, &["boot"], &config.squashfs, source_date_epoch);
+        
+        let compression_started = Instant::now();
+        self.run_command(job_id, "squashfs", cmd).await?;
+        let compression_time = compression_started.elapsed();
         
         // Create ISO
         let iso_path = build_dir.join(format!("{}.iso", config.name));
-        let mut cmd = AsyncCommand::new("xorriso");
-        cmd.args(&[
-            "-as", "mkisofs",
-            "-iso-level", "3",
-            "-full-iso9660-filenames",
-            "-volid", &config.name,
-            "-appid", "Linux ISO Creator",
-            "-publisher", "Linux ISO Creator",
-            "-preparer", "Linux ISO Creator",
-            "-eltorito-boot", "boot/grub/i386-pc/eltorito.img",
-            "-no-emul-boot",
-            "-boot-load-size", "4",
-            "-boot-info-table",
-            "-eltorito-alt-boot",
-            "-e", "boot/grub/efi.img",
-            "-no-emul-boot",
-            "-isohybrid-gpt-basdat",
-            "-output", iso_path.to_str().unwrap(),
-            iso_dir.to_str().unwrap(),
-        ]);
-        
-        let output = cmd.output().await
-            .context("Failed to create ISO")?;
-        
-        if !output.status.success() {
-            return Err(anyhow::anyhow!("xorriso failed: {}", String::from_utf8_lossy(&output.stderr)));
-        }
-        
-        Ok(iso_path)
-    }
-
-    async fn create_live_system(&self, chroot_dir: &Path, iso_dir: &Path) -> Result<()> {
-        // Create boot directory
-        let boot_dir = iso_dir.join("boot");
-        fs::create_dir_all(&boot_dir).await?;
+        if let Some(epoch) = source_date_epoch {
+            reproducible::clamp_mtimes(&iso_dir, epoch).await?;
+        }
+        let cmd = boot::xorriso_command(&volume_id, &iso_dir, &iso_path, source_date_epoch);
+        
+        self.run_command(job_id, "iso", cmd).await?;
+        
+        let report = SizeReport {
+            compressor: config.squashfs.compressor,
+            rootfs_size,
+            squashfs_size: fs::metadata(&squashfs_path).await?.len(),
+            iso_size: fs::metadata(&iso_path).await?.len(),
+            compression_time_ms: compression_time.as_millis() as u64,
+            bytes_saved: None,
+        };
+        Ok((iso_path, report))
+    }
+
+    async fn record_size_report(&self, job_id: Uuid, report: SizeReport) -> Result<()> {
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+        let mut message = format!(
+            "Compressed {:.1} MiB root filesystem to {:.1} MiB with {} in {:.1}s; ISO is {:.1} MiB",
+            mib(report.rootfs_size), mib(report.squashfs_size), report.compressor.name(),
+            report.compression_time_ms as f64 / 1000.0, mib(report.iso_size),
+        );
+        if let Some(bytes_saved) = report.bytes_saved {
+            message.push_str(&format!("; minimizing saved {:.1} MiB", mib(bytes_saved)));
+        }
+        self.jobs.update(job_id, |job| {
+            self.push_log(job, LogLevel::Info, message);
+            job.size_report = Some(report);
+        }).await?;
+        Ok(())
+    }
+
+    /// Builds the images requested in `config.output_formats` from the root
+    /// filesystem and ISO tree, returning them as artifacts to upload.
+    async fn create_output_formats(
+        &self,
+        job_id: Uuid,
+        config: &IsoConfig,
+        build_dir: &Path,
+        iso_path: &Path,
+    ) -> Result<Vec<(ArtifactKind, String, PathBuf)>> {
+        let wants = |format: OutputFormat| config.output_formats.contains(&format);
+        let mut outputs = Vec::new();
+        
+        if wants(OutputFormat::RawDisk) || wants(OutputFormat::Qcow2) || wants(OutputFormat::Vhdx) {
+            let raw_path = self.create_disk_image(job_id, config, build_dir).await?;
+            for (format, kind, extension) in [
+                (OutputFormat::Qcow2, ArtifactKind::Qcow2, "qcow2"),
+                (OutputFormat::Vhdx, ArtifactKind::Vhdx, "vhdx"),
+            ] {
+                if wants(format) {
+                    let path = raw_path.with_extension(extension);
+                    self.run_command(job_id, "disk", outputs::convert_command(&raw_path, &path, extension)).await?;
+                    outputs.push((kind, artifact_file_name(&config.name, extension), path));
+                }
+            }
+            if wants(OutputFormat::RawDisk) {
+                outputs.push((ArtifactKind::RawDisk, artifact_file_name(&config.name, "img"), raw_path));
+            }
+        }
+        
+        if wants(OutputFormat::Pxe) {
+            let live_boot = self.bootstrappers.for_distro(&config.distro)?.live_boot();
+            let bundle_path = build_dir.join("netboot.tar");
+            let mtime = reproducible::source_date_epoch(config)
+                .unwrap_or_else(|| Utc::now().timestamp().max(0) as u64);
+            outputs::write_netboot_bundle(
+                &build_dir.join("iso"),
+                &bundle_path,
+                &config.name,
+                &live_boot,
+                &artifact_file_name(&config.name, "iso"),
+                iso_path,
+                mtime,
+            ).await.context("Failed to write the netboot bundle")?;
+            outputs.push((
+                ArtifactKind::NetbootBundle,
+                artifact_file_name(&format!("{}-netboot", config.name), "tar"),
+                bundle_path,
+            ));
+        }
+        
+        Ok(outputs)
+    }
+
+    /// Builds a GPT disk image of the root filesystem that boots in BIOS
+    /// and UEFI mode, without loop devices or mounts.
+    async fn create_disk_image(&self, job_id: Uuid, config: &IsoConfig, build_dir: &Path) -> Result<PathBuf> {
+        info!("Creating disk image for {}", config.name);
+        
+        let chroot_dir = build_dir.join("chroot");
+        let disk_dir = build_dir.join("disk");
+        fs::create_dir_all(&disk_dir).await?;
+        
+        let source_date_epoch = reproducible::source_date_epoch(config);
+        let seed = source_date_epoch.map(|epoch| format!("{}:{}", config.id, epoch));
+        let ids = DiskIds::new(seed.as_deref());
+        
+        // GRUB finds the root filesystem by UUID and boots the kernel in it
+        let kernel = boot::find_kernel(&chroot_dir).await?;
+        let initrd = boot::find_initrd(&chroot_dir, &kernel)?;
+        let embedded_cfg = disk_dir.join("grub.cfg");
+        let grub_cfg = boot::disk_grub_config(&config.name, &ids.root_filesystem.to_string(), &kernel.path, &initrd);
+        fs::write(&embedded_cfg, grub_cfg).await?;
+        if let Some(epoch) = source_date_epoch {
+            reproducible::clamp_mtimes(&disk_dir, epoch).await?;
+        }
+        
+        let core_image = disk_dir.join("core.img");
+        self.run_command(job_id, "disk", boot::disk_bios_core_command(&embedded_cfg, &core_image)).await?;
+        let efi_binary = disk_dir.join("bootx64.efi");
+        self.run_command(job_id, "disk", boot::efi_binary_command(&embedded_cfg, &efi_binary)).await?;
+        if let Some(epoch) = source_date_epoch {
+            reproducible::clamp_mtimes(&disk_dir, epoch).await?;
+        }
+        let esp_image = disk_dir.join("esp.img");
+        for cmd in boot::efi_image_commands(&efi_binary, &esp_image, source_date_epoch.is_some()).await? {
+            self.run_command(job_id, "disk", cmd).await?;
+        }
+        
+        let root_image = disk_dir.join("root.img");
+        let root_size = outputs::root_filesystem_size(&chroot_dir).await?;
+        let cmd = outputs::root_filesystem_command(&chroot_dir, &root_image, root_size, &ids);
+        self.run_command(job_id, "disk", cmd).await?;
+        
+        // Partition a sparse file and copy the filesystems into place
+        let layout = DiskLayout::new(fs::metadata(&esp_image).await?.len(), root_size);
+        let disk_path = disk_dir.join("disk.img");
+        fs::File::create(&disk_path).await?.set_len(layout.total_size).await?;
+        self.run_command(job_id, "disk", layout.partition_command(&disk_path, &ids)).await?;
+        outputs::write_partition(&disk_path, &esp_image, layout.esp_start).await?;
+        outputs::write_partition(&disk_path, &root_image, layout.root_start).await?;
+        boot::install_bios_boot(&disk_path, &core_image, layout.bios_boot_start_sector(), layout.bios_boot_sectors()).await?;
+        fs::remove_file(&root_image).await?;
+        
+        self.append_log(job_id, LogLevel::Info, format!(
+            "Created {} MiB disk image with root filesystem {}",
+            layout.total_size / (1024 * 1024), ids.root_filesystem,
+        )).await;
+        Ok(disk_path)
+    }
+
+    /// Copies the kernel and initramfs onto the ISO and creates the GRUB
+    /// menu plus the BIOS and UEFI boot images that load it.
+    async fn create_live_system(
+        &self,
+        job_id: Uuid,
+        config: &IsoConfig,
+        bootstrapper: &dyn Bootstrapper,
+        volume_id: &str,
+        build_dir: &Path,
+    ) -> Result<()> {
+        let chroot_dir = build_dir.join("chroot");
+        let iso_dir = build_dir.join("iso");
+        let grub_build_dir = build_dir.join("grub");
+        fs::create_dir_all(iso_dir.join("boot/grub")).await?;
+        fs::create_dir_all(&grub_build_dir).await?;
+        
+        // Find the kernel and make sure its initramfs can boot a live system
+        let kernel = boot::find_kernel(&chroot_dir).await?;
+        if let Some(cmd) = bootstrapper.initramfs_command(&chroot_dir, &kernel) {
+            self.run_command(job_id, "initramfs", cmd).await?;
+        }
+        let initrd = boot::find_initrd(&chroot_dir, &kernel)?;
+        self.append_log(job_id, LogLevel::Info, format!(
+            "Using kernel /{} and initramfs /{}", kernel.path.display(), initrd.display(),
+        )).await;
+        
+        fs::copy(chroot_dir.join(&kernel.path), iso_dir.join(boot::ISO_KERNEL_PATH)).await
+            .context("Failed to copy the kernel")?;
+        fs::copy(chroot_dir.join(&initrd), iso_dir.join(boot::ISO_INITRD_PATH)).await
+            .context("Failed to copy the initramfs")?;
         
         // Create GRUB configuration
-        let grub_dir = boot_dir.join("grub");
-        fs::create_dir_all(&grub_dir).await?;
+        let grub_cfg = boot::grub_config(&config.name, &bootstrapper.live_boot().kernel_args(volume_id));
+        fs::write(iso_dir.join("boot/grub/grub.cfg"), grub_cfg).await?;
+        
+        let embedded_cfg = grub_build_dir.join("embedded.cfg");
+        fs::write(&embedded_cfg, boot::embedded_grub_config(volume_id)).await?;
+        
+        // grub-mkstandalone packs the config into a tar with its mtime
+        let source_date_epoch = reproducible::source_date_epoch(config);
+        if let Some(epoch) = source_date_epoch {
+            reproducible::clamp_mtimes(&grub_build_dir, epoch).await?;
+        }
+        
+        // BIOS: CD boot sector followed by a standalone core image
+        let core_image = grub_build_dir.join("core.img");
+        self.run_command(job_id, "bootloader", boot::bios_core_command(&embedded_cfg, &core_image)).await?;
+        boot::write_bios_image(&core_image, &iso_dir.join(boot::ISO_BIOS_IMAGE_PATH)).await?;
+        
+        // UEFI: FAT image holding a standalone GRUB as the fallback loader
+        let efi_binary = grub_build_dir.join("bootx64.efi");
+        self.run_command(job_id, "bootloader", boot::efi_binary_command(&embedded_cfg, &efi_binary)).await?;
+        if let Some(epoch) = source_date_epoch {
+            reproducible::clamp_mtimes(&grub_build_dir, epoch).await?;
+        }
+        let efi_image = iso_dir.join(boot::ISO_EFI_IMAGE_PATH);
+        for cmd in boot::efi_image_commands(&efi_binary, &efi_image, source_date_epoch.is_some()).await? {
+            self.run_command(job_id, "bootloader", cmd).await?;
+        }
         
-        let grub_cfg = r#"set timeout=10
-set default=0
+        info!("Created live system boot files for job {}", job_id);
+        Ok(())
+    }
 
-menuentry "Linux Live" {
-    linux /boot/vmlinuz boot=live quiet splash
-    initrd /boot/initrd.img
-}
-"#;
+    /// Builds the image again from scratch below `build_dir/rebuild` and
+    /// compares it with `iso_path`, recording the result on the job. Fails
+    /// the build if the two images differ.
+    async fn verify_reproducible(
+        &self,
+        job_id: Uuid,
+        config: &IsoConfig,
+        source_date_epoch: u64,
+        build_dir: &Path,
+        iso_path: &Path,
+    ) -> Result<()> {
+        let rebuild_dir = build_dir.join("rebuild");
+        fs::create_dir_all(&rebuild_dir).await?;
+        self.append_log(job_id, LogLevel::Info, "Rebuilding the image to verify it is reproducible".to_string()).await;
+        
+        self.build_root_filesystem(job_id, config, &rebuild_dir, false).await?;
+        let (rebuild_iso_path, _) = self.create_iso_image(job_id, config, &rebuild_dir).await?;
+        
+        let sha256 = sha256_file(iso_path).await?;
+        let rebuild_sha256 = sha256_file(&rebuild_iso_path).await?;
+        
+        let mut differences = Vec::new();
+        if sha256 != rebuild_sha256 {
+            differences.extend(reproducible::diff_trees(&build_dir.join("iso"), &rebuild_dir.join("iso"), "iso").await?);
+            differences.extend(reproducible::diff_trees(&build_dir.join("chroot"), &rebuild_dir.join("chroot"), "rootfs").await?);
+            if differences.is_empty() {
+                differences.push("iso: image metadata differs".to_string());
+            }
+        }
+        
+        let total_differences = differences.len();
+        differences.truncate(MAX_REPORTED_DIFFERENCES);
+        let report = ReproducibilityReport {
+            source_date_epoch,
+            reproducible: total_differences == 0,
+            sha256,
+            rebuild_sha256,
+            differences,
+            total_differences,
+        };
         
-        let grub_cfg_path = grub_dir.join("grub.cfg");
-        fs::write(&grub_cfg_path, grub_cfg).await?;
+        self.jobs.update(job_id, |job| {
+            if report.reproducible {
+                self.push_log(job, LogLevel::Info, format!("Rebuild is bit-for-bit identical ({})", report.sha256));
+            } else {
+                self.push_log(job, LogLevel::Warning, format!(
+                    "Rebuild differs in {} paths ({} vs {})", total_differences, report.sha256, report.rebuild_sha256,
+                ));
+                for difference in &report.differences {
+                    self.push_log(job, LogLevel::Warning, difference.clone());
+                }
+            }
+            job.reproducibility = Some(report.clone());
+        }).await?;
+        
+        unmount_all_under(&rebuild_dir).await;
+        if let Err(e) = fs::remove_dir_all(&rebuild_dir).await {
+            warn!("Failed to remove rebuild directory for job {}: {}", job_id, e);
+        }
+        
+        if !report.reproducible {
+            return Err(anyhow::anyhow!("Build is not reproducible: {} paths differ between builds", total_differences));
+        }
+        Ok(())
+    }
+
+    /// Boots the ISO once with SeaBIOS and once with OVMF and stores the
+    /// results on the job. Failures only fail the build if the config says so.
+    async fn run_boot_tests(&self, job_id: Uuid, boot_test: &BootTestConfig, iso_path: &Path) -> Result<()> {
+        let mut failed = Vec::new();
         
-        // Copy kernel and initrd if they exist
-        let vmlinuz = chroot_dir.join("boot/vmlinuz-*");
-        let initrd = chroot_dir.join("boot/initrd.img-*");
+        for firmware in [BootFirmware::Bios, BootFirmware::Uefi] {
+            self.append_log(job_id, LogLevel::Info, format!("Booting the ISO with {} firmware", firmware)).await;
+            let result = boot_test::run_boot_test(iso_path, firmware, boot_test).await;
+            
+            let (level, message) = match &result.error {
+                None => (LogLevel::Info, format!("{} boot test passed in {}s", firmware, result.duration_secs)),
+                Some(error) => {
+                    failed.push(firmware.to_string());
+                    (LogLevel::Warning, format!("{} boot test failed after {}s: {}", firmware, result.duration_secs, error))
+                }
+            };
+            info!("Job {}: {}", job_id, message);
+            
+            self.jobs.update(job_id, |job| {
+                self.push_log(job, level, message);
+                job.boot_tests.push(result);
+            }).await?;
+        }
         
-        // This is simplified - in reality you'd need to find the actual kernel files
-        info!("Creating live system boot files");
+        if boot_test.required && !failed.is_empty() {
+            return Err(anyhow::anyhow!("Boot test failed with {} firmware", failed.join(" and ")));
+        }
         
         Ok(())
     }
 
-    async fn upload_iso(&self, job_id: Uuid, iso_path: &Path) -> Result<String> {
-        info!("Uploading ISO for job {}", job_id);
+    /// Uploads the file at `path` to the artifact store and records it on the job.
+    async fn store_artifact(&self, job_id: Uuid, kind: ArtifactKind, name: String, path: &Path) -> Result<BuildArtifact> {
+        let key = artifact_key(job_id, &name);
+        let size = fs::metadata(path).await?.len();
         
-        // For now, just return a mock URL
-        // In production, this would upload to R2, S3, or similar
-        let filename = iso_path.file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("custom-linux.iso");
+        info!("Uploading {} for job {} to {} as {}", name, job_id, self.artifacts.name(), key);
+        self.append_log(job_id, LogLevel::Info, format!(
+            "Uploading {} ({} MiB) to {}", name, size / (1024 * 1024), self.artifacts.name(),
+        )).await;
         
-        let download_url = format!("https://storage.example.com/isos/{}/{}", job_id, filename);
+        let sha256 = self.artifacts.put(&key, path).await
+            .with_context(|| format!("Failed to upload {}", name))?;
         
-        // Simulate upload
-        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+        let artifact = BuildArtifact { kind, name, key, size, sha256 };
+        self.jobs.update(job_id, |job| job.artifacts.push(artifact.clone())).await?;
         
-        Ok(download_url)
+        Ok(artifact)
     }
 
-    async fn update_job_status(&self, job_id: Uuid, status: BuildStatus, progress: u8, message: &str) -> Result<()> {
-        // This would update the job in the database/state
-        info!("Job {}: {} - {}% - {}", job_id, status, progress, message);
+    /// Publishes `build-manifest.json`, which records what went into the build.
+    async fn publish_build_manifest(&self, job_id: Uuid, config: &IsoConfig, build_dir: &Path) -> Result<()> {
+        let bootstrapper = self.bootstrappers.for_distro(&config.distro)?;
+        let manifest = BuildManifest::collect(job_id, config, bootstrapper.as_ref()).await?;
+        
+        let manifest_name = "build-manifest.json".to_string();
+        let manifest_path = build_dir.join(&manifest_name);
+        fs::write(&manifest_path, serde_json::to_vec_pretty(&manifest)?).await?;
+        self.store_artifact(job_id, ArtifactKind::BuildManifest, manifest_name, &manifest_path).await?;
+        Ok(())
+    }
+
+    /// Publishes a `checksums.sha256` manifest of every stored artifact,
+    /// plus its minisign signature.
+    async fn publish_checksums(&self, job_id: Uuid, build_dir: &Path) -> Result<()> {
+        let job = self.jobs.get(job_id).await?
+            .ok_or_else(|| anyhow::anyhow!("Build job {} not found", job_id))?;
+        
+        // Same format as `sha256sum`, so `sha256sum -c` can check downloads.
+        let manifest: String = job.artifacts.iter()
+            .filter(|artifact| !matches!(artifact.kind, ArtifactKind::Checksums | ArtifactKind::Signature))
+            .map(|artifact| format!("{}  {}\n", artifact.sha256, artifact.name))
+            .collect();
+        
+        let manifest_name = "checksums.sha256".to_string();
+        let manifest_path = build_dir.join(&manifest_name);
+        fs::write(&manifest_path, &manifest).await?;
+        self.store_artifact(job_id, ArtifactKind::Checksums, manifest_name.clone(), &manifest_path).await?;
+        
+        let trusted_comment = format!("timestamp:{}\tfile:{}\thashed", Utc::now().timestamp(), manifest_name);
+        let signature_name = format!("{}.minisig", manifest_name);
+        let signature_path = build_dir.join(&signature_name);
+        fs::write(&signature_path, self.signer.sign(manifest.as_bytes(), &trusted_comment)).await?;
+        self.store_artifact(job_id, ArtifactKind::Signature, signature_name, &signature_path).await?;
+        
+        self.append_log(job_id, LogLevel::Info, format!(
+            "Signed {} with key {}", manifest_name, self.signer.key_id(),
+        )).await;
         Ok(())
     }
 
-    async fn set_download_url(&self, job_id: Uuid, url: String) -> Result<()> {
-        // This would update the job with the download URL
-        info!("Job {}: Download URL set to {}", job_id, url);
+    /// Runs an external command, streaming each stdout/stderr line into the
+    /// job log as it is produced.
+    ///
+    /// Lines are tagged with the build stage and command name. Only the first
+    /// `MAX_LOGGED_LINES` lines of command output in a build reach the job
+    /// log; the last few stderr lines are always kept for the error returned
+    /// on failure.
+    async fn run_command(&self, job_id: Uuid, stage: &str, cmd: AsyncCommand) -> Result<()> {
+        let label = command_label(&cmd);
+        self.run_labeled_command(job_id, stage, &label, cmd).await
+    }
+
+    /// Runs `cmd`, logging its output tagged with `label`.
+    async fn run_labeled_command(&self, job_id: Uuid, stage: &str, label: &str, mut cmd: AsyncCommand) -> Result<()> {
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .kill_on_drop(true);
+        
+        if let Some(epoch) = self.source_date_epochs.lock().unwrap().get(&job_id) {
+            cmd.env("SOURCE_DATE_EPOCH", epoch.to_string());
+        }
+        
+        let mut child = cmd.spawn()
+            .with_context(|| format!("Failed to run {}", label))?;
+        let mut process_group = ProcessGroupGuard(child.id());
+        
+        let (tx, mut rx) = mpsc::channel(256);
+        if let Some(stdout) = child.stdout.take() {
+            tokio::spawn(forward_lines(stdout, OutputStream::Stdout, tx.clone()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(forward_lines(stderr, OutputStream::Stderr, tx));
+        }
+        
+        let mut suppressed = 0usize;
+        let mut stderr_tail = VecDeque::with_capacity(ERROR_TAIL_LINES);
+        
+        while let Some((stream, line)) = rx.recv().await {
+            if stream == OutputStream::Stderr {
+                if stderr_tail.len() == ERROR_TAIL_LINES {
+                    stderr_tail.pop_front();
+                }
+                stderr_tail.push_back(line.clone());
+            }
+            
+            if self.reserve_log_line(job_id) {
+                let level = classify_line(stream, &line);
+                self.append_log(job_id, level, format!("[{}] {}: {}", stage, label, line)).await;
+            } else {
+                if suppressed == 0 {
+                    self.append_log(job_id, LogLevel::Warning, format!(
+                        "[{}] {}: build output exceeds {} lines, further lines are not logged",
+                        stage, label, MAX_LOGGED_LINES,
+                    )).await;
+                }
+                suppressed += 1;
+            }
+        }
+        
+        let status = child.wait().await
+            .with_context(|| format!("Failed to wait for {}", label))?;
+        process_group.disarm();
+        
+        if suppressed > 0 {
+            self.append_log(job_id, LogLevel::Info, format!(
+                "[{}] {}: {} output lines omitted", stage, label, suppressed,
+            )).await;
+        }
+        
+        if !status.success() {
+            let tail: Vec<String> = stderr_tail.into_iter().collect();
+            return Err(anyhow::anyhow!("{} failed ({}): {}", label, status, tail.join("\n")));
+        }
+        
         Ok(())
     }
+
+    /// Counts a line of command output against the job's `MAX_LOGGED_LINES`.
+    /// Returns `false` once the job has logged that many.
+    fn reserve_log_line(&self, job_id: Uuid) -> bool {
+        let mut logged_lines = self.logged_lines.lock().unwrap();
+        let logged = logged_lines.entry(job_id).or_insert(0);
+        if *logged >= MAX_LOGGED_LINES {
+            return false;
+        }
+        *logged += 1;
+        true
+    }
+
+    /// Appends a single log entry to the job, e.g. from a build stage.
+    async fn append_log(&self, job_id: Uuid, level: LogLevel, message: String) {
+        let log = BuildLog {
+            timestamp: Utc::now(),
+            level,
+            message,
+        };
+        let result = self.jobs.append_log(job_id, log, |index, log| {
+            self.events.publish(job_id, MessageType::LogMessage, log_payload(index, log));
+        }).await;
+        if let Err(e) = result {
+            warn!("Failed to store log for job {}: {}", job_id, e);
+        }
+    }
+
+    async fn update_job_status(&self, job_id: Uuid, status: BuildStatus, progress: u8, message: &str) -> Result<()> {
+        info!("Job {}: {} - {}% - {}", job_id, status, progress, message);
+        
+        self.jobs.update(job_id, |job| {
+            if job.status != status {
+                job.queue_position = None;
+                self.events.publish(job_id, MessageType::StatusUpdate, serde_json::json!({
+                    "status": status,
+                    "queue_position": null,
+                }));
+            }
+            if job.progress != progress {
+                self.events.publish(job_id, MessageType::ProgressUpdate, serde_json::json!({
+                    "progress": progress,
+                }));
+            }
+            
+            job.status = status;
+            job.progress = progress;
+            self.push_log(job, LogLevel::Info, message.to_string());
+            
+            if job.status == BuildStatus::Completed {
+                job.completed_at = Some(Utc::now());
+                self.events.publish(job_id, MessageType::Completed, serde_json::json!({
+                    "download_url": job.download_url,
+                }));
+                self.events.close(job_id);
+            }
+        }).await?
+            .ok_or_else(|| anyhow::anyhow!("Build job {} not found", job_id))
+    }
+
+    /// Publishes a download link for `artifact` that stays valid for `DOWNLOAD_URL_TTL`.
+    async fn set_download_url(&self, job_id: Uuid, artifact: &BuildArtifact) -> Result<()> {
+        let url = self.artifacts.download_url(&artifact.key, DOWNLOAD_URL_TTL).await?;
+        let expires_at = Utc::now() + chrono::Duration::from_std(DOWNLOAD_URL_TTL)?;
+        info!("Job {}: Download URL set to {} (expires {})", job_id, url, expires_at);
+        
+        self.jobs.update(job_id, |job| {
+            job.download_url = Some(url);
+            job.download_expires_at = Some(expires_at);
+        }).await?
+            .ok_or_else(|| anyhow::anyhow!("Build job {} not found", job_id))
+    }
+
+    /// Marks a job as failed, keeping whatever progress it had reached.
+    pub async fn mark_failed(&self, job_id: Uuid, error: &str) {
+        let result = self.jobs.update(job_id, |job| {
+            job.status = BuildStatus::Failed;
+            job.completed_at = Some(Utc::now());
+            self.events.publish(job_id, MessageType::StatusUpdate, serde_json::json!({
+                "status": BuildStatus::Failed,
+            }));
+            let message = format!("Build failed: {}", error);
+            self.push_log(job, LogLevel::Error, message.clone());
+            self.events.publish(job_id, MessageType::Error, serde_json::json!({
+                "error": message,
+            }));
+        }).await;
+        if let Err(e) = result {
+            error!("Failed to store failure of job {}: {}", job_id, e);
+        }
+        self.events.close(job_id);
+    }
+
+    pub async fn mark_cancelled(&self, job_id: Uuid) {
+        let result = self.jobs.update(job_id, |job| {
+            job.status = BuildStatus::Cancelled;
+            job.queue_position = None;
+            job.completed_at = Some(Utc::now());
+            self.events.publish(job_id, MessageType::StatusUpdate, serde_json::json!({
+                "status": BuildStatus::Cancelled,
+                "queue_position": null,
+            }));
+            self.push_log(job, LogLevel::Warning, "Build cancelled".to_string());
+        }).await;
+        if let Err(e) = result {
+            error!("Failed to store cancellation of job {}: {}", job_id, e);
+        }
+        self.events.close(job_id);
+    }
+
+    /// Appends a log entry to the job and publishes it to live subscribers.
+    ///
+    /// Called with the job store write lock held so event order always
+    /// matches the order of `BuildJob.logs`.
+    fn push_log(&self, job: &mut BuildJob, level: LogLevel, message: String) {
+        let log = BuildLog {
+            timestamp: Utc::now(),
+            level,
+            message,
+        };
+        self.events.publish(job.id, MessageType::LogMessage, log_payload(job.logs.len(), &log));
+        job.logs.push(log);
+    }
+}
+
+/// Maximum number of command output lines a build may add to the job log.
+const MAX_LOGGED_LINES: usize = 20_000;
+
+/// Longest output line kept in the job log, in bytes.
+const MAX_LINE_LENGTH: usize = 1_024;
+
+/// Number of trailing stderr lines included in a command's error.
+const ERROR_TAIL_LINES: usize = 20;
+
+/// Kills a command's whole process group when dropped, so aborting a build
+/// also stops everything the command started (dpkg, maintainer scripts, ...).
+struct ProcessGroupGuard(Option<u32>);
+
+impl ProcessGroupGuard {
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        if let Some(pgid) = self.0 {
+            // SAFETY: killpg has no memory-safety preconditions; the group was
+            // created for this command by `process_group(0)`.
+            unsafe {
+                libc::killpg(pgid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Lazily unmounts every mount point below `root`, deepest first.
+async fn unmount_all_under(root: &Path) {
+    let mounts = match fs::read_to_string("/proc/self/mounts").await {
+        Ok(mounts) => mounts,
+        Err(e) => {
+            warn!("Failed to read mount table: {}", e);
+            return;
+        }
+    };
+    
+    let mut mount_points: Vec<PathBuf> = mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(|point| PathBuf::from(point.replace("\\040", " ")))
+        .filter(|point| point.starts_with(root))
+        .collect();
+    mount_points.sort();
+    
+    for point in mount_points.iter().rev() {
+        info!("Unmounting {}", point.display());
+        match AsyncCommand::new("umount").arg("--lazy").arg(point).status().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("umount {} exited with {}", point.display(), status),
+            Err(e) => warn!("Failed to run umount for {}: {}", point.display(), e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Reads `reader` line by line and forwards each line to `tx` until EOF.
+///
+/// At most `MAX_LINE_LENGTH` bytes of a line are buffered; the rest is
+/// skipped, so output without newlines cannot exhaust memory.
+async fn forward_lines<R>(reader: R, stream: OutputStream, tx: mpsc::Sender<(OutputStream, String)>)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    let mut buf = Vec::with_capacity(MAX_LINE_LENGTH + 1);
+    
+    loop {
+        let chunk = match reader.fill_buf().await {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                warn!("Failed to read command output: {}", e);
+                break;
+            }
+        };
+        let at_eof = chunk.is_empty();
+        let newline = chunk.iter().position(|&b| b == b'\n');
+        let consumed = newline.map_or(chunk.len(), |index| index + 1);
+        // One byte over the limit marks the line as truncated.
+        let room = (MAX_LINE_LENGTH + 1).saturating_sub(buf.len());
+        buf.extend_from_slice(&chunk[..consumed.min(room)]);
+        reader.consume(consumed);
+        
+        if newline.is_none() && !at_eof {
+            continue;
+        }
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end();
+        if !line.is_empty() && tx.send((stream, truncate_line(line))).await.is_err() {
+            break;
+        }
+        if at_eof {
+            break;
+        }
+        buf.clear();
+    }
+}
+
+fn truncate_line(line: &str) -> String {
+    if line.len() <= MAX_LINE_LENGTH {
+        return line.to_string();
+    }
+    let mut end = MAX_LINE_LENGTH;
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}…", &line[..end])
+}
+
+/// Picks a log level for a line of command output. stdout is informational;
+/// stderr is a warning unless it looks like an error (apt's `E:`, `error:`).
+fn classify_line(stream: OutputStream, line: &str) -> LogLevel {
+    match stream {
+        OutputStream::Stdout => LogLevel::Info,
+        OutputStream::Stderr => {
+            let lower = line.to_ascii_lowercase();
+            if lower.starts_with("e:") || lower.starts_with("error") || lower.contains("fatal") {
+                LogLevel::Error
+            } else {
+                LogLevel::Warning
+            }
+        }
+    }
+}
+
+/// Short name for a command used to tag its log lines, e.g. `debootstrap`
+/// or `chroot apt-get`.
+fn command_label(cmd: &AsyncCommand) -> String {
+    let cmd = cmd.as_std();
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    if program == "chroot" {
+        // Skip the chroot directory and name the command run inside it.
+        if let Some(inner) = cmd.get_args().nth(1) {
+            return format!("chroot {}", inner.to_string_lossy());
+        }
+    }
+    program
 }
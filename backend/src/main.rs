@@ -1,35 +1,210 @@
 use axum::{
-    extract::{Path, State},
-    http::{StatusCode, HeaderMap},
+    body::Bytes,
+    extract::{ws::WebSocketUpgrade, ConnectInfo, DefaultBodyLimit, Path, Query, State},
+    http::{header, HeaderMap, Method, StatusCode},
     response::{Json, Response},
     routing::{get, post},
     Router,
 };
+use clap::{Parser, ValueEnum};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Duration;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::services::ServeDir;
 use tracing::{info, error, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use uuid::Uuid;
 
+mod artifacts;
+mod blobs;
+mod boot;
+mod boot_test;
+mod bootstrap;
+mod download;
+mod events;
+mod identity;
 mod iso_builder;
+mod minimize;
 mod models;
+mod outputs;
+mod overlays;
+mod reproducible;
+mod sandbox;
+mod sbom;
+mod scripts;
+mod scheduler;
+mod signing;
+mod size_breakdown;
+mod squashfs;
+mod ssh;
+mod storage;
+mod vulnerability;
 mod websocket;
 
+use artifacts::{ArtifactStore, DownloadCheck, LocalArtifactStore, S3ArtifactStore, S3Config};
+use blobs::{BlobInfo, BlobStore};
+use boot_test::BootTestLimits;
+use events::JobEvents;
 use iso_builder::IsoBuilder;
 use models::*;
+use sandbox::ScriptLimits;
+use scheduler::BuildScheduler;
+use signing::ManifestSigner;
+use storage::{JobStore, SqliteJobStorage};
+
+#[derive(Debug, Parser)]
+#[command(about = "ISO Creator backend")]
+struct Args {
+    /// Number of builds that may run at the same time.
+    #[arg(long, default_value_t = 2)]
+    workers: usize,
+
+    /// SQLite database holding build jobs and their logs.
+    #[arg(long, default_value = "iso-builder.db")]
+    database: PathBuf,
+
+    /// Minisign secret key that signs build checksums; created if missing.
+    #[arg(long, default_value = "signing.key")]
+    signing_key: PathBuf,
+
+    /// Where finished ISOs are stored.
+    #[arg(long, value_enum, default_value_t = ArtifactBackend::Local)]
+    artifact_store: ArtifactBackend,
+
+    /// Directory of the local artifact store.
+    #[arg(long, default_value = "artifacts")]
+    artifact_dir: PathBuf,
+
+    /// Address clients reach this server at, used in local download links.
+    #[arg(long, env = "PUBLIC_URL", default_value = "http://localhost:8080")]
+    public_url: String,
+
+    /// S3 endpoint, e.g. https://s3.eu-west-1.amazonaws.com or a MinIO URL.
+    #[arg(long, env = "S3_ENDPOINT")]
+    s3_endpoint: Option<String>,
+
+    #[arg(long, env = "S3_BUCKET")]
+    s3_bucket: Option<String>,
+
+    #[arg(long, env = "S3_REGION", default_value = "us-east-1")]
+    s3_region: String,
+
+    #[arg(long, env = "AWS_ACCESS_KEY_ID", hide_env_values = true)]
+    s3_access_key: Option<String>,
+
+    #[arg(long, env = "AWS_SECRET_ACCESS_KEY", hide_env_values = true)]
+    s3_secret_key: Option<String>,
+
+    /// Address buckets as a subdomain of the endpoint instead of a path prefix.
+    #[arg(long)]
+    s3_virtual_hosted_style: bool,
+
+    /// Directory of offline vulnerability databases (OSV dumps, the Debian
+    /// security tracker's JSON, OVAL files) that builds can be scanned against.
+    #[arg(long, env = "VULNERABILITY_DB_DIR")]
+    vulnerability_db: Option<PathBuf>,
+
+    /// Container tool that runs custom scripts against the image's root filesystem.
+    #[arg(long, value_enum, default_value_t = sandbox::ScriptSandbox::Nspawn)]
+    script_sandbox: sandbox::ScriptSandbox,
+
+    /// Takes the submitting user from the x-user-id header instead of the
+    /// client's address. Only for use behind a proxy that authenticates
+    /// users and sets the header itself.
+    #[arg(long)]
+    trust_user_header: bool,
+
+    /// Longest a custom script may be allowed to run, in seconds.
+    #[arg(long, default_value_t = 3600)]
+    max_script_timeout_secs: u64,
+
+    /// Most memory a custom script may be given, in MiB.
+    #[arg(long, default_value_t = 4096)]
+    max_script_memory_mb: u64,
+
+    /// Most CPU a custom script may be given, in percent of one core.
+    #[arg(long, default_value_t = 400)]
+    max_script_cpu_percent: u32,
+
+    /// Lets builds give their custom scripts the host's network.
+    #[arg(long)]
+    allow_script_network: bool,
+
+    /// Longest a boot test may be allowed to wait for the system, in seconds.
+    #[arg(long, default_value_t = 1800)]
+    max_boot_test_timeout_secs: u64,
+
+    /// Most memory a boot test's virtual machine may be given, in MiB.
+    #[arg(long, default_value_t = 4096)]
+    max_boot_test_memory_mb: u32,
+
+    /// Directory of files uploaded for use in builds.
+    #[arg(long, default_value = "blobs")]
+    blob_dir: PathBuf,
+
+    /// Bearer token clients must send to upload to /api/blobs; uploads are
+    /// refused when unset.
+    #[arg(long, env = "BLOB_UPLOAD_TOKEN", hide_env_values = true)]
+    blob_upload_token: Option<String>,
+
+    /// Hours an uploaded blob is kept after its last upload, unless a queued
+    /// or running build uses it; 0 keeps blobs forever.
+    #[arg(long, default_value_t = 168)]
+    blob_retention_hours: u64,
+
+    /// Largest file that may be uploaded to /api/blobs, in MiB.
+    #[arg(long, default_value_t = 64)]
+    max_blob_size_mb: usize,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ArtifactBackend {
+    Local,
+    S3,
+}
+
+impl Args {
+    fn s3_config(&self) -> anyhow::Result<S3Config> {
+        let required = |value: &Option<String>, name: &str| {
+            value.clone().ok_or_else(|| anyhow::anyhow!("--artifact-store s3 requires {}", name))
+        };
+        Ok(S3Config {
+            endpoint: required(&self.s3_endpoint, "--s3-endpoint")?,
+            bucket: required(&self.s3_bucket, "--s3-bucket")?,
+            region: self.s3_region.clone(),
+            access_key: required(&self.s3_access_key, "AWS_ACCESS_KEY_ID")?,
+            secret_key: required(&self.s3_secret_key, "AWS_SECRET_ACCESS_KEY")?,
+            path_style: !self.s3_virtual_hosted_style,
+        })
+    }
+}
 
 #[derive(Clone)]
 pub struct AppState {
-    jobs: Arc<RwLock<HashMap<Uuid, BuildJob>>>,
-    iso_builder: IsoBuilder,
+    jobs: JobStore,
+    events: JobEvents,
+    scheduler: BuildScheduler,
+    artifacts: Arc<dyn ArtifactStore>,
+    signer: Arc<ManifestSigner>,
+    blobs: Arc<BlobStore>,
+    /// Bearer token required for blob uploads; uploads are refused without one.
+    blob_upload_token: Option<String>,
+    /// Whether `USER_HEADER` identifies the submitting user.
+    trust_user_header: bool,
+    script_limits: ScriptLimits,
+    boot_test_limits: BootTestLimits,
+    /// Set when artifacts are stored locally and downloaded through this server.
+    local_artifacts: Option<Arc<LocalArtifactStore>>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
     // Initialize tracing
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new(
@@ -40,17 +215,78 @@ async fn main() -> anyhow::Result<()> {
 
     info!("Starting ISO Creator Backend");
 
+    let storage = SqliteJobStorage::open(&args.database)?;
+    let jobs = JobStore::new(Arc::new(storage));
+    let interrupted = jobs.fail_interrupted().await?;
+    if interrupted > 0 {
+        warn!("Marked {} builds interrupted by the last shutdown as failed", interrupted);
+    }
+    let (artifacts, local_artifacts): (Arc<dyn ArtifactStore>, _) = match args.artifact_store {
+        ArtifactBackend::Local => {
+            let store = Arc::new(LocalArtifactStore::open(&args.artifact_dir, &args.public_url)?);
+            info!("Storing artifacts in {}", args.artifact_dir.display());
+            (store.clone(), Some(store))
+        }
+        ArtifactBackend::S3 => {
+            let config = args.s3_config()?;
+            info!("Storing artifacts in bucket {} at {}", config.bucket, config.endpoint);
+            (Arc::new(S3ArtifactStore::new(config)?), None)
+        }
+    };
+    let signer = Arc::new(ManifestSigner::load_or_generate(&args.signing_key)?);
+    info!("Signing build checksums with key {}", signer.key_id());
+    let blobs = Arc::new(BlobStore::open(&args.blob_dir)?);
+    if args.blob_retention_hours > 0 {
+        let retention = Duration::from_secs(args.blob_retention_hours * 60 * 60);
+        tokio::spawn(remove_expired_blobs(blobs.clone(), jobs.clone(), retention));
+    }
+    let events = JobEvents::new();
+    let iso_builder = IsoBuilder::new(
+        jobs.clone(),
+        events.clone(),
+        artifacts.clone(),
+        signer.clone(),
+        args.vulnerability_db.clone(),
+        args.script_sandbox,
+        blobs.clone(),
+    );
     let state = AppState {
-        jobs: Arc::new(RwLock::new(HashMap::new())),
-        iso_builder: IsoBuilder::new(),
+        jobs: jobs.clone(),
+        events: events.clone(),
+        scheduler: BuildScheduler::new(iso_builder, jobs, events, args.workers),
+        artifacts,
+        signer,
+        blobs,
+        blob_upload_token: args.blob_upload_token.clone(),
+        trust_user_header: args.trust_user_header,
+        script_limits: ScriptLimits {
+            max_timeout_secs: args.max_script_timeout_secs,
+            max_memory_mb: args.max_script_memory_mb,
+            max_cpu_percent: args.max_script_cpu_percent,
+            allow_network: args.allow_script_network,
+        },
+        boot_test_limits: BootTestLimits {
+            max_timeout_secs: args.max_boot_test_timeout_secs,
+            max_memory_mb: args.max_boot_test_memory_mb,
+        },
+        local_artifacts,
     };
+    info!("Running up to {} concurrent builds", args.workers);
 
     let app = Router::new()
         // API routes
         .route("/api/distros", get(get_distros))
         .route("/api/iso/create", post(create_iso))
-        .route("/api/build/:id", get(get_build_job))
+        .route("/api/build/:id", get(get_build_job).delete(cancel_build_job))
+        .route("/api/build/:id/iso", get(download_iso))
+        .route("/api/build/:id/size-breakdown", get(get_size_breakdown))
         .route("/api/gallery", get(get_gallery))
+        .route("/api/artifacts/*key", get(download_artifact))
+        .route("/api/signing-key", get(get_signing_key))
+        .route(
+            "/api/blobs",
+            post(upload_blob).layer(DefaultBodyLimit::max(args.max_blob_size_mb * 1024 * 1024)),
+        )
         .route("/ws/:id", get(websocket_handler))
         // Serve static files
         .nest_service("/static", ServeDir::new("static"))
@@ -67,7 +303,7 @@ async fn main() -> anyhow::Result<()> {
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
     info!("Server listening on {}", listener.local_addr()?);
     
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
     
     Ok(())
 }
@@ -88,6 +324,27 @@ async fn get_distros() -> Json<Vec<DistroTemplate>> {
             ],
             desktop_environment: "GNOME".to_string(),
             base_image: "ubuntu:22.04".to_string(),
+            release: "jammy".to_string(),
+            mirror: None,
+            components: vec!["main".to_string(), "restricted".to_string(), "universe".to_string()],
+        },
+        DistroTemplate {
+            id: "ubuntu-24.04".to_string(),
+            name: "Ubuntu 24.04 LTS".to_string(),
+            description: "The latest Ubuntu long-term support release".to_string(),
+            icon: "🟠".to_string(),
+            category: DistroCategory::Ubuntu,
+            default_packages: vec![
+                "ubuntu-desktop-minimal".to_string(),
+                "gnome-shell".to_string(),
+                "firefox".to_string(),
+                "libreoffice".to_string(),
+            ],
+            desktop_environment: "GNOME".to_string(),
+            base_image: "ubuntu:24.04".to_string(),
+            release: "noble".to_string(),
+            mirror: None,
+            components: vec!["main".to_string(), "restricted".to_string(), "universe".to_string()],
         },
         DistroTemplate {
             id: "debian-12".to_string(),
@@ -102,6 +359,26 @@ async fn get_distros() -> Json<Vec<DistroTemplate>> {
             ],
             desktop_environment: "GNOME".to_string(),
             base_image: "debian:12".to_string(),
+            release: "bookworm".to_string(),
+            mirror: None,
+            components: vec!["main".to_string()],
+        },
+        DistroTemplate {
+            id: "debian-13".to_string(),
+            name: "Debian 13".to_string(),
+            description: "The next stable release of the universal operating system".to_string(),
+            icon: "❤️".to_string(),
+            category: DistroCategory::Debian,
+            default_packages: vec![
+                "gnome".to_string(),
+                "firefox-esr".to_string(),
+                "libreoffice".to_string(),
+            ],
+            desktop_environment: "GNOME".to_string(),
+            base_image: "debian:13".to_string(),
+            release: "trixie".to_string(),
+            mirror: None,
+            components: vec!["main".to_string()],
         },
         DistroTemplate {
             id: "arch-linux".to_string(),
@@ -116,10 +393,13 @@ async fn get_distros() -> Json<Vec<DistroTemplate>> {
             ],
             desktop_environment: "GNOME".to_string(),
             base_image: "archlinux:latest".to_string(),
+            release: "latest".to_string(),
+            mirror: None,
+            components: vec!["core".to_string(), "extra".to_string()],
         },
         DistroTemplate {
-            id: "fedora-39".to_string(),
-            name: "Fedora 39".to_string(),
+            id: "fedora-40".to_string(),
+            name: "Fedora 40".to_string(),
             description: "Leading-edge platform for developers, artists, and sysadmins".to_string(),
             icon: "🔵".to_string(),
             category: DistroCategory::Fedora,
@@ -129,17 +409,99 @@ async fn get_distros() -> Json<Vec<DistroTemplate>> {
                 "libreoffice".to_string(),
             ],
             desktop_environment: "GNOME".to_string(),
-            base_image: "fedora:39".to_string(),
+            base_image: "fedora:40".to_string(),
+            release: "40".to_string(),
+            mirror: None,
+            components: vec!["Everything".to_string()],
+        },
+        DistroTemplate {
+            id: "fedora-41".to_string(),
+            name: "Fedora 41".to_string(),
+            description: "Leading-edge platform for developers, artists, and sysadmins".to_string(),
+            icon: "🔵".to_string(),
+            category: DistroCategory::Fedora,
+            default_packages: vec![
+                "@gnome-desktop".to_string(),
+                "firefox".to_string(),
+                "libreoffice".to_string(),
+            ],
+            desktop_environment: "GNOME".to_string(),
+            base_image: "fedora:41".to_string(),
+            release: "41".to_string(),
+            mirror: None,
+            components: vec!["Everything".to_string()],
+        },
+        DistroTemplate {
+            id: "rocky-8".to_string(),
+            name: "Rocky Linux 8".to_string(),
+            description: "Enterprise Linux, binary-compatible with RHEL 8".to_string(),
+            icon: "🟢".to_string(),
+            category: DistroCategory::Rocky,
+            default_packages: vec![
+                "@workstation-product-environment".to_string(),
+                "firefox".to_string(),
+            ],
+            desktop_environment: "GNOME".to_string(),
+            base_image: "rockylinux:8".to_string(),
+            release: "8".to_string(),
+            mirror: None,
+            components: vec!["BaseOS".to_string(), "AppStream".to_string()],
+        },
+        DistroTemplate {
+            id: "rocky-9".to_string(),
+            name: "Rocky Linux 9".to_string(),
+            description: "Enterprise Linux, binary-compatible with RHEL 9".to_string(),
+            icon: "🟢".to_string(),
+            category: DistroCategory::Rocky,
+            default_packages: vec![
+                "@workstation-product-environment".to_string(),
+                "firefox".to_string(),
+            ],
+            desktop_environment: "GNOME".to_string(),
+            base_image: "rockylinux:9".to_string(),
+            release: "9".to_string(),
+            mirror: None,
+            components: vec!["BaseOS".to_string(), "AppStream".to_string()],
         },
     ];
 
     Json(distros)
 }
 
+/// Header identifying the submitting user, used for fair scheduling when
+/// `--trust-user-header` is set.
+const USER_HEADER: &str = "x-user-id";
+
 async fn create_iso(
     State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(config): Json<IsoConfig>,
 ) -> Result<Json<BuildJob>, (StatusCode, String)> {
+    identity::validate(&config.system).map_err(bad_request)?;
+    overlays::validate(&config.files, &state.blobs).await.map_err(bad_request)?;
+    squashfs::validate(&config.squashfs).map_err(bad_request)?;
+    sandbox::validate(&config.script_sandbox, &state.script_limits).map_err(bad_request)?;
+    if let Some(boot_test) = &config.boot_test {
+        boot_test::validate(boot_test, &state.boot_test_limits).map_err(bad_request)?;
+    }
+    if let Some(minimize) = &config.minimize {
+        minimize::validate(minimize).map_err(bad_request)?;
+    }
+    
+    // Clients could claim any user, so builds belong to their address
+    // unless a proxy in front vouches for the header.
+    let owner = if state.trust_user_header {
+        headers
+            .get(USER_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty())
+            .unwrap_or("anonymous")
+            .to_string()
+    } else {
+        client.ip().to_string()
+    };
+    
     let job_id = Uuid::new_v4();
     let job = BuildJob {
         id: job_id,
@@ -154,33 +516,24 @@ async fn create_iso(
             level: LogLevel::Info,
             message: "Build job created and queued".to_string(),
         }],
+        owner: owner.clone(),
+        queue_position: None,
+        boot_tests: Vec::new(),
+        download_expires_at: None,
+        artifacts: Vec::new(),
+        reproducibility: None,
+        sbom: None,
+        vulnerabilities: None,
+        size_report: None,
     };
 
     // Store job
-    {
-        let mut jobs = state.jobs.write().await;
-        jobs.insert(job_id, job.clone());
-    }
+    state.jobs.insert(job.clone()).await.map_err(internal_error)?;
 
-    // Start build process
-    let state_clone = state.clone();
-    tokio::spawn(async move {
-        if let Err(e) = state_clone.iso_builder.build_iso(job_id, config).await {
-            error!("Build failed for job {}: {}", job_id, e);
-            
-            // Update job status to failed
-            let mut jobs = state_clone.jobs.write().await;
-            if let Some(job) = jobs.get_mut(&job_id) {
-                job.status = BuildStatus::Failed;
-                job.logs.push(BuildLog {
-                    timestamp: chrono::Utc::now(),
-                    level: LogLevel::Error,
-                    message: format!("Build failed: {}", e),
-                });
-            }
-        }
-    });
+    // Queue build process
+    state.scheduler.submit(job_id, config, owner).await;
 
+    let job = state.jobs.get(job_id).await.map_err(internal_error)?.unwrap_or(job);
     Ok(Json(job))
 }
 
@@ -188,38 +541,231 @@ async fn get_build_job(
     State(state): State<AppState>,
     Path(job_id): Path<Uuid>,
 ) -> Result<Json<BuildJob>, (StatusCode, String)> {
-    let jobs = state.jobs.read().await;
-    
-    match jobs.get(&job_id) {
-        Some(job) => Ok(Json(job.clone())),
+    match state.jobs.get(job_id).await.map_err(internal_error)? {
+        Some(job) => Ok(Json(job)),
         None => Err((StatusCode::NOT_FOUND, "Build job not found".to_string())),
     }
 }
 
-async fn get_gallery(State(state): State<AppState>) -> Json<Vec<BuildJob>> {
-    let jobs = state.jobs.read().await;
-    let completed_jobs = jobs
-        .values()
-        .filter(|job| matches!(job.status, BuildStatus::Completed))
-        .cloned()
-        .collect();
+async fn cancel_build_job(
+    State(state): State<AppState>,
+    Path(job_id): Path<Uuid>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match state.jobs.get(job_id).await.map_err(internal_error)? {
+        Some(job) if job.is_finished() => {
+            return Err((StatusCode::CONFLICT, format!("Build job is already {}", job.status)));
+        }
+        Some(_) => {}
+        None => return Err((StatusCode::NOT_FOUND, "Build job not found".to_string())),
+    }
+    
+    if state.scheduler.cancel(job_id).await {
+        info!("Cancellation requested for job {}", job_id);
+        Ok(StatusCode::ACCEPTED)
+    } else {
+        Err((StatusCode::CONFLICT, "Build job is not running".to_string()))
+    }
+}
+
+async fn get_gallery(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<BuildJob>>, (StatusCode, String)> {
+    let completed_jobs = state.jobs
+        .list(&[BuildStatus::Completed])
+        .await
+        .map_err(internal_error)?;
     
-    Json(completed_jobs)
+    Ok(Json(completed_jobs))
 }
 
-async fn websocket_handler(
+#[derive(Debug, Deserialize)]
+struct DownloadParams {
+    /// Unix time the link expires at.
+    expires: i64,
+    signature: String,
+}
+
+/// Serves a finished build's ISO; `HEAD` and range requests are supported
+/// so downloads can be resumed.
+async fn download_iso(
+    State(state): State<AppState>,
+    Path(job_id): Path<Uuid>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let Some(job) = state.jobs.get(job_id).await.map_err(internal_error)? else {
+        return Err((StatusCode::NOT_FOUND, "Build job not found".to_string()));
+    };
+    let artifact = job.artifacts.iter()
+        .find(|artifact| artifact.kind == ArtifactKind::Iso)
+        .filter(|_| job.status == BuildStatus::Completed)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "ISO is not available for this build".to_string()))?;
+    if job.download_expires_at.is_some_and(|expires_at| expires_at < chrono::Utc::now()) {
+        return Err((StatusCode::GONE, "ISO download has expired".to_string()));
+    }
+    
+    let display_name = format!("{}.iso", job.config.name.trim());
+    download::serve_artifact(
+        state.artifacts.as_ref(),
+        artifact,
+        &display_name,
+        "application/x-iso9660-image",
+        &method,
+        &headers,
+    ).await
+}
+
+/// Serves the JSON breakdown of what takes up space in a build's image.
+async fn get_size_breakdown(
     State(state): State<AppState>,
     Path(job_id): Path<Uuid>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let Some(job) = state.jobs.get(job_id).await.map_err(internal_error)? else {
+        return Err((StatusCode::NOT_FOUND, "Build job not found".to_string()));
+    };
+    let artifact = job.artifacts.iter()
+        .find(|artifact| artifact.kind == ArtifactKind::SizeBreakdown)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Size breakdown is not available for this build".to_string()))?;
+    
+    download::serve_artifact(
+        state.artifacts.as_ref(),
+        artifact,
+        &artifact.name,
+        "application/json",
+        &method,
+        &headers,
+    ).await
+}
+
+/// Serves an artifact from the local store to holders of a signed link.
+async fn download_artifact(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(params): Query<DownloadParams>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let Some(store) = &state.local_artifacts else {
+        return Err((StatusCode::NOT_FOUND, "Artifacts are not served by this server".to_string()));
+    };
+    
+    match store.verify(&key, params.expires, &params.signature) {
+        DownloadCheck::Valid => {}
+        DownloadCheck::Expired => return Err((StatusCode::GONE, "Download link has expired".to_string())),
+        DownloadCheck::Invalid => return Err((StatusCode::FORBIDDEN, "Invalid download link".to_string())),
+    }
+    
+    // Keys start with the id of the job that produced the artifact.
+    let not_found = || (StatusCode::NOT_FOUND, "Artifact not found".to_string());
+    let job_id = key.split('/').next()
+        .and_then(|id| Uuid::parse_str(id).ok())
+        .ok_or_else(not_found)?;
+    let job = state.jobs.get(job_id).await.map_err(internal_error)?.ok_or_else(not_found)?;
+    let artifact = job.artifacts.iter().find(|artifact| artifact.key == key).ok_or_else(not_found)?;
+    
+    download::serve_artifact(
+        state.artifacts.as_ref(),
+        artifact,
+        &artifact.name,
+        "application/octet-stream",
+        &method,
+        &headers,
+    ).await
+}
+
+/// Public half of the key that signs `checksums.sha256`, as a minisign
+/// public key file.
+async fn get_signing_key(State(state): State<AppState>) -> ([(header::HeaderName, &'static str); 1], String) {
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], state.signer.public_key_file())
+}
+
+/// How often blobs past their retention period are looked for.
+const BLOB_CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Periodically removes blobs older than `retention` that no unfinished
+/// build refers to.
+async fn remove_expired_blobs(blobs: Arc<BlobStore>, jobs: JobStore, retention: Duration) {
+    let mut interval = tokio::time::interval(BLOB_CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let unfinished = [BuildStatus::Queued, BuildStatus::Building, BuildStatus::Packaging, BuildStatus::Uploading];
+        let in_use = match jobs.list(&unfinished).await {
+            Ok(jobs) => jobs.into_iter()
+                .flat_map(|job| job.config.files)
+                .filter_map(|file| file.blob)
+                .collect(),
+            Err(e) => {
+                warn!("Failed to list builds before removing expired blobs: {}", e);
+                continue;
+            }
+        };
+        match blobs.remove_older_than(retention, in_use).await {
+            Ok(0) => {}
+            Ok(removed) => info!("Removed {} expired blobs", removed),
+            Err(e) => warn!("Failed to remove expired blobs: {}", e),
+        }
+    }
+}
+
+/// Stores the request body for use in builds, e.g. as a file overlay.
+/// Requires `Authorization: Bearer <--blob-upload-token>`.
+async fn upload_blob(
+    State(state): State<AppState>,
     headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<BlobInfo>, (StatusCode, String)> {
+    let Some(token) = &state.blob_upload_token else {
+        return Err((StatusCode::FORBIDDEN, "Blob uploads are disabled on this server".to_string()));
+    };
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // Comparing digests keeps the comparison time independent of the token.
+    if Sha256::digest(presented.as_bytes()) != Sha256::digest(token.as_bytes()) {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid blob upload token".to_string()));
+    }
+    
+    let blob = state.blobs.put(&body).await.map_err(internal_error)?;
+    info!("Stored blob {} ({} bytes)", blob.sha256, blob.size);
+    Ok(Json(blob))
+}
+
+fn bad_request(error: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, format!("{:#}", error))
+}
+
+fn internal_error(error: anyhow::Error) -> (StatusCode, String) {
+    error!("Request failed: {:#}", error);
+    (StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamParams {
+    /// Index of the first log entry the client has not seen yet.
+    #[serde(default)]
+    log_offset: usize,
+}
+
+async fn websocket_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Path(job_id): Path<Uuid>,
+    Query(params): Query<StreamParams>,
 ) -> Result<Response, StatusCode> {
     // Check if job exists
-    {
-        let jobs = state.jobs.read().await;
-        if !jobs.contains_key(&job_id) {
-            return Err(StatusCode::NOT_FOUND);
+    match state.jobs.get(job_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Failed to look up job {}: {}", job_id, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     }
 
     // Upgrade to WebSocket
-    websocket::handle_websocket(state, job_id).await
+    websocket::handle_websocket(ws, state, job_id, params.log_offset).await
 }
@@ -0,0 +1,200 @@
+use crate::bootstrap::PackageManager;
+use crate::identity;
+use crate::models::{IsoConfig, MinimizeConfig};
+use crate::reproducible::VOLATILE_DIRS;
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Documentation directories emptied in minimal images.
+const DOC_DIRS: &[&str] = &[
+    "usr/share/doc",
+    "usr/share/man",
+    "usr/share/info",
+    "usr/share/gtk-doc",
+    "usr/share/help",
+];
+
+/// Message catalogs, one directory per language.
+const LOCALE_DIR: &str = "usr/share/locale";
+
+/// dpkg configuration keeping documentation out of installed packages.
+const DPKG_EXCLUDES: &str = "etc/dpkg/dpkg.cfg.d/90-iso-builder-minimize";
+
+/// rpm macros limiting the translations installed.
+const RPM_LANGUAGES: &str = "etc/rpm/macros.image-language-conf";
+
+/// Checks `minimize` before a build is queued.
+pub fn validate(minimize: &MinimizeConfig) -> Result<()> {
+    for locale in &minimize.keep_locales {
+        if !identity::is_valid_locale(locale) {
+            return Err(anyhow::anyhow!("Invalid locale {:?} to keep, expected e.g. de or pt_BR", locale));
+        }
+    }
+    Ok(())
+}
+
+/// Names of the translation directories to keep: those of `system.locale`
+/// and of `keep_locales`, each without its charset and also without its
+/// territory, so `pt_BR.UTF-8` keeps `pt_BR` and `pt`.
+pub fn kept_languages(config: &IsoConfig, minimize: &MinimizeConfig) -> BTreeSet<String> {
+    let mut languages = BTreeSet::new();
+    for locale in config.system.locale.iter().chain(&minimize.keep_locales) {
+        let (locale, modifier) = match locale.split_once('@') {
+            Some((locale, modifier)) => (locale, Some(modifier)),
+            None => (locale.as_str(), None),
+        };
+        let locale = locale.split('.').next().unwrap_or(locale);
+        let language = locale.split('_').next().unwrap_or(locale);
+        for name in [locale, language] {
+            languages.insert(name.to_string());
+            if let Some(modifier) = modifier {
+                languages.insert(format!("{}@{}", name, modifier));
+            }
+        }
+    }
+    languages
+}
+
+/// Configures the package manager in `chroot_dir` to skip documentation and
+/// translations other than `languages` in every package installed later.
+pub async fn install_rules(chroot_dir: &Path, package_manager: PackageManager, languages: &BTreeSet<String>) -> Result<()> {
+    match package_manager {
+        PackageManager::Apt => {
+            let mut rules = String::from("# Written by the ISO builder\n");
+            for dir in DOC_DIRS {
+                rules.push_str(&format!("path-exclude=/{}/*\n", dir));
+            }
+            // Kept for license compliance; the SBOM reads them too.
+            rules.push_str("path-include=/usr/share/doc/*/copyright\n");
+            rules.push_str(&format!("path-exclude=/{}/*\n", LOCALE_DIR));
+            rules.push_str(&format!("path-include=/{}/locale.alias\n", LOCALE_DIR));
+            for language in languages {
+                rules.push_str(&format!("path-include=/{}/{}/*\n", LOCALE_DIR, language));
+            }
+            let path = chroot_dir.join(DPKG_EXCLUDES);
+            fs::create_dir_all(path.parent().unwrap()).await?;
+            fs::write(&path, rules).await?;
+        }
+        PackageManager::Dnf => {
+            set_option(&chroot_dir.join("etc/dnf/dnf.conf"), "[main]", "tsflags", "tsflags=nodocs").await?;
+            let install_langs: Vec<&str> = std::iter::once("C").chain(languages.iter().map(String::as_str)).collect();
+            let path = chroot_dir.join(RPM_LANGUAGES);
+            fs::create_dir_all(path.parent().unwrap()).await?;
+            fs::write(&path, format!("%_install_langs {}\n", install_langs.join(":"))).await?;
+        }
+        PackageManager::Pacman => {
+            let mut patterns: Vec<String> = DOC_DIRS.iter().map(|dir| format!("{}/*", dir)).collect();
+            patterns.push(format!("{}/*", LOCALE_DIR));
+            // Later patterns win, so these exceptions come last.
+            patterns.push(format!("!{}/locale.alias", LOCALE_DIR));
+            for language in languages {
+                patterns.push(format!("!{}/{}/*", LOCALE_DIR, language));
+            }
+            let line = format!("NoExtract = {}", patterns.join(" "));
+            set_option(&chroot_dir.join("etc/pacman.conf"), "[options]", "NoExtract", &line).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces `key` in the ini-style file at `path` with `line`, placed at
+/// the start of `section`.
+async fn set_option(path: &Path, section: &str, key: &str, line: &str) -> Result<()> {
+    let contents = match fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let mut lines: Vec<&str> = contents.lines()
+        .filter(|existing| existing.split_once('=').map(|(name, _)| name.trim()) != Some(key))
+        .collect();
+    match lines.iter().position(|existing| existing.trim() == section) {
+        Some(index) => lines.insert(index + 1, line),
+        None => {
+            lines.push(section);
+            lines.push(line);
+        }
+    }
+    fs::write(path, format!("{}\n", lines.join("\n"))).await
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Removes documentation, translations other than `languages`, package
+/// caches and logs from `chroot_dir`. Returns the bytes freed.
+pub async fn prune(chroot_dir: &Path, package_manager: PackageManager, languages: BTreeSet<String>) -> Result<u64> {
+    let chroot_dir = chroot_dir.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut freed = 0;
+        for dir in DOC_DIRS {
+            let keep_copyright = package_manager == PackageManager::Apt && *dir == "usr/share/doc";
+            freed += remove_under(&chroot_dir.join(dir), true, &|relative, metadata| {
+                // Packages whose documentation lives in another package's
+                // directory link to it.
+                keep_copyright
+                    && (metadata.file_type().is_symlink() || relative.file_name().is_some_and(|name| name == "copyright"))
+            })?;
+        }
+        freed += remove_under(&chroot_dir.join(LOCALE_DIR), true, &|relative, metadata| {
+            // e.g. locale.alias next to the language directories
+            let top_level_file = relative.components().count() == 1 && !metadata.is_dir();
+            top_level_file || relative.components().next()
+                .is_some_and(|language| languages.contains(&*language.as_os_str().to_string_lossy()))
+        })?;
+        for dir in VOLATILE_DIRS {
+            freed += remove_under(&chroot_dir.join(dir), false, &|_, _| false)?;
+        }
+        Ok(freed)
+    })
+    .await?
+}
+
+/// Removes the files under `root` that `keep` does not match, given their
+/// path relative to `root`, and the directories left empty when
+/// `remove_dirs` is set. Returns the bytes freed; files with other hard
+/// links free nothing.
+fn remove_under(root: &Path, remove_dirs: bool, keep: &dyn Fn(&Path, &std::fs::Metadata) -> bool) -> Result<u64> {
+    let mut freed = 0;
+    let mut dirs = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        let dir = root.join(&relative);
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let relative = relative.join(entry.file_name());
+            let metadata = std::fs::symlink_metadata(entry.path())?;
+            if keep(&relative, &metadata) {
+                continue;
+            }
+            if metadata.is_dir() {
+                pending.push(relative.clone());
+                dirs.push(relative);
+            } else {
+                std::fs::remove_file(entry.path())
+                    .with_context(|| format!("Failed to remove {}", entry.path().display()))?;
+                if metadata.nlink() == 1 {
+                    freed += metadata.len();
+                }
+            }
+        }
+    }
+    if remove_dirs {
+        // Deepest first; directories still holding kept files stay.
+        for relative in dirs.iter().rev() {
+            match std::fs::remove_dir(root.join(relative)) {
+                Err(e) if e.kind() != std::io::ErrorKind::DirectoryNotEmpty => {
+                    return Err(e).with_context(|| format!("Failed to remove {}", relative.display()));
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(freed)
+}
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -12,14 +13,24 @@ pub struct DistroTemplate {
     pub default_packages: Vec<String>,
     pub desktop_environment: String,
     pub base_image: String,
+    /// Release the base system is bootstrapped from, e.g. `bookworm`, `noble`, `41` or `9`.
+    #[serde(default)]
+    pub release: String,
+    /// Package mirror; the bootstrap backend's default mirror is used when unset.
+    #[serde(default)]
+    pub mirror: Option<String>,
+    /// Repository components (apt) or repositories (dnf, pacman) to enable.
+    #[serde(default)]
+    pub components: Vec<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum DistroCategory {
     Ubuntu,
     Debian,
     Arch,
     Fedora,
+    Rocky,
     Custom,
 }
 
@@ -29,10 +40,518 @@ pub struct IsoConfig {
     pub name: String,
     pub distro: DistroTemplate,
     pub packages: Vec<String>,
-    pub custom_scripts: Vec<String>,
+    pub custom_scripts: Vec<CustomScript>,
     pub desktop_environment: Option<String>,
     pub theme: ThemeConfig,
     pub created_at: DateTime<Utc>,
+    /// Overrides the distro template's mirror, e.g. for an internal mirror.
+    #[serde(default)]
+    pub mirror: Option<String>,
+    /// Components enabled in addition to the template's.
+    #[serde(default)]
+    pub extra_components: Vec<String>,
+    #[serde(default)]
+    pub priority: BuildPriority,
+    /// Boots the finished ISO in QEMU before it is uploaded.
+    #[serde(default)]
+    pub boot_test: Option<BootTestConfig>,
+    /// Builds the image reproducibly when set.
+    #[serde(default)]
+    pub reproducible: Option<ReproducibleConfig>,
+    /// Scans the installed packages against the server's vulnerability databases.
+    #[serde(default)]
+    pub vulnerability_scan: Option<VulnerabilityScanConfig>,
+    /// Limits applied to each of `custom_scripts` run during the build.
+    #[serde(default)]
+    pub script_sandbox: ScriptSandboxConfig,
+    /// Files written into the image after theming, before post-install scripts.
+    #[serde(default)]
+    pub files: Vec<FileOverlay>,
+    /// Hostname, users, locale, timezone and keyboard; distro defaults when unset.
+    #[serde(default)]
+    pub system: SystemConfig,
+    /// Images built in addition to the ISO, each stored as its own artifact.
+    #[serde(default)]
+    pub output_formats: Vec<OutputFormat>,
+    /// Compression of the root filesystem on the ISO.
+    #[serde(default)]
+    pub squashfs: SquashfsConfig,
+    /// Leaves documentation, unused translations, package caches and logs
+    /// out of the image.
+    #[serde(default)]
+    pub minimize: Option<MinimizeConfig>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MinimizeConfig {
+    /// Languages whose translations are kept in addition to that of
+    /// `system.locale`, e.g. `de` or `pt_BR`.
+    #[serde(default)]
+    pub keep_locales: Vec<String>,
+}
+
+/// How the root filesystem is compressed into the ISO's squashfs.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SquashfsConfig {
+    #[serde(default)]
+    pub compressor: SquashfsCompressor,
+    /// 1-9 for gzip and 1-22 for zstd; xz and lz4 have no levels.
+    #[serde(default)]
+    pub level: Option<u32>,
+    /// Block size in bytes, a power of two from 4 KiB to 1 MiB; 128 KiB when unset.
+    #[serde(default)]
+    pub block_size: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SquashfsCompressor {
+    /// What `mksquashfs` uses by default.
+    #[default]
+    Gzip,
+    /// Smallest images, slowest to build and boot.
+    Xz,
+    Zstd,
+    /// Fastest to decompress, largest images.
+    Lz4,
+}
+
+impl SquashfsCompressor {
+    /// Name `mksquashfs -comp` takes.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SquashfsCompressor::Gzip => "gzip",
+            SquashfsCompressor::Xz => "xz",
+            SquashfsCompressor::Zstd => "zstd",
+            SquashfsCompressor::Lz4 => "lz4",
+        }
+    }
+
+    /// Compression levels the compressor accepts, if it has any.
+    pub fn levels(&self) -> Option<std::ops::RangeInclusive<u32>> {
+        match self {
+            SquashfsCompressor::Gzip => Some(1..=9),
+            SquashfsCompressor::Zstd => Some(1..=22),
+            SquashfsCompressor::Xz | SquashfsCompressor::Lz4 => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputFormat {
+    /// Raw GPT disk with a BIOS boot partition, an EFI system partition and
+    /// an ext4 root, bootable in BIOS and UEFI mode.
+    RawDisk,
+    /// The raw disk converted to qcow2, e.g. for Proxmox or libvirt.
+    Qcow2,
+    /// The raw disk converted to a dynamic VHDX, e.g. for Hyper-V.
+    Vhdx,
+    /// Kernel, initramfs, squashfs and an iPXE script for network boot.
+    Pxe,
+}
+
+/// Scheduling lane for a build; higher lanes are always dispatched first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum BuildPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BootTestConfig {
+    /// Text on the serial console that means the system booted, e.g. a login prompt.
+    #[serde(default = "default_boot_success_pattern")]
+    pub success_pattern: String,
+    /// How long each boot may take before the test fails, up to the
+    /// server's maximum.
+    #[serde(default = "default_boot_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Memory of the virtual machine, up to the server's maximum.
+    #[serde(default = "default_boot_memory_mb")]
+    pub memory_mb: u32,
+    /// Fails the build when a boot test does not pass.
+    #[serde(default)]
+    pub required: bool,
+}
+
+fn default_boot_success_pattern() -> String {
+    "login:".to_string()
+}
+
+fn default_boot_timeout_secs() -> u64 {
+    900
+}
+
+fn default_boot_memory_mb() -> u32 {
+    2048
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SystemConfig {
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// e.g. `en_US.UTF-8`.
+    #[serde(default)]
+    pub locale: Option<String>,
+    /// Zone from the tz database, e.g. `Europe/Berlin`.
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub keyboard: Option<KeyboardConfig>,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    /// Installs and enables sshd with this policy; a default hardened policy
+    /// is used when unset but a user has authorized keys.
+    #[serde(default)]
+    pub ssh: Option<SshConfig>,
+}
+
+impl SystemConfig {
+    /// sshd policy of the image, if it runs sshd.
+    pub fn ssh_policy(&self) -> Option<SshConfig> {
+        match &self.ssh {
+            Some(ssh) => Some(ssh.clone()),
+            None if self.users.iter().any(|user| !user.authorized_keys.is_empty()) => Some(SshConfig::default()),
+            None => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SshConfig {
+    #[serde(default)]
+    pub password_authentication: bool,
+    #[serde(default)]
+    pub permit_root_login: bool,
+    /// Ciphers sshd offers; AEAD and CTR modes when empty.
+    #[serde(default)]
+    pub ciphers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyboardConfig {
+    /// XKB layout, e.g. `de`; also used as the console keymap.
+    pub layout: String,
+    /// XKB variant, e.g. `nodeadkeys`.
+    #[serde(default)]
+    pub variant: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserConfig {
+    pub name: String,
+    #[serde(default)]
+    pub full_name: Option<String>,
+    /// crypt(3) hash, e.g. from `mkpasswd -m sha-512`; the account is locked
+    /// for password logins when unset. Plain-text passwords are rejected.
+    /// Never serialized, so it stays out of API responses, job events and
+    /// the job database; only the running build sees it.
+    #[serde(default, skip_serializing)]
+    pub password_hash: Option<String>,
+    /// Allows the user to run any command through `sudo`.
+    #[serde(default)]
+    pub sudo: bool,
+    /// Login shell; the distro's default when unset.
+    #[serde(default)]
+    pub shell: Option<String>,
+    /// Supplementary groups, which must exist in the image.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// OpenSSH public keys written to the user's `~/.ssh/authorized_keys`.
+    #[serde(default)]
+    pub authorized_keys: Vec<String>,
+}
+
+/// A file placed into the image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileOverlay {
+    /// Absolute path in the image; parent directories are created.
+    pub path: String,
+    /// Inline contents; exactly one of `content` and `blob` is set.
+    #[serde(default)]
+    pub content: Option<String>,
+    /// SHA-256 of a file uploaded to `/api/blobs`.
+    #[serde(default)]
+    pub blob: Option<String>,
+    /// Octal permissions, e.g. `0600`.
+    #[serde(default = "default_overlay_mode")]
+    pub mode: String,
+    /// `user:group` as names from the image or numeric ids.
+    #[serde(default = "default_overlay_owner")]
+    pub owner: String,
+    /// Replaces `{{ variable }}` placeholders in the contents, see
+    /// `overlays::TEMPLATE_VARIABLES`.
+    #[serde(default)]
+    pub template: bool,
+}
+
+fn default_overlay_mode() -> String {
+    "0644".to_string()
+}
+
+fn default_overlay_owner() -> String {
+    "root:root".to_string()
+}
+
+/// A user script run while building the image or on the installed system.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "CustomScriptEntry")]
+pub struct CustomScript {
+    /// Shown in the build log and used for the script's file and unit names.
+    pub name: Option<String>,
+    pub phase: ScriptPhase,
+    /// Runs the script by its shebang, or with `sh` if it has none, when unset.
+    pub interpreter: Option<ScriptInterpreter>,
+    /// Scripts of a phase run in ascending order; ties keep their list order.
+    pub order: i32,
+    pub content: String,
+}
+
+/// A script as submitted: either just its content, run after the packages
+/// are installed, or the full description.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CustomScriptEntry {
+    Content(String),
+    Script {
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        phase: ScriptPhase,
+        #[serde(default)]
+        interpreter: Option<ScriptInterpreter>,
+        #[serde(default)]
+        order: i32,
+        content: String,
+    },
+}
+
+impl From<CustomScriptEntry> for CustomScript {
+    fn from(entry: CustomScriptEntry) -> Self {
+        match entry {
+            CustomScriptEntry::Content(content) => Self {
+                name: None,
+                phase: ScriptPhase::default(),
+                interpreter: None,
+                order: 0,
+                content,
+            },
+            CustomScriptEntry::Script { name, phase, interpreter, order, content } => Self {
+                name,
+                phase,
+                interpreter,
+                order,
+                content,
+            },
+        }
+    }
+}
+
+/// When a custom script runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScriptPhase {
+    /// In the sandbox, after the base system is bootstrapped and before any
+    /// package is installed, e.g. to add a repository.
+    PreInstall,
+    /// In the sandbox, after packages and theming.
+    #[default]
+    PostInstall,
+    /// On the booted system, once, from a systemd oneshot unit, e.g. for
+    /// steps that need the target hardware.
+    FirstBoot,
+}
+
+impl std::fmt::Display for ScriptPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ScriptPhase::PreInstall => "pre-install",
+            ScriptPhase::PostInstall => "post-install",
+            ScriptPhase::FirstBoot => "first-boot",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScriptInterpreter {
+    Sh,
+    Bash,
+    Python3,
+}
+
+impl ScriptInterpreter {
+    /// Path of the interpreter in the image.
+    pub fn path(&self) -> &'static str {
+        match self {
+            ScriptInterpreter::Sh => "/bin/sh",
+            ScriptInterpreter::Bash => "/bin/bash",
+            ScriptInterpreter::Python3 => "/usr/bin/python3",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptSandboxConfig {
+    /// Wall-clock time each script may run before it is killed, up to the
+    /// server's maximum.
+    #[serde(default = "default_script_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Memory limit per script, up to the server's maximum.
+    #[serde(default = "default_script_memory_mb")]
+    pub memory_mb: Option<u64>,
+    /// CPU limit per script in percent of one core, up to the server's maximum.
+    #[serde(default = "default_script_cpu_percent")]
+    pub cpu_percent: Option<u32>,
+    #[serde(default)]
+    pub network: ScriptNetwork,
+    /// Fails the build when a script fails or times out.
+    #[serde(default)]
+    pub fail_on_error: bool,
+}
+
+impl Default for ScriptSandboxConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: default_script_timeout_secs(),
+            memory_mb: default_script_memory_mb(),
+            cpu_percent: default_script_cpu_percent(),
+            network: ScriptNetwork::default(),
+            fail_on_error: false,
+        }
+    }
+}
+
+fn default_script_timeout_secs() -> u64 {
+    1800
+}
+
+fn default_script_memory_mb() -> Option<u64> {
+    Some(2048)
+}
+
+fn default_script_cpu_percent() -> Option<u32> {
+    Some(200)
+}
+
+/// Network access of a custom script.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScriptNetwork {
+    /// Only a loopback interface.
+    #[default]
+    None,
+    /// The host's network, e.g. to download files.
+    Host,
+}
+
+/// Firmware a boot test runs under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BootFirmware {
+    /// Legacy BIOS boot via SeaBIOS.
+    Bios,
+    /// UEFI boot via OVMF.
+    Uefi,
+}
+
+impl std::fmt::Display for BootFirmware {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BootFirmware::Bios => "BIOS",
+            BootFirmware::Uefi => "UEFI",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BootTestResult {
+    pub firmware: BootFirmware,
+    pub passed: bool,
+    pub duration_secs: u64,
+    /// Why the test failed, if it did.
+    pub error: Option<String>,
+    /// Serial console output, truncated to its last part for long boots.
+    pub transcript: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReproducibleConfig {
+    /// Timestamp used for every file and image date, in seconds since the
+    /// Unix epoch. Defaults to the config's `created_at`.
+    #[serde(default)]
+    pub source_date_epoch: Option<u64>,
+    /// Builds the image a second time and compares the two results.
+    #[serde(default)]
+    pub verify: bool,
+}
+
+/// Outcome of rebuilding an image to check that it is reproducible.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReproducibilityReport {
+    pub source_date_epoch: u64,
+    pub reproducible: bool,
+    /// Hex-encoded SHA-256 of the ISO from each build.
+    pub sha256: String,
+    pub rebuild_sha256: String,
+    /// Paths that differ between the builds, prefixed with `iso/` or
+    /// `rootfs/`; long lists are cut short.
+    pub differences: Vec<String>,
+    /// Number of differing paths, including those left out of `differences`.
+    pub total_differences: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VulnerabilityScanConfig {
+    /// Fails the build when a vulnerability of at least this severity is found.
+    #[serde(default)]
+    pub fail_on: Option<VulnerabilitySeverity>,
+}
+
+/// Severity of a vulnerability, from least to most severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum VulnerabilitySeverity {
+    /// The database does not rate it.
+    Unknown,
+    Negligible,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl VulnerabilitySeverity {
+    /// Qualitative rating of a CVSS v3 base score.
+    pub fn from_cvss(score: f64) -> Self {
+        match score {
+            score if score >= 9.0 => VulnerabilitySeverity::Critical,
+            score if score >= 7.0 => VulnerabilitySeverity::High,
+            score if score >= 4.0 => VulnerabilitySeverity::Medium,
+            score if score > 0.0 => VulnerabilitySeverity::Low,
+            _ => VulnerabilitySeverity::Negligible,
+        }
+    }
+}
+
+impl std::fmt::Display for VulnerabilitySeverity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            VulnerabilitySeverity::Unknown => "unknown",
+            VulnerabilitySeverity::Negligible => "negligible",
+            VulnerabilitySeverity::Low => "low",
+            VulnerabilitySeverity::Medium => "medium",
+            VulnerabilitySeverity::High => "high",
+            VulnerabilitySeverity::Critical => "critical",
+        })
+    }
+}
+
+/// Summary of a build's vulnerability scan.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VulnerabilitySummary {
+    pub total: usize,
+    /// Number of findings per severity.
+    pub by_severity: BTreeMap<VulnerabilitySeverity, usize>,
+    pub highest: Option<VulnerabilitySeverity>,
+    /// Number of database files the packages were checked against.
+    pub databases: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -61,9 +580,104 @@ pub struct BuildJob {
     pub completed_at: Option<DateTime<Utc>>,
     pub download_url: Option<String>,
     pub logs: Vec<BuildLog>,
+    /// User the build was submitted for, used for fair scheduling.
+    #[serde(default)]
+    pub owner: String,
+    /// 1-based position in the build queue while the job is `Queued`.
+    #[serde(default)]
+    pub queue_position: Option<usize>,
+    #[serde(default)]
+    pub boot_tests: Vec<BootTestResult>,
+    /// When `download_url` stops working.
+    #[serde(default)]
+    pub download_expires_at: Option<DateTime<Utc>>,
+    /// Files produced by the build, in the order they were stored.
+    #[serde(default)]
+    pub artifacts: Vec<BuildArtifact>,
+    /// Set when a reproducible build was verified by rebuilding it.
+    #[serde(default)]
+    pub reproducibility: Option<ReproducibilityReport>,
+    /// What the SBOMs of the image list.
+    #[serde(default)]
+    pub sbom: Option<SbomSummary>,
+    #[serde(default)]
+    pub vulnerabilities: Option<VulnerabilitySummary>,
+    /// Sizes of the root filesystem and ISO, set once the ISO is built.
+    #[serde(default)]
+    pub size_report: Option<SizeReport>,
+}
+
+/// How much the root filesystem shrank and what compressing it cost.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SizeReport {
+    pub compressor: SquashfsCompressor,
+    /// Bytes in the files that went into the squashfs, before compression.
+    pub rootfs_size: u64,
+    pub squashfs_size: u64,
+    pub iso_size: u64,
+    /// Time `mksquashfs` took, in milliseconds.
+    pub compression_time_ms: u64,
+    /// Bytes minimal-footprint mode removed from the root filesystem.
+    #[serde(default)]
+    pub bytes_saved: Option<u64>,
+}
+
+/// Summary of the packages listed in a build's SBOMs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SbomSummary {
+    pub package_manager: String,
+    pub package_count: usize,
+    /// Packages whose license could not be expressed as an SPDX expression.
+    pub packages_without_license: usize,
+    /// Packages whose repository metadata did not give a checksum.
+    pub packages_without_checksum: usize,
+    /// Number of packages per SPDX license expression, `NOASSERTION` for unknown.
+    pub licenses: BTreeMap<String, usize>,
 }
 
+/// A file produced by a build and kept in the artifact store.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildArtifact {
+    #[serde(default)]
+    pub kind: ArtifactKind,
+    /// File name offered to users.
+    pub name: String,
+    /// Key of the file in the artifact store.
+    pub key: String,
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the file.
+    #[serde(default)]
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArtifactKind {
+    /// The bootable ISO image.
+    #[default]
+    Iso,
+    /// `checksums.sha256` listing every other artifact of the build.
+    Checksums,
+    /// Minisign signature of the checksums manifest.
+    Signature,
+    /// `build-manifest.json` recording the inputs of the build.
+    BuildManifest,
+    /// SPDX 2.3 JSON SBOM of the installed packages.
+    SpdxSbom,
+    /// CycloneDX 1.5 JSON SBOM of the installed packages.
+    CycloneDxSbom,
+    /// JSON report of the vulnerabilities found in the installed packages.
+    VulnerabilityReport,
+    /// JSON breakdown of the image's size by package and path.
+    SizeBreakdown,
+    /// Raw GPT disk image.
+    RawDisk,
+    Qcow2,
+    Vhdx,
+    /// Tar archive with everything needed to boot the image over the network.
+    NetbootBundle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum BuildStatus {
     Queued,
     Building,
@@ -71,6 +685,29 @@ pub enum BuildStatus {
     Uploading,
     Completed,
     Failed,
+    Cancelled,
+}
+
+impl BuildJob {
+    /// Whether the job has reached a terminal status.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.status, BuildStatus::Completed | BuildStatus::Failed | BuildStatus::Cancelled)
+    }
+}
+
+impl std::fmt::Display for BuildStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self {
+            BuildStatus::Queued => "queued",
+            BuildStatus::Building => "building",
+            BuildStatus::Packaging => "packaging",
+            BuildStatus::Uploading => "uploading",
+            BuildStatus::Completed => "completed",
+            BuildStatus::Failed => "failed",
+            BuildStatus::Cancelled => "cancelled",
+        };
+        f.write_str(label)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -107,6 +744,13 @@ pub struct WebSocketMessage {
     pub data: serde_json::Value,
 }
 
+/// Messages a client may send over the job WebSocket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Cancel,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MessageType {
     StatusUpdate,
@@ -0,0 +1,228 @@
+use crate::bootstrap::LiveBoot;
+use crate::boot;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncSeekExt;
+use tokio::process::Command as AsyncCommand;
+use uuid::Uuid;
+
+const SECTOR_SIZE: u64 = 512;
+const MIB: u64 = 1024 * 1024;
+
+/// The BIOS boot partition holding GRUB's core image starts at 1 MiB, the
+/// usual alignment, and is 1 MiB long.
+const BIOS_BOOT_START: u64 = MIB;
+const BIOS_BOOT_SIZE: u64 = MIB;
+
+/// Free space in the root filesystem on top of a fifth of its contents.
+const ROOT_HEADROOM: u64 = 512 * MIB;
+
+/// Space for the secondary GPT at the end of the disk.
+const GPT_BACKUP_SIZE: u64 = MIB;
+
+/// Identifiers of a disk image. Random, or derived from the config in
+/// reproducible builds so both builds agree.
+pub struct DiskIds {
+    pub disk: Uuid,
+    pub bios_boot: Uuid,
+    pub esp: Uuid,
+    pub root: Uuid,
+    /// UUID of the root filesystem, which GRUB and the kernel look for.
+    pub root_filesystem: Uuid,
+    /// Seeds the ext4 directory hashes.
+    pub hash_seed: Uuid,
+}
+
+impl DiskIds {
+    pub fn new(seed: Option<&str>) -> Self {
+        let id = |name: &str| match seed {
+            Some(seed) => {
+                let digest = Sha256::digest(format!("{}:{}", seed, name));
+                let bytes: [u8; 16] = digest[..16].try_into().expect("SHA-256 is 32 bytes");
+                uuid::Builder::from_random_bytes(bytes).into_uuid()
+            }
+            None => Uuid::new_v4(),
+        };
+        Self {
+            disk: id("disk"),
+            bios_boot: id("bios-boot"),
+            esp: id("esp"),
+            root: id("root"),
+            root_filesystem: id("root-filesystem"),
+            hash_seed: id("hash-seed"),
+        }
+    }
+}
+
+/// Where the partitions of a disk image go, in bytes.
+pub struct DiskLayout {
+    pub esp_start: u64,
+    pub esp_size: u64,
+    pub root_start: u64,
+    pub root_size: u64,
+    pub total_size: u64,
+}
+
+impl DiskLayout {
+    pub fn new(esp_size: u64, root_size: u64) -> Self {
+        let esp_start = BIOS_BOOT_START + BIOS_BOOT_SIZE;
+        let esp_size = esp_size.div_ceil(MIB) * MIB;
+        let root_start = esp_start + esp_size;
+        let root_size = root_size.div_ceil(MIB) * MIB;
+        Self {
+            esp_start,
+            esp_size,
+            root_start,
+            root_size,
+            total_size: root_start + root_size + GPT_BACKUP_SIZE,
+        }
+    }
+
+    pub fn bios_boot_start_sector(&self) -> u64 {
+        BIOS_BOOT_START / SECTOR_SIZE
+    }
+
+    pub fn bios_boot_sectors(&self) -> u64 {
+        BIOS_BOOT_SIZE / SECTOR_SIZE
+    }
+
+    /// `sgdisk` invocation writing the partition table to `disk`.
+    pub fn partition_command(&self, disk: &Path, ids: &DiskIds) -> AsyncCommand {
+        let partitions = [
+            (1, BIOS_BOOT_START, BIOS_BOOT_SIZE, "EF02", ids.bios_boot, "bios"),
+            (2, self.esp_start, self.esp_size, "EF00", ids.esp, "esp"),
+            // Linux root (x86-64), found automatically by systemd-gpt-auto-generator.
+            (3, self.root_start, self.root_size, "8304", ids.root, "root"),
+        ];
+        let mut cmd = AsyncCommand::new("sgdisk");
+        cmd.arg("--clear").arg(format!("--disk-guid={}", ids.disk));
+        for (number, start, size, type_code, guid, name) in partitions {
+            let first = start / SECTOR_SIZE;
+            let last = (start + size) / SECTOR_SIZE - 1;
+            cmd.arg(format!("--new={}:{}:{}", number, first, last))
+                .arg(format!("--typecode={}:{}", number, type_code))
+                .arg(format!("--partition-guid={}:{}", number, guid))
+                .arg(format!("--change-name={}:{}", number, name));
+        }
+        cmd.arg(disk);
+        cmd
+    }
+}
+
+/// Size the root filesystem needs to hold everything under `root`.
+pub async fn root_filesystem_size(root: &Path) -> Result<u64> {
+    let root = root.to_path_buf();
+    let used = tokio::task::spawn_blocking(move || tree_size(&root)).await??;
+    Ok(used + used / 5 + ROOT_HEADROOM)
+}
+
+/// Bytes the files under `dir` take up in 4 KiB blocks, plus an inode each.
+fn tree_size(dir: &Path) -> Result<u64> {
+    let mut total = 0;
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read {}", dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let metadata = std::fs::symlink_metadata(entry.path())?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            }
+            total += metadata.len().div_ceil(4096) * 4096 + 256;
+        }
+    }
+    Ok(total)
+}
+
+/// `mkfs.ext4` invocation creating the root filesystem image at `output`
+/// from the contents of `chroot_dir`, without mounting anything.
+pub fn root_filesystem_command(chroot_dir: &Path, output: &Path, size: u64, ids: &DiskIds) -> AsyncCommand {
+    let mut cmd = AsyncCommand::new("mkfs.ext4");
+    cmd.args(["-q", "-F", "-L", "root"])
+        .arg("-U").arg(ids.root_filesystem.to_string())
+        .arg("-E").arg(format!("root_owner=0:0,hash_seed={}", ids.hash_seed))
+        .arg("-d").arg(chroot_dir)
+        .arg(output)
+        .arg(format!("{}k", size.div_ceil(1024)));
+    cmd
+}
+
+/// Copies the partition image at `image` into `disk` at byte `offset`.
+pub async fn write_partition(disk: &Path, image: &Path, offset: u64) -> Result<()> {
+    let mut source = fs::File::open(image).await
+        .with_context(|| format!("Failed to open {}", image.display()))?;
+    let mut target = fs::OpenOptions::new().write(true).open(disk).await
+        .with_context(|| format!("Failed to open {}", disk.display()))?;
+    target.seek(std::io::SeekFrom::Start(offset)).await?;
+    tokio::io::copy(&mut source, &mut target).await
+        .with_context(|| format!("Failed to copy {} into the disk image", image.display()))?;
+    target.sync_all().await?;
+    Ok(())
+}
+
+/// `qemu-img` invocation converting the raw disk image `raw` to `format`.
+pub fn convert_command(raw: &Path, output: &Path, format: &str) -> AsyncCommand {
+    let mut cmd = AsyncCommand::new("qemu-img");
+    cmd.args(["convert", "-f", "raw", "-O", format]);
+    if format == "vhdx" {
+        cmd.args(["-o", "subformat=dynamic"]);
+    }
+    cmd.arg(raw).arg(output);
+    cmd
+}
+
+/// Writes the netboot bundle for the ISO tree in `iso_dir` to `output`: the
+/// kernel, initramfs and squashfs, plus `boot.ipxe`. Initramfs that can only
+/// boot an ISO get the ISO at `iso_path` instead of the squashfs.
+/// Entries are dated `mtime` so reproducible builds produce the same archive.
+pub async fn write_netboot_bundle(
+    iso_dir: &Path,
+    output: &Path,
+    title: &str,
+    live_boot: &LiveBoot,
+    iso_name: &str,
+    iso_path: &Path,
+    mtime: u64,
+) -> Result<()> {
+    let script = boot::ipxe_script(title, &live_boot.netboot_args("${base-url}", iso_name));
+    let root_filesystem = if live_boot.netboot_args.contains("{iso}") {
+        (iso_name.to_string(), iso_path.to_path_buf())
+    } else {
+        (live_boot.squashfs_path.to_string(), iso_dir.join(live_boot.squashfs_path))
+    };
+    let files: Vec<(String, PathBuf)> = vec![
+        ("vmlinuz".to_string(), iso_dir.join(boot::ISO_KERNEL_PATH)),
+        ("initrd.img".to_string(), iso_dir.join(boot::ISO_INITRD_PATH)),
+        root_filesystem,
+    ];
+    let output = output.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::create(&output)
+            .with_context(|| format!("Failed to create {}", output.display()))?;
+        let mut archive = tar::Builder::new(std::io::BufWriter::new(file));
+        let header = |size: u64| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(size);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(mtime);
+            header
+        };
+
+        archive.append_data(&mut header(script.len() as u64), "boot.ipxe", script.as_bytes())?;
+        for (name, path) in files {
+            let source = std::fs::File::open(&path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            let size = source.metadata()?.len();
+            archive.append_data(&mut header(size), &name, source)?;
+        }
+        archive.into_inner()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    })
+    .await?
+}
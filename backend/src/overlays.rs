@@ -0,0 +1,293 @@
+use crate::blobs::BlobStore;
+use crate::models::{FileOverlay, IsoConfig};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs;
+use uuid::Uuid;
+
+/// Placeholders a template overlay may use, as `{{ name }}`.
+pub const TEMPLATE_VARIABLES: &[&str] = &["name", "job_id", "distro", "release", "created_at"];
+
+/// Values of `TEMPLATE_VARIABLES` for a build.
+pub fn template_variables(job_id: Uuid, config: &IsoConfig) -> BTreeMap<&'static str, String> {
+    BTreeMap::from([
+        ("name", config.name.clone()),
+        ("job_id", job_id.to_string()),
+        ("distro", config.distro.id.clone()),
+        ("release", config.distro.release.clone()),
+        ("created_at", config.created_at.to_rfc3339()),
+    ])
+}
+
+/// Symbolic links followed while resolving one path before giving up.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Checks `files` before a build is queued: paths must be absolute and stay
+/// inside the root filesystem, each file needs exactly one source, blobs
+/// must have been uploaded, and modes, owners and templates must parse.
+pub async fn validate(files: &[FileOverlay], blobs: &BlobStore) -> Result<()> {
+    let mut seen = HashSet::new();
+    for file in files {
+        let path = image_path(&file.path)?;
+        if !seen.insert(path) {
+            return Err(anyhow::anyhow!("File {} is listed more than once", file.path));
+        }
+        parse_mode(&file.mode).with_context(|| format!("Invalid mode for {}", file.path))?;
+        parse_owner(&file.owner).with_context(|| format!("Invalid owner for {}", file.path))?;
+
+        match (&file.content, &file.blob) {
+            (Some(content), None) => {
+                if file.template {
+                    let placeholders = TEMPLATE_VARIABLES.iter().map(|name| (*name, String::new())).collect();
+                    render(content, &placeholders).with_context(|| format!("Invalid template {}", file.path))?;
+                }
+            }
+            (None, Some(blob)) => {
+                if blobs.get(blob).await?.is_none() {
+                    return Err(anyhow::anyhow!("Blob {} for {} has not been uploaded", blob, file.path));
+                }
+            }
+            _ => return Err(anyhow::anyhow!("File {} needs either content or a blob", file.path)),
+        }
+    }
+    Ok(())
+}
+
+/// Writes `files` into `chroot_dir`. Symbolic links in the image are
+/// resolved as they would be inside it, so no file lands outside the root
+/// filesystem. Returns the paths written.
+pub async fn apply(
+    chroot_dir: &Path,
+    files: &[FileOverlay],
+    blobs: &BlobStore,
+    variables: &BTreeMap<&str, String>,
+) -> Result<Vec<String>> {
+    let mut written = Vec::new();
+    for file in files {
+        let relative = image_path(&file.path)?;
+        let mode = parse_mode(&file.mode)?;
+        let (user, group) = parse_owner(&file.owner)?;
+        let uid = resolve_id(chroot_dir, "etc/passwd", user).await
+            .with_context(|| format!("Unknown user {} for {}", user, file.path))?;
+        let gid = resolve_id(chroot_dir, "etc/group", group).await
+            .with_context(|| format!("Unknown group {} for {}", group, file.path))?;
+
+        let mut contents = match (&file.content, &file.blob) {
+            (Some(content), _) => content.clone().into_bytes(),
+            (None, Some(blob)) => {
+                let path = blobs.get(blob).await?
+                    .ok_or_else(|| anyhow::anyhow!("Blob {} for {} is missing", blob, file.path))?;
+                fs::read(&path).await?
+            }
+            (None, None) => return Err(anyhow::anyhow!("File {} needs either content or a blob", file.path)),
+        };
+        if file.template {
+            let template = String::from_utf8(contents)
+                .with_context(|| format!("Template {} is not UTF-8", file.path))?;
+            contents = render(&template, variables)
+                .with_context(|| format!("Invalid template {}", file.path))?
+                .into_bytes();
+        }
+
+        let destination = resolve_in_root(chroot_dir, &relative).await
+            .with_context(|| format!("Failed to resolve {} in the image", file.path))?;
+        match fs::symlink_metadata(&destination).await {
+            Ok(metadata) if metadata.is_dir() => {
+                return Err(anyhow::anyhow!("{} is a directory in the image", file.path));
+            }
+            // Replace links instead of writing through them.
+            Ok(_) => fs::remove_file(&destination).await?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        fs::write(&destination, &contents).await
+            .with_context(|| format!("Failed to write {}", file.path))?;
+        std::os::unix::fs::lchown(&destination, Some(uid), Some(gid))
+            .with_context(|| format!("Failed to change the owner of {}", file.path))?;
+        // After chown, which clears the setuid and setgid bits.
+        fs::set_permissions(&destination, std::fs::Permissions::from_mode(mode)).await?;
+
+        written.push(file.path.clone());
+    }
+    Ok(written)
+}
+
+/// `path` relative to the image root; it must be absolute and may not
+/// contain `.` or `..` components.
+fn image_path(path: &str) -> Result<PathBuf> {
+    let mut components = Path::new(path).components();
+    if path.contains('\0') || components.next() != Some(Component::RootDir) {
+        return Err(anyhow::anyhow!("File path {:?} must be absolute", path));
+    }
+    let mut relative = PathBuf::new();
+    for component in components {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            _ => return Err(anyhow::anyhow!("File path {:?} must not contain . or ..", path)),
+        }
+    }
+    if relative.as_os_str().is_empty() {
+        return Err(anyhow::anyhow!("File path {:?} does not name a file", path));
+    }
+    Ok(relative)
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .with_context(|| format!("{:?} is not an octal mode", mode))?;
+    if mode > 0o7777 {
+        return Err(anyhow::anyhow!("Mode {:o} is out of range", mode));
+    }
+    Ok(mode)
+}
+
+fn parse_owner(owner: &str) -> Result<(&str, &str)> {
+    let (user, group) = owner.split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Owner {:?} must be user:group", owner))?;
+    for name in [user, group] {
+        let valid = !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '$'));
+        if !valid {
+            return Err(anyhow::anyhow!("{:?} is not a valid user or group", name));
+        }
+    }
+    Ok((user, group))
+}
+
+/// Numeric id of `name` in the image's passwd or group `database`.
+async fn resolve_id(chroot_dir: &Path, database: &str, name: &str) -> Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let entries = fs::read_to_string(chroot_dir.join(database)).await
+        .with_context(|| format!("Failed to read /{}", database))?;
+    entries.lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() > 2 && fields[0] == name)
+        .and_then(|fields| fields[2].parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("{} is not in /{}", name, database))
+}
+
+/// Replaces `{{ variable }}` with its value; unknown variables are an error.
+fn render(template: &str, variables: &BTreeMap<&str, String>) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        let name = rest[start + 2..start + end].trim();
+        let value = variables.get(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown template variable {:?}", name))?;
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(value);
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Host path of `relative` inside `root`, following symbolic links in its
+/// parent directories the way they resolve inside the image and creating
+/// missing directories. The last component is not followed.
+async fn resolve_in_root(root: &Path, relative: &Path) -> Result<PathBuf> {
+    let file_name = relative.file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} does not name a file", relative.display()))?;
+    let mut pending: VecDeque<OsString> = relative.parent()
+        .map(|parent| parent.iter().map(|part| part.to_os_string()).collect())
+        .unwrap_or_default();
+    let mut resolved = PathBuf::new();
+    let mut hops = 0;
+
+    while let Some(part) = pending.pop_front() {
+        match part.to_str() {
+            Some("." | "/") => continue,
+            Some("..") => {
+                resolved.pop();
+                continue;
+            }
+            _ => {}
+        }
+        let candidate = root.join(&resolved).join(&part);
+        match fs::symlink_metadata(&candidate).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                hops += 1;
+                if hops > MAX_SYMLINK_HOPS {
+                    return Err(anyhow::anyhow!("Too many levels of symbolic links"));
+                }
+                let target = fs::read_link(&candidate).await?;
+                if target.is_absolute() {
+                    resolved.clear();
+                }
+                for component in target.iter().rev() {
+                    pending.push_front(component.to_os_string());
+                }
+            }
+            Ok(metadata) if metadata.is_dir() => resolved.push(&part),
+            Ok(_) => {
+                return Err(anyhow::anyhow!("/{} is not a directory", resolved.join(&part).display()));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                fs::create_dir(&candidate).await?;
+                fs::set_permissions(&candidate, std::fs::Permissions::from_mode(0o755)).await?;
+                resolved.push(&part);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(root.join(resolved).join(file_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[tokio::test]
+    async fn follows_absolute_symlinks_inside_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("usr/lib")).unwrap();
+        symlink("/usr/lib", root.path().join("lib")).unwrap();
+
+        let path = resolve_in_root(root.path(), Path::new("lib/modules/extra.conf")).await.unwrap();
+        assert_eq!(path, root.path().join("usr/lib/modules/extra.conf"));
+        assert!(root.path().join("usr/lib/modules").is_dir());
+    }
+
+    #[tokio::test]
+    async fn keeps_dot_dot_symlinks_inside_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("etc")).unwrap();
+        symlink("../../../../tmp", root.path().join("etc/escape")).unwrap();
+
+        let path = resolve_in_root(root.path(), Path::new("etc/escape/file")).await.unwrap();
+        assert_eq!(path, root.path().join("tmp/file"));
+
+        let path = resolve_in_root(root.path(), Path::new("../../outside/file")).await.unwrap();
+        assert_eq!(path, root.path().join("outside/file"));
+    }
+
+    #[tokio::test]
+    async fn does_not_follow_the_last_component() {
+        let root = tempfile::tempdir().unwrap();
+        symlink("/etc/shadow", root.path().join("link")).unwrap();
+
+        let path = resolve_in_root(root.path(), Path::new("link")).await.unwrap();
+        assert_eq!(path, root.path().join("link"));
+    }
+
+    #[tokio::test]
+    async fn rejects_symlink_loops_and_files() {
+        let root = tempfile::tempdir().unwrap();
+        symlink("b", root.path().join("a")).unwrap();
+        symlink("a", root.path().join("b")).unwrap();
+        std::fs::write(root.path().join("file"), "").unwrap();
+
+        assert!(resolve_in_root(root.path(), Path::new("a/file")).await.is_err());
+        assert!(resolve_in_root(root.path(), Path::new("file/other")).await.is_err());
+    }
+}
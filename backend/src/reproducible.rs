@@ -0,0 +1,252 @@
+use crate::artifacts::hash_file;
+use crate::bootstrap::{Bootstrapper, PackageSource};
+use crate::models::IsoConfig;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::process::Command as AsyncCommand;
+use uuid::Uuid;
+
+/// Files rewritten on every build whose contents do not matter for the
+/// installed system; the system recreates them when they are missing.
+const VOLATILE_FILES: &[&str] = &[
+    "var/lib/dbus/machine-id",
+    "var/lib/systemd/random-seed",
+    "var/cache/ldconfig/aux-cache",
+];
+
+/// Directories holding package caches and logs of the build itself. Their
+/// files are removed, the directories are kept.
+pub const VOLATILE_DIRS: &[&str] = &[
+    "var/cache/apt",
+    "var/lib/apt/lists",
+    "var/cache/dnf",
+    "var/cache/pacman/pkg",
+    "var/log",
+];
+
+/// Host tools whose versions affect the image, with the flag that prints them.
+const BUILD_TOOLS: &[(&str, &str)] = &[
+    ("mksquashfs", "-version"),
+    ("xorriso", "-version"),
+    ("grub-mkstandalone", "--version"),
+    ("mkfs.vfat", "--help"),
+];
+
+/// `SOURCE_DATE_EPOCH` for `config`, or `None` outside reproducible mode.
+/// Defaults to the time the config was created, so resubmitting the same
+/// config reproduces the same image.
+pub fn source_date_epoch(config: &IsoConfig) -> Option<u64> {
+    let reproducible = config.reproducible.as_ref()?;
+    Some(reproducible.source_date_epoch.unwrap_or_else(|| config.created_at.timestamp().max(0) as u64))
+}
+
+/// Inputs of a build, published next to the image as `build-manifest.json`.
+#[derive(Debug, Serialize)]
+pub struct BuildManifest {
+    pub job_id: Uuid,
+    pub name: String,
+    /// SHA-256 of the config as submitted.
+    pub config_sha256: String,
+    pub source_date_epoch: Option<u64>,
+    pub distro: String,
+    pub bootstrapper: String,
+    pub release: String,
+    pub mirror: String,
+    pub components: Vec<String>,
+    /// Packages installed on top of the base system, in install order.
+    pub packages: Vec<String>,
+    /// Versions of the host tools that assembled the image.
+    pub tools: BTreeMap<String, String>,
+}
+
+impl BuildManifest {
+    pub async fn collect(job_id: Uuid, config: &IsoConfig, bootstrapper: &dyn Bootstrapper) -> Result<Self> {
+        let source = PackageSource::resolve(config, bootstrapper)?;
+
+        let mut packages: Vec<String> = bootstrapper.live_boot().packages.iter().map(|p| p.to_string()).collect();
+        packages.extend(config.desktop_environment.iter().cloned());
+        packages.extend(config.packages.iter().cloned());
+
+        let mut tools = BTreeMap::new();
+        for (program, flag) in BUILD_TOOLS {
+            tools.insert(program.to_string(), tool_version(program, flag).await);
+        }
+
+        Ok(Self {
+            job_id,
+            name: config.name.clone(),
+            config_sha256: hex::encode(Sha256::digest(serde_json::to_vec(config)?)),
+            source_date_epoch: source_date_epoch(config),
+            distro: config.distro.id.clone(),
+            bootstrapper: bootstrapper.name().to_string(),
+            release: source.release,
+            mirror: source.mirror,
+            components: source.components,
+            packages,
+            tools,
+        })
+    }
+}
+
+/// First line `program` prints about its version, or `unavailable`.
+async fn tool_version(program: &str, flag: &str) -> String {
+    let output = match AsyncCommand::new(program).arg(flag).output().await {
+        Ok(output) => output,
+        Err(_) => return "unavailable".to_string(),
+    };
+    // Some tools print their version to stderr.
+    [&output.stdout, &output.stderr]
+        .iter()
+        .flat_map(|stream| String::from_utf8_lossy(stream).lines().map(str::to_string).collect::<Vec<_>>())
+        .find(|line| !line.trim().is_empty())
+        .map(|line| line.trim().to_string())
+        .unwrap_or_else(|| "unavailable".to_string())
+}
+
+/// Removes caches, logs and ids that differ between otherwise identical
+/// builds from the root filesystem.
+pub async fn remove_volatile_files(chroot_dir: &Path) -> Result<()> {
+    let chroot_dir = chroot_dir.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        // An empty machine-id makes systemd generate one on first boot.
+        let machine_id = chroot_dir.join("etc/machine-id");
+        if machine_id.is_file() {
+            std::fs::write(&machine_id, "")?;
+        }
+        for file in VOLATILE_FILES {
+            match std::fs::remove_file(chroot_dir.join(file)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        for dir in VOLATILE_DIRS {
+            remove_files_under(&chroot_dir.join(dir))?;
+        }
+        Ok(())
+    })
+    .await?
+}
+
+fn remove_files_under(dir: &Path) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_files_under(&entry.path())?;
+        } else {
+            std::fs::remove_file(entry.path())
+                .with_context(|| format!("Failed to remove {}", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets the modification time of everything under `root` that is newer
+/// than `epoch` to `epoch`. Symbolic links are left alone.
+pub async fn clamp_mtimes(root: &Path, epoch: u64) -> Result<()> {
+    let root = root.to_path_buf();
+    let epoch = UNIX_EPOCH + Duration::from_secs(epoch);
+    tokio::task::spawn_blocking(move || {
+        for (path, metadata) in walk(&root)? {
+            if metadata.file_type().is_symlink() || metadata.modified()? <= epoch {
+                continue;
+            }
+            let file = std::fs::File::open(&path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            file.set_times(std::fs::FileTimes::new().set_accessed(epoch).set_modified(epoch))
+                .with_context(|| format!("Failed to set the mtime of {}", path.display()))?;
+        }
+        Ok(())
+    })
+    .await?
+}
+
+/// What a path in a build tree is, as far as the image is concerned.
+#[derive(Debug, PartialEq, Eq)]
+enum TreeEntry {
+    Directory { mode: u32 },
+    File { mode: u32, sha256: [u8; 32] },
+    Symlink { target: PathBuf },
+    Special { mode: u32 },
+}
+
+/// Lists the paths under `first` and `second` that differ in type, contents,
+/// permissions or link target, as `<prefix>/<path>: <reason>`.
+/// Timestamps are ignored since reproducible builds normalise them.
+pub async fn diff_trees(first: &Path, second: &Path, prefix: &str) -> Result<Vec<String>> {
+    let (first, second, prefix) = (first.to_path_buf(), second.to_path_buf(), prefix.to_string());
+    tokio::task::spawn_blocking(move || {
+        let first = tree_entries(&first)?;
+        let mut second = tree_entries(&second)?;
+        let mut differences = Vec::new();
+
+        for (path, entry) in first {
+            let reason = match second.remove(&path) {
+                None => "only in the first build",
+                Some(other) => match (&entry, &other) {
+                    _ if entry == other => continue,
+                    (TreeEntry::File { sha256: a, .. }, TreeEntry::File { sha256: b, .. }) if a != b => "contents differ",
+                    (TreeEntry::Symlink { .. }, TreeEntry::Symlink { .. }) => "link target differs",
+                    _ if std::mem::discriminant(&entry) == std::mem::discriminant(&other) => "permissions differ",
+                    _ => "file type differs",
+                },
+            };
+            differences.push(format!("{}/{}: {}", prefix, path.display(), reason));
+        }
+        for path in second.into_keys() {
+            differences.push(format!("{}/{}: only in the second build", prefix, path.display()));
+        }
+
+        differences.sort();
+        Ok(differences)
+    })
+    .await?
+}
+
+fn tree_entries(root: &Path) -> Result<BTreeMap<PathBuf, TreeEntry>> {
+    let mut entries = BTreeMap::new();
+    for (path, metadata) in walk(root)? {
+        let mode = metadata.permissions().mode();
+        let file_type = metadata.file_type();
+        let entry = if file_type.is_symlink() {
+            TreeEntry::Symlink { target: std::fs::read_link(&path)? }
+        } else if file_type.is_dir() {
+            TreeEntry::Directory { mode }
+        } else if file_type.is_file() {
+            TreeEntry::File { mode, sha256: hash_file(&path)? }
+        } else {
+            TreeEntry::Special { mode }
+        };
+        entries.insert(path.strip_prefix(root)?.to_path_buf(), entry);
+    }
+    Ok(entries)
+}
+
+/// Every path below `root` with its metadata, without following symlinks.
+fn walk(root: &Path) -> Result<Vec<(PathBuf, std::fs::Metadata)>> {
+    let mut found = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read {}", dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let metadata = std::fs::symlink_metadata(entry.path())?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            }
+            found.push((entry.path(), metadata));
+        }
+    }
+    Ok(found)
+}
+
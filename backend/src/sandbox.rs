@@ -0,0 +1,294 @@
+use crate::models::{ScriptNetwork, ScriptSandboxConfig};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use tokio::fs;
+use tokio::process::Command as AsyncCommand;
+use tracing::warn;
+
+/// Where a custom script is mounted inside its sandbox.
+pub const SCRIPT_MOUNT_PATH: &str = "/run/iso-builder/script";
+
+/// `PATH` inside the sandbox.
+const SANDBOX_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Number of user and group ids mapped into a sandbox's user namespace.
+const ID_RANGE: u32 = 65536;
+
+/// First host id of the range mapped into a `bwrap` sandbox. It lies above
+/// the ranges `systemd-nspawn --private-users=pick` chooses from, and root
+/// needs it in `/etc/subuid` and `/etc/subgid` for `newuidmap`.
+pub const BUBBLEWRAP_ID_BASE: u32 = 0x7000_0000;
+
+/// Container tool that runs custom scripts against the root filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ScriptSandbox {
+    /// `systemd-nspawn`, unsharing every namespace including a user
+    /// namespace with a 65536-id range it picks.
+    Nspawn,
+    /// `bwrap`, unsharing every namespace including a user namespace that
+    /// maps 65536 ids from `BUBBLEWRAP_ID_BASE`.
+    Bubblewrap,
+}
+
+/// Most a build may ask for its custom scripts, set by the server.
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptLimits {
+    pub max_timeout_secs: u64,
+    pub max_memory_mb: u64,
+    pub max_cpu_percent: u32,
+    /// Whether scripts may use the host's network.
+    pub allow_network: bool,
+}
+
+/// Checks the sandbox settings of a build against the server's `limits`
+/// before it is queued.
+pub fn validate(config: &ScriptSandboxConfig, limits: &ScriptLimits) -> Result<()> {
+    if config.timeout_secs == 0 || config.timeout_secs > limits.max_timeout_secs {
+        return Err(anyhow::anyhow!(
+            "Script timeout must be from 1 to {} seconds", limits.max_timeout_secs,
+        ));
+    }
+    if !config.memory_mb.is_some_and(|memory| memory > 0 && memory <= limits.max_memory_mb) {
+        return Err(anyhow::anyhow!(
+            "Script memory limit must be from 1 to {} MiB", limits.max_memory_mb,
+        ));
+    }
+    if !config.cpu_percent.is_some_and(|cpu| cpu > 0 && cpu <= limits.max_cpu_percent) {
+        return Err(anyhow::anyhow!(
+            "Script CPU limit must be from 1 to {} percent", limits.max_cpu_percent,
+        ));
+    }
+    if config.network == ScriptNetwork::Host && !limits.allow_network {
+        return Err(anyhow::anyhow!("This server does not give scripts network access"));
+    }
+    Ok(())
+}
+
+/// A sandboxed script run, ready to be executed.
+pub struct SandboxedCommand {
+    pub command: AsyncCommand,
+    /// Transient systemd scope holding every process of the script.
+    pub unit: String,
+}
+
+/// Builds the command that runs `argv` inside `chroot_dir` with the script
+/// at `script_path` mounted read-only at `SCRIPT_MOUNT_PATH`.
+///
+/// The sandbox runs in a transient systemd scope called `unit_name`, which
+/// enforces the memory and CPU limits and can be killed as a whole.
+pub fn script_command(
+    sandbox: ScriptSandbox,
+    chroot_dir: &Path,
+    script_path: &Path,
+    argv: &[String],
+    env: &[(String, String)],
+    config: &ScriptSandboxConfig,
+    unit_name: &str,
+) -> SandboxedCommand {
+    let limits = config.memory_mb.iter()
+        .flat_map(|memory| [format!("MemoryMax={}M", memory), "MemorySwapMax=0".to_string()])
+        .chain(config.cpu_percent.map(|cpu| format!("CPUQuota={}%", cpu)));
+
+    let mut command = AsyncCommand::new("systemd-run");
+    command.args(["--scope", "--quiet", "--collect"])
+        .arg(format!("--unit={}", unit_name));
+    for limit in limits {
+        command.arg("--property").arg(limit);
+    }
+    command.arg("--");
+
+    match sandbox {
+        ScriptSandbox::Nspawn => {
+            // The scope above is the container's unit; nothing is
+            // registered with machined and the image's /etc is left alone.
+            command.arg("systemd-nspawn")
+                .args(["--quiet", "--register=no", "--keep-unit", "--as-pid2", "--console=pipe"])
+                .args(["--private-users=pick", "--private-users-ownership=auto"])
+                .args(["--link-journal=no", "--timezone=off"])
+                .arg(format!("--directory={}", chroot_dir.display()))
+                .arg("--chdir=/")
+                .arg(format!("--bind-ro={}:{}", script_path.display(), SCRIPT_MOUNT_PATH))
+                .arg(format!("--setenv=PATH={}", SANDBOX_PATH));
+            match config.network {
+                ScriptNetwork::None => command.args(["--private-network", "--resolv-conf=off"]),
+                ScriptNetwork::Host => command.arg("--resolv-conf=bind-host"),
+            };
+            for (key, value) in env {
+                command.arg(format!("--setenv={}={}", key, value));
+            }
+            command.arg("--");
+        }
+        ScriptSandbox::Bubblewrap => {
+            // bwrap maps only its caller's id into a user namespace of its
+            // own, so it runs as root of one `unshare` made with a full
+            // range instead; `prepare_root` shifts the tree into it.
+            let map = format!("{},0,{}", BUBBLEWRAP_ID_BASE, ID_RANGE);
+            command.args(["unshare", "--user"])
+                .arg(format!("--map-users={}", map))
+                .arg(format!("--map-groups={}", map))
+                .args(["--setuid", "0", "--setgid", "0", "--", "bwrap"])
+                .arg("--bind").arg(chroot_dir).arg("/")
+                .args(["--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp", "--tmpfs", "/run"])
+                .arg("--ro-bind").arg(script_path).arg(SCRIPT_MOUNT_PATH)
+                .args(["--unshare-pid", "--unshare-ipc", "--unshare-uts", "--unshare-cgroup-try"])
+                .args(["--die-with-parent", "--new-session", "--chdir", "/", "--clearenv"])
+                .args(["--setenv", "PATH", SANDBOX_PATH, "--setenv", "HOME", "/root"]);
+            match config.network {
+                ScriptNetwork::None => command.arg("--unshare-net"),
+                ScriptNetwork::Host => command.args(["--ro-bind", "/etc/resolv.conf", "/etc/resolv.conf"]),
+            };
+            for (key, value) in env {
+                command.arg("--setenv").arg(key).arg(value);
+            }
+        }
+    }
+
+    command.args(argv);
+    SandboxedCommand { command, unit: format!("{}.scope", unit_name) }
+}
+
+/// Gets `chroot_dir` ready for a script in `sandbox`: `bwrap` needs the
+/// tree owned by the ids mapped into its user namespace.
+pub async fn prepare_root(sandbox: ScriptSandbox, chroot_dir: &Path) -> Result<()> {
+    match sandbox {
+        // systemd-nspawn maps or chowns the tree itself.
+        ScriptSandbox::Nspawn => Ok(()),
+        ScriptSandbox::Bubblewrap => shift_ids(chroot_dir, 0, BUBBLEWRAP_ID_BASE).await,
+    }
+}
+
+/// Gives `chroot_dir` back to host root after a script. Like
+/// `systemd-nspawn`, this takes the owner of the tree's top directory as
+/// the base of the range it was shifted to.
+pub async fn restore_root(chroot_dir: &Path) -> Result<()> {
+    let base = fs::symlink_metadata(chroot_dir).await
+        .with_context(|| format!("Failed to stat {}", chroot_dir.display()))?
+        .uid();
+    if base == 0 {
+        return Ok(());
+    }
+    shift_ids(chroot_dir, base, 0).await
+}
+
+/// Moves the owner and group of every file under `root` on its filesystem
+/// from the `ID_RANGE` ids starting at `from` to those starting at `to`.
+async fn shift_ids(root: &Path, from: u32, to: u32) -> Result<()> {
+    let root = root.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let device = std::fs::symlink_metadata(&root)?.dev();
+        shift_tree(&root, device, from, to)
+            .with_context(|| format!("Failed to shift ownership of {}", root.display()))
+    })
+    .await?
+}
+
+fn shift_tree(path: &Path, device: u64, from: u32, to: u32) -> Result<()> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    // Leave whatever the build mounted into the tree, such as /proc, alone.
+    if metadata.dev() != device {
+        return Ok(());
+    }
+    let shift = |id: u32| id.checked_sub(from).filter(|offset| *offset < ID_RANGE).map(|offset| to + offset);
+    let (uid, gid) = (shift(metadata.uid()), shift(metadata.gid()));
+    if uid.is_some() || gid.is_some() {
+        // chown drops setuid and setgid bits and file capabilities.
+        let capability = (!metadata.is_symlink()).then(|| file_capability(path)).flatten();
+        std::os::unix::fs::lchown(path, uid, gid)?;
+        if !metadata.is_symlink() && metadata.mode() & 0o6000 != 0 {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(metadata.mode()))?;
+        }
+        if let Some(capability) = capability {
+            set_file_capability(path, &capability)?;
+        }
+    }
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            shift_tree(&entry?.path(), device, from, to)?;
+        }
+    }
+    Ok(())
+}
+
+const CAPABILITY_XATTR: &[u8] = b"security.capability\0";
+
+fn file_capability(path: &Path) -> Option<Vec<u8>> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut value = vec![0u8; 64];
+    let len = unsafe {
+        libc::lgetxattr(path.as_ptr(), CAPABILITY_XATTR.as_ptr().cast(), value.as_mut_ptr().cast(), value.len())
+    };
+    if len < 0 {
+        return None;
+    }
+    value.truncate(len as usize);
+    Some(value)
+}
+
+fn set_file_capability(path: &Path, value: &[u8]) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let result = unsafe {
+        libc::lsetxattr(c_path.as_ptr(), CAPABILITY_XATTR.as_ptr().cast(), value.as_ptr().cast(), value.len(), 0)
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to restore the file capabilities of {}", path.display()));
+    }
+    Ok(())
+}
+
+/// Kills everything left in the systemd scope `unit`, e.g. after a timeout.
+pub async fn kill_unit(unit: &str) {
+    let result = AsyncCommand::new("systemctl")
+        .args(["kill", "--signal=SIGKILL", unit])
+        .status()
+        .await;
+    match result {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("systemctl kill {} exited with {}", unit, status),
+        Err(e) => warn!("Failed to run systemctl kill for {}: {}", unit, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(sandbox: ScriptSandbox) -> Vec<String> {
+        let config = ScriptSandboxConfig::default();
+        let sandboxed = script_command(
+            sandbox,
+            Path::new("/build/chroot"),
+            Path::new("/build/scripts/post-install.sh"),
+            &["/bin/sh".to_string(), SCRIPT_MOUNT_PATH.to_string()],
+            &[],
+            &config,
+            "iso-builder-script-test",
+        );
+        sandboxed.command.as_std().get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn nspawn_runs_in_a_private_user_namespace() {
+        let args = args(ScriptSandbox::Nspawn);
+        assert!(args.contains(&"--private-users=pick".to_string()));
+        assert!(args.contains(&"--private-users-ownership=auto".to_string()));
+    }
+
+    #[test]
+    fn bubblewrap_runs_in_a_full_range_user_namespace() {
+        let args = args(ScriptSandbox::Bubblewrap);
+        let map = format!("{},0,65536", BUBBLEWRAP_ID_BASE);
+        let unshare = args.iter().position(|arg| arg == "unshare").unwrap();
+        let bwrap = args.iter().position(|arg| arg == "bwrap").unwrap();
+        assert!(unshare < bwrap);
+        assert!(args[unshare..bwrap].contains(&"--user".to_string()));
+        assert!(args[unshare..bwrap].contains(&format!("--map-users={}", map)));
+        assert!(args[unshare..bwrap].contains(&format!("--map-groups={}", map)));
+    }
+}
@@ -0,0 +1,623 @@
+use crate::bootstrap::{chroot_command, PackageManager};
+use crate::models::*;
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use flate2::read::GzDecoder;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use uuid::Uuid;
+
+/// Characters left alone in purl names and versions.
+const PURL_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// License ids from the SPDX license list that distro packages commonly
+/// declare. Anything else is reported as `NOASSERTION`.
+const SPDX_LICENSES: &[&str] = &[
+    "0BSD", "AFL-2.1", "AGPL-3.0-only", "AGPL-3.0-or-later", "Apache-1.1", "Apache-2.0",
+    "Artistic-1.0", "Artistic-1.0-Perl", "Artistic-2.0", "BSD-1-Clause", "BSD-2-Clause",
+    "BSD-3-Clause", "BSD-4-Clause", "BSL-1.0", "bzip2-1.0.6", "CC-BY-3.0", "CC-BY-4.0",
+    "CC-BY-SA-3.0", "CC-BY-SA-4.0", "CC0-1.0", "CDDL-1.0", "curl", "EPL-1.0", "EPL-2.0",
+    "FSFAP", "FSFUL", "FSFULLR", "FTL", "GFDL-1.2-only", "GFDL-1.2-or-later", "GFDL-1.3-only",
+    "GFDL-1.3-or-later", "GPL-1.0-only", "GPL-1.0-or-later", "GPL-2.0-only", "GPL-2.0-or-later",
+    "GPL-3.0-only", "GPL-3.0-or-later", "HPND", "ICU", "IJG", "ISC", "LGPL-2.0-only",
+    "LGPL-2.0-or-later", "LGPL-2.1-only", "LGPL-2.1-or-later", "LGPL-3.0-only",
+    "LGPL-3.0-or-later", "Libpng", "libtiff", "MIT", "MIT-0", "MPL-1.1", "MPL-2.0", "NCSA",
+    "OFL-1.1", "OpenSSL", "PHP-3.01", "PostgreSQL", "PSF-2.0", "Python-2.0", "Ruby",
+    "Sleepycat", "Unicode-3.0", "Unicode-DFS-2016", "Unlicense", "Vim", "W3C", "WTFPL", "X11",
+    "Zlib", "ZPL-2.1",
+    // Exceptions, only valid after WITH.
+    "Autoconf-exception-3.0", "Bison-exception-2.2", "Classpath-exception-2.0",
+    "GCC-exception-2.0", "GCC-exception-3.1", "Libtool-exception", "Linux-syscall-note",
+    "LLVM-exception", "OpenSSL-exception",
+];
+
+/// Short names used by Debian copyright files that differ from SPDX ids.
+const DEBIAN_LICENSE_NAMES: &[(&str, &str)] = &[
+    ("GPL-1+", "GPL-1.0-or-later"),
+    ("GPL-2", "GPL-2.0-only"),
+    ("GPL-2+", "GPL-2.0-or-later"),
+    ("GPL-3", "GPL-3.0-only"),
+    ("GPL-3+", "GPL-3.0-or-later"),
+    ("LGPL-2", "LGPL-2.0-only"),
+    ("LGPL-2+", "LGPL-2.0-or-later"),
+    ("LGPL-2.1", "LGPL-2.1-only"),
+    ("LGPL-2.1+", "LGPL-2.1-or-later"),
+    ("LGPL-3", "LGPL-3.0-only"),
+    ("LGPL-3+", "LGPL-3.0-or-later"),
+    ("AGPL-3", "AGPL-3.0-only"),
+    ("AGPL-3+", "AGPL-3.0-or-later"),
+    ("GFDL-1.2+", "GFDL-1.2-or-later"),
+    ("GFDL-1.3+", "GFDL-1.3-or-later"),
+    ("Expat", "MIT"),
+    ("Artistic", "Artistic-1.0"),
+    ("PSF-2", "PSF-2.0"),
+];
+
+/// A package installed in the root filesystem.
+#[derive(Debug, Clone)]
+pub struct InstalledPackage {
+    pub name: String,
+    /// Full version, including the epoch if the package has one.
+    pub version: String,
+    pub arch: String,
+    /// Source package the package was built from, if the database records it.
+    pub source: Option<String>,
+    /// License as the package declares it.
+    pub license: Option<String>,
+    /// Hex-encoded SHA-256 of the package file, when the repository
+    /// metadata that lists it is still in the root filesystem.
+    pub sha256: Option<String>,
+    /// Bytes the package's files take up, as its database records it.
+    pub installed_size: Option<u64>,
+}
+
+impl InstalledPackage {
+    /// `license` as an SPDX license expression, if it can be expressed as one.
+    fn spdx_license(&self) -> Option<String> {
+        spdx_expression(self.license.as_deref()?)
+    }
+}
+
+/// Software bill of materials of a root filesystem.
+#[derive(Clone)]
+pub struct Sbom {
+    pub package_manager: PackageManager,
+    /// Distro part of package URLs, e.g. `debian` in `pkg:deb/debian/bash@5.2`.
+    pub namespace: String,
+    pub packages: Vec<InstalledPackage>,
+}
+
+impl Sbom {
+    /// Reads the package database of `package_manager` in `chroot_dir`.
+    pub async fn collect(chroot_dir: &Path, package_manager: PackageManager, distro: &DistroTemplate) -> Result<Self> {
+        let mut packages = match package_manager {
+            PackageManager::Apt => {
+                let chroot_dir = chroot_dir.to_path_buf();
+                tokio::task::spawn_blocking(move || read_dpkg_packages(&chroot_dir)).await??
+            }
+            PackageManager::Dnf => read_rpm_packages(chroot_dir).await?,
+            PackageManager::Pacman => {
+                let chroot_dir = chroot_dir.to_path_buf();
+                tokio::task::spawn_blocking(move || read_pacman_packages(&chroot_dir)).await??
+            }
+        };
+        if packages.is_empty() {
+            return Err(anyhow::anyhow!("The {} package database lists no installed packages", package_manager));
+        }
+        packages.sort_by(|a, b| (&a.name, &a.arch).cmp(&(&b.name, &b.arch)));
+
+        let namespace = match distro.category {
+            DistroCategory::Ubuntu => "ubuntu".to_string(),
+            DistroCategory::Debian => "debian".to_string(),
+            DistroCategory::Arch => "arch".to_string(),
+            DistroCategory::Fedora => "fedora".to_string(),
+            DistroCategory::Rocky => "rocky".to_string(),
+            DistroCategory::Custom => distro.id.to_lowercase(),
+        };
+
+        Ok(Self { package_manager, namespace, packages })
+    }
+
+    pub fn summary(&self) -> SbomSummary {
+        let mut licenses = BTreeMap::new();
+        for package in &self.packages {
+            let license = package.spdx_license().unwrap_or_else(|| "NOASSERTION".to_string());
+            *licenses.entry(license).or_insert(0) += 1;
+        }
+
+        SbomSummary {
+            package_manager: self.package_manager.to_string(),
+            package_count: self.packages.len(),
+            packages_without_license: licenses.get("NOASSERTION").copied().unwrap_or(0),
+            packages_without_checksum: self.packages.iter().filter(|p| p.sha256.is_none()).count(),
+            licenses,
+        }
+    }
+
+    /// Package URL identifying `package`.
+    fn purl(&self, package: &InstalledPackage) -> String {
+        let (purl_type, version, epoch) = match self.package_manager {
+            PackageManager::Apt => ("deb", package.version.as_str(), None),
+            // rpm purls carry the epoch as a qualifier.
+            PackageManager::Dnf => match package.version.split_once(':') {
+                Some((epoch, version)) => ("rpm", version, Some(epoch)),
+                None => ("rpm", package.version.as_str(), None),
+            },
+            PackageManager::Pacman => ("alpm", package.version.as_str(), None),
+        };
+
+        let mut purl = format!(
+            "pkg:{}/{}/{}@{}?arch={}",
+            purl_type,
+            utf8_percent_encode(&self.namespace, PURL_COMPONENT),
+            utf8_percent_encode(&package.name, PURL_COMPONENT),
+            utf8_percent_encode(version, PURL_COMPONENT),
+            utf8_percent_encode(&package.arch, PURL_COMPONENT),
+        );
+        if let Some(epoch) = epoch {
+            purl.push_str(&format!("&epoch={}", epoch));
+        }
+        purl
+    }
+
+    /// SPDX 2.3 JSON document describing the image and its packages.
+    pub fn spdx_json(&self, job_id: Uuid, config: &IsoConfig, created: DateTime<Utc>) -> Value {
+        let mut packages = vec![json!({
+            "SPDXID": "SPDXRef-Image",
+            "name": config.name,
+            "downloadLocation": "NOASSERTION",
+            "filesAnalyzed": false,
+            "primaryPackagePurpose": "OPERATING-SYSTEM",
+        })];
+        let mut relationships = vec![json!({
+            "spdxElementId": "SPDXRef-DOCUMENT",
+            "relationshipType": "DESCRIBES",
+            "relatedSpdxElement": "SPDXRef-Image",
+        })];
+
+        for (index, package) in self.packages.iter().enumerate() {
+            let spdx_id = format!("SPDXRef-Package-{}-{}", index, spdx_id_part(&package.name));
+            let mut entry = json!({
+                "SPDXID": spdx_id,
+                "name": package.name,
+                "versionInfo": package.version,
+                "supplier": "NOASSERTION",
+                "downloadLocation": "NOASSERTION",
+                "filesAnalyzed": false,
+                "licenseConcluded": "NOASSERTION",
+                "licenseDeclared": package.spdx_license().unwrap_or_else(|| "NOASSERTION".to_string()),
+                "copyrightText": "NOASSERTION",
+                "externalRefs": [{
+                    "referenceCategory": "PACKAGE-MANAGER",
+                    "referenceType": "purl",
+                    "referenceLocator": self.purl(package),
+                }],
+            });
+            if let Some(sha256) = &package.sha256 {
+                entry["checksums"] = json!([{ "algorithm": "SHA256", "checksumValue": sha256 }]);
+            }
+            if let (None, Some(license)) = (package.spdx_license(), &package.license) {
+                entry["licenseComments"] = json!(format!("Declared license: {}", license));
+            }
+            packages.push(entry);
+            relationships.push(json!({
+                "spdxElementId": "SPDXRef-Image",
+                "relationshipType": "CONTAINS",
+                "relatedSpdxElement": spdx_id,
+            }));
+        }
+
+        json!({
+            "spdxVersion": "SPDX-2.3",
+            "dataLicense": "CC0-1.0",
+            "SPDXID": "SPDXRef-DOCUMENT",
+            "name": config.name,
+            "documentNamespace": format!("urn:uuid:{}", job_id),
+            "creationInfo": {
+                "created": created.to_rfc3339_opts(SecondsFormat::Secs, true),
+                "creators": [format!("Tool: iso-creator-backend-{}", env!("CARGO_PKG_VERSION"))],
+            },
+            "packages": packages,
+            "relationships": relationships,
+        })
+    }
+
+    /// CycloneDX 1.5 JSON document describing the image and its packages.
+    pub fn cyclonedx_json(&self, job_id: Uuid, config: &IsoConfig, created: DateTime<Utc>) -> Value {
+        let components: Vec<Value> = self.packages.iter().map(|package| {
+            let purl = self.purl(package);
+            let mut component = json!({
+                "type": "library",
+                "bom-ref": purl,
+                "name": package.name,
+                "version": package.version,
+                "purl": purl,
+            });
+            match (package.spdx_license(), &package.license) {
+                (Some(expression), _) => component["licenses"] = json!([{ "expression": expression }]),
+                (None, Some(license)) => component["licenses"] = json!([{ "license": { "name": license } }]),
+                (None, None) => {}
+            }
+            if let Some(sha256) = &package.sha256 {
+                component["hashes"] = json!([{ "alg": "SHA-256", "content": sha256 }]);
+            }
+            component
+        }).collect();
+        let depends_on: Vec<String> = self.packages.iter().map(|package| self.purl(package)).collect();
+
+        json!({
+            "bomFormat": "CycloneDX",
+            "specVersion": "1.5",
+            "serialNumber": format!("urn:uuid:{}", job_id),
+            "version": 1,
+            "metadata": {
+                "timestamp": created.to_rfc3339_opts(SecondsFormat::Secs, true),
+                "tools": {
+                    "components": [{
+                        "type": "application",
+                        "name": "iso-creator-backend",
+                        "version": env!("CARGO_PKG_VERSION"),
+                    }],
+                },
+                "component": {
+                    "type": "operating-system",
+                    "bom-ref": "image",
+                    "name": config.name,
+                },
+            },
+            "components": components,
+            "dependencies": [{ "ref": "image", "dependsOn": depends_on }],
+        })
+    }
+}
+
+/// `name` reduced to the characters allowed in an SPDX id.
+fn spdx_id_part(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '-' })
+        .collect()
+}
+
+/// Translates a declared license into an SPDX expression.
+///
+/// Understands SPDX ids, the Debian copyright short names and `and`/`or`/
+/// `with` in any case; returns `None` if any part is not a known license.
+fn spdx_expression(license: &str) -> Option<String> {
+    let spaced = license.replace('(', " ( ").replace(')', " ) ").replace(',', " and ").replace('|', " or ");
+    let mut parts = Vec::new();
+
+    for token in spaced.split_whitespace() {
+        let part = match token.to_ascii_lowercase().as_str() {
+            "and" | "or" | "with" | "(" | ")" => token.to_ascii_uppercase(),
+            _ => spdx_license_id(token)?.to_string(),
+        };
+        parts.push(part);
+    }
+
+    let expression = parts.join(" ").replace("( ", "(").replace(" )", ")");
+    (!expression.is_empty()).then_some(expression)
+}
+
+fn spdx_license_id(name: &str) -> Option<&'static str> {
+    if let Some((_, id)) = DEBIAN_LICENSE_NAMES.iter().find(|(debian, _)| debian.eq_ignore_ascii_case(name)) {
+        return Some(id);
+    }
+    // Deprecated `GPL-2.0+` style ids.
+    if let Some(base) = name.strip_suffix('+') {
+        let or_later = format!("{}-or-later", base);
+        return SPDX_LICENSES.iter().copied().find(|id| id.eq_ignore_ascii_case(&or_later));
+    }
+    SPDX_LICENSES.iter().copied().find(|id| id.eq_ignore_ascii_case(name))
+}
+
+/// Reads `Key: value` stanzas separated by blank lines, keeping only the
+/// first line of each of `fields`.
+fn for_each_stanza<R: BufRead>(reader: R, fields: &[&str], mut f: impl FnMut(&HashMap<String, String>)) -> Result<()> {
+    let mut stanza = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            if !stanza.is_empty() {
+                f(&stanza);
+                stanza.clear();
+            }
+            continue;
+        }
+        if line.starts_with([' ', '\t']) {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            if fields.contains(&key) {
+                stanza.insert(key.to_string(), value.trim().to_string());
+            }
+        }
+    }
+    if !stanza.is_empty() {
+        f(&stanza);
+    }
+    Ok(())
+}
+
+fn read_dpkg_packages(chroot_dir: &Path) -> Result<Vec<InstalledPackage>> {
+    let status_path = chroot_dir.join("var/lib/dpkg/status");
+    let status = std::fs::File::open(&status_path)
+        .with_context(|| format!("Failed to open {}", status_path.display()))?;
+
+    let checksums = apt_list_checksums(&chroot_dir.join("var/lib/apt/lists"))?;
+    let mut packages = Vec::new();
+    for_each_stanza(BufReader::new(status), &["Package", "Version", "Architecture", "Status", "Source", "Installed-Size"], |stanza| {
+        let installed = stanza.get("Status").is_some_and(|status| status.ends_with(" installed"));
+        let (Some(name), Some(version), Some(arch)) = (stanza.get("Package"), stanza.get("Version"), stanza.get("Architecture")) else {
+            return;
+        };
+        if !installed {
+            return;
+        }
+        packages.push(InstalledPackage {
+            name: name.clone(),
+            version: version.clone(),
+            arch: arch.clone(),
+            // `Source: name (version)` when it differs from the binary package.
+            source: stanza.get("Source").and_then(|source| source.split_whitespace().next()).map(str::to_string),
+            license: debian_copyright_license(chroot_dir, name),
+            sha256: checksums.get(&(name.clone(), version.clone(), arch.clone())).cloned(),
+            // In KiB.
+            installed_size: stanza.get("Installed-Size").and_then(|size| size.parse::<u64>().ok()).map(|kib| kib * 1024),
+        });
+    })?;
+
+    Ok(packages)
+}
+
+/// SHA-256 of every package in apt's downloaded `Packages` indexes, by
+/// name, version and architecture.
+fn apt_list_checksums(lists_dir: &Path) -> Result<HashMap<(String, String, String), String>> {
+    let mut checksums = HashMap::new();
+    let entries = match std::fs::read_dir(lists_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(checksums),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", lists_dir.display())),
+    };
+
+    for entry in entries {
+        let path = entry?.path();
+        if !path.to_string_lossy().ends_with("_Packages") {
+            continue;
+        }
+        let file = std::fs::File::open(&path)?;
+        for_each_stanza(BufReader::new(file), &["Package", "Version", "Architecture", "SHA256"], |stanza| {
+            if let (Some(name), Some(version), Some(arch), Some(sha256)) =
+                (stanza.get("Package"), stanza.get("Version"), stanza.get("Architecture"), stanza.get("SHA256"))
+            {
+                checksums.insert((name.clone(), version.clone(), arch.clone()), sha256.clone());
+            }
+        })?;
+    }
+
+    Ok(checksums)
+}
+
+/// License of the `Files: *` paragraph of a machine-readable Debian
+/// copyright file, falling back to the header's license.
+fn debian_copyright_license(chroot_dir: &Path, package: &str) -> Option<String> {
+    let copyright = std::fs::read_to_string(chroot_dir.join("usr/share/doc").join(package).join("copyright")).ok()?;
+    if !copyright.trim_start().starts_with("Format:") {
+        return None;
+    }
+
+    let mut header_license = None;
+    for (index, paragraph) in copyright.split("\n\n").enumerate() {
+        let field = |name: &str| {
+            paragraph.lines()
+                .find_map(|line| line.strip_prefix(name).and_then(|rest| rest.strip_prefix(':')))
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        if field("Files").as_deref() == Some("*") {
+            return field("License");
+        }
+        if index == 0 {
+            header_license = field("License");
+        }
+    }
+    header_license
+}
+
+async fn read_rpm_packages(chroot_dir: &Path) -> Result<Vec<InstalledPackage>> {
+    let mut cmd = chroot_command(chroot_dir, &[
+        "rpm", "-qa", "--queryformat", "%{NAME}\\t%{EPOCH}\\t%{VERSION}-%{RELEASE}\\t%{ARCH}\\t%{LICENSE}\\t%{SOURCERPM}\\t%{SIZE}\\n",
+    ]);
+    cmd.stdin(Stdio::null()).kill_on_drop(true);
+    let output = cmd.output().await.context("Failed to run rpm")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("rpm -qa failed ({}): {}", output.status, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    let chroot = chroot_dir.to_path_buf();
+    let checksums = tokio::task::spawn_blocking(move || dnf_cache_checksums(&chroot)).await??;
+
+    let mut packages = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [name, epoch, version, arch, license, source_rpm, size] = fields[..] else {
+            continue;
+        };
+        // Public keys imported into the rpm database show up as packages.
+        if name == "gpg-pubkey" {
+            continue;
+        }
+        let version = match epoch {
+            "(none)" | "0" => version.to_string(),
+            epoch => format!("{}:{}", epoch, version),
+        };
+        packages.push(InstalledPackage {
+            name: name.to_string(),
+            sha256: checksums.get(&(name.to_string(), version.clone(), arch.to_string())).cloned(),
+            version,
+            arch: arch.to_string(),
+            // `name-version-release.src.rpm`
+            source: source_rpm.rsplitn(3, '-').nth(2).map(str::to_string),
+            license: (license != "(none)").then(|| license.to_string()),
+            installed_size: size.parse().ok(),
+        });
+    }
+
+    Ok(packages)
+}
+
+/// SHA-256 of every package in the gzip-compressed `primary.xml` repository
+/// metadata in dnf's cache, by name, version and architecture.
+fn dnf_cache_checksums(chroot_dir: &Path) -> Result<HashMap<(String, String, String), String>> {
+    let mut checksums = HashMap::new();
+    let mut pending: Vec<PathBuf> = ["var/cache/dnf", "var/cache/libdnf5"].iter().map(|dir| chroot_dir.join(dir)).collect();
+
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            if !path.to_string_lossy().ends_with("primary.xml.gz") {
+                continue;
+            }
+            let mut xml = String::new();
+            GzDecoder::new(std::fs::File::open(&path)?).read_to_string(&mut xml)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            for package in xml.split("<package type=\"rpm\">").skip(1) {
+                if let Some((key, sha256)) = parse_primary_package(package) {
+                    checksums.insert(key, sha256);
+                }
+            }
+        }
+    }
+
+    Ok(checksums)
+}
+
+fn parse_primary_package(xml: &str) -> Option<((String, String, String), String)> {
+    let element = |name: &str| {
+        let start = xml.find(&format!("<{}", name))?;
+        let rest = &xml[start..];
+        let tag_end = rest.find('>')?;
+        let content_end = rest.find(&format!("</{}>", name)).unwrap_or(tag_end);
+        Some((&rest[..tag_end], &rest[(tag_end + 1).min(content_end)..content_end]))
+    };
+    let attribute = |tag: &str, name: &str| {
+        let start = tag.find(&format!(" {}=\"", name))? + name.len() + 3;
+        let end = tag[start..].find('"')?;
+        Some(tag[start..start + end].to_string())
+    };
+
+    let (_, name) = element("name")?;
+    let (_, arch) = element("arch")?;
+    let (version_tag, _) = element("version")?;
+    let (checksum_tag, checksum) = element("checksum")?;
+    if attribute(checksum_tag, "type")? != "sha256" {
+        return None;
+    }
+
+    let epoch = attribute(version_tag, "epoch").unwrap_or_default();
+    let version = format!("{}-{}", attribute(version_tag, "ver")?, attribute(version_tag, "rel")?);
+    let version = match epoch.as_str() {
+        "" | "0" => version,
+        epoch => format!("{}:{}", epoch, version),
+    };
+
+    Some(((name.to_string(), version, arch.to_string()), checksum.to_string()))
+}
+
+fn read_pacman_packages(chroot_dir: &Path) -> Result<Vec<InstalledPackage>> {
+    let local_dir = chroot_dir.join("var/lib/pacman/local");
+    let checksums = pacman_sync_checksums(&chroot_dir.join("var/lib/pacman/sync"))?;
+
+    let mut packages = Vec::new();
+    for entry in std::fs::read_dir(&local_dir).with_context(|| format!("Failed to read {}", local_dir.display()))? {
+        let Ok(desc) = std::fs::read_to_string(entry?.path().join("desc")) else {
+            continue;
+        };
+        let desc = parse_pacman_desc(&desc);
+        let first = |key: &str| desc.get(key).and_then(|values| values.first()).cloned();
+        let (Some(name), Some(version), Some(arch)) = (first("NAME"), first("VERSION"), first("ARCH")) else {
+            continue;
+        };
+        let license = desc.get("LICENSE").filter(|licenses| !licenses.is_empty()).map(|licenses| licenses.join(" AND "));
+
+        packages.push(InstalledPackage {
+            sha256: checksums.get(&(name.clone(), version.clone())).cloned(),
+            source: first("BASE"),
+            installed_size: first("SIZE").and_then(|size| size.parse().ok()),
+            name,
+            version,
+            arch,
+            license,
+        });
+    }
+
+    Ok(packages)
+}
+
+/// Parses pacman's `%KEY%` sections into their lines.
+fn parse_pacman_desc(desc: &str) -> HashMap<String, Vec<String>> {
+    let mut sections: HashMap<String, Vec<String>> = HashMap::new();
+    let mut current = None;
+    for line in desc.lines() {
+        if let Some(key) = line.strip_prefix('%').and_then(|line| line.strip_suffix('%')) {
+            current = Some(key.to_string());
+            sections.entry(key.to_string()).or_default();
+        } else if line.is_empty() {
+            current = None;
+        } else if let Some(key) = &current {
+            sections.entry(key.clone()).or_default().push(line.to_string());
+        }
+    }
+    sections
+}
+
+/// SHA-256 of every package in pacman's gzip-compressed sync databases,
+/// by name and version.
+fn pacman_sync_checksums(sync_dir: &Path) -> Result<HashMap<(String, String), String>> {
+    let mut checksums = HashMap::new();
+    let entries = match std::fs::read_dir(sync_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(checksums),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", sync_dir.display())),
+    };
+
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("db") {
+            continue;
+        }
+        let mut magic = [0u8; 2];
+        let mut file = std::fs::File::open(&path)?;
+        if file.read_exact(&mut magic).is_err() || magic != [0x1f, 0x8b] {
+            continue;
+        }
+
+        let mut archive = tar::Archive::new(GzDecoder::new(std::fs::File::open(&path)?));
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.path()?.ends_with("desc") {
+                continue;
+            }
+            let mut desc = String::new();
+            entry.read_to_string(&mut desc)?;
+            let desc = parse_pacman_desc(&desc);
+            let first = |key: &str| desc.get(key).and_then(|values| values.first()).cloned();
+            if let (Some(name), Some(version), Some(sha256)) = (first("NAME"), first("VERSION"), first("SHA256SUM")) {
+                checksums.insert((name, version), sha256);
+            }
+        }
+    }
+
+    Ok(checksums)
+}
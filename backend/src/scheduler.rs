@@ -0,0 +1,297 @@
+use crate::events::JobEvents;
+use crate::iso_builder::IsoBuilder;
+use crate::models::*;
+use crate::storage::JobStore;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, Notify};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Bounded build queue in front of `IsoBuilder`.
+///
+/// At most `workers` builds run at once. Queued builds are dispatched from
+/// the highest priority lane first; within a lane the user with the fewest
+/// running builds goes next, and submission order breaks ties.
+#[derive(Clone)]
+pub struct BuildScheduler {
+    state: Arc<Mutex<SchedulerState>>,
+    builder: IsoBuilder,
+    jobs: JobStore,
+    events: JobEvents,
+    workers: usize,
+    /// Woken whenever a build finishes and a worker becomes free.
+    worker_freed: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    queue: Vec<QueuedBuild>,
+    running: HashMap<Uuid, RunningBuild>,
+    next_seq: u64,
+}
+
+struct QueuedBuild {
+    job_id: Uuid,
+    config: IsoConfig,
+    owner: String,
+    priority: BuildPriority,
+    seq: u64,
+}
+
+struct RunningBuild {
+    owner: String,
+    cancel: CancellationToken,
+}
+
+/// Frees a running build's worker when dropped, including when the build
+/// panics.
+struct WorkerSlot {
+    scheduler: BuildScheduler,
+    job_id: Uuid,
+}
+
+impl Drop for WorkerSlot {
+    fn drop(&mut self) {
+        let scheduler = self.scheduler.clone();
+        let job_id = self.job_id;
+        let panicked = std::thread::panicking();
+        tokio::spawn(async move {
+            if panicked {
+                scheduler.builder.mark_failed(job_id, "The build crashed").await;
+            }
+            scheduler.state.lock().await.running.remove(&job_id);
+            scheduler.worker_freed.notify_one();
+        });
+    }
+}
+
+impl SchedulerState {
+    fn running_for(&self, owner: &str) -> usize {
+        self.running.values().filter(|build| build.owner == owner).count()
+    }
+
+    /// Index into `queue` of the build to dispatch next.
+    fn pick_next(&self) -> Option<usize> {
+        let next = self.dispatch_order().into_iter().next()?;
+        self.queue.iter().position(|build| build.job_id == next)
+    }
+
+    /// Job ids in the order they would be dispatched if nothing else changed.
+    fn dispatch_order(&self) -> Vec<Uuid> {
+        let mut running: HashMap<&str, usize> = self
+            .queue
+            .iter()
+            .map(|build| (build.owner.as_str(), self.running_for(&build.owner)))
+            .collect();
+        let mut remaining: Vec<&QueuedBuild> = self.queue.iter().collect();
+        let mut order = Vec::with_capacity(remaining.len());
+
+        while !remaining.is_empty() {
+            let index = remaining
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, build)| {
+                    let owner_running = running.get(build.owner.as_str()).copied().unwrap_or(0);
+                    (Reverse(build.priority), owner_running, build.seq)
+                })
+                .map(|(index, _)| index)
+                .unwrap();
+            let build = remaining.remove(index);
+            *running.entry(build.owner.as_str()).or_insert(0) += 1;
+            order.push(build.job_id);
+        }
+
+        order
+    }
+}
+
+impl BuildScheduler {
+    /// Creates the scheduler and starts its dispatch loop; must be called
+    /// from within the Tokio runtime.
+    pub fn new(builder: IsoBuilder, jobs: JobStore, events: JobEvents, workers: usize) -> Self {
+        let scheduler = Self {
+            state: Arc::new(Mutex::new(SchedulerState::default())),
+            builder,
+            jobs,
+            events,
+            workers: workers.max(1),
+            worker_freed: Arc::new(Notify::new()),
+        };
+
+        let dispatcher = scheduler.clone();
+        tokio::spawn(async move {
+            loop {
+                dispatcher.worker_freed.notified().await;
+                let mut state = dispatcher.state.lock().await;
+                dispatcher.dispatch(&mut state).await;
+            }
+        });
+
+        scheduler
+    }
+
+    /// Queues a build that has already been added to the job store.
+    pub async fn submit(&self, job_id: Uuid, config: IsoConfig, owner: String) {
+        let mut state = self.state.lock().await;
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        info!("Queueing job {} for {} ({:?} priority)", job_id, owner, config.priority);
+        state.queue.push(QueuedBuild {
+            job_id,
+            priority: config.priority,
+            config,
+            owner,
+            seq,
+        });
+        self.dispatch(&mut state).await;
+    }
+
+    /// Cancels a queued or running build. Returns `false` if the scheduler
+    /// does not know the job, e.g. because it already finished.
+    pub async fn cancel(&self, job_id: Uuid) -> bool {
+        let mut state = self.state.lock().await;
+
+        if let Some(index) = state.queue.iter().position(|build| build.job_id == job_id) {
+            state.queue.remove(index);
+            self.builder.mark_cancelled(job_id).await;
+            self.update_positions(&state).await;
+            return true;
+        }
+
+        match state.running.get(&job_id) {
+            Some(build) => {
+                build.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Starts queued builds while there are free workers, then refreshes
+    /// the queue positions of everything still waiting.
+    async fn dispatch(&self, state: &mut SchedulerState) {
+        while state.running.len() < self.workers {
+            let Some(index) = state.pick_next() else {
+                break;
+            };
+            let build = state.queue.remove(index);
+            let cancel = CancellationToken::new();
+            state.running.insert(build.job_id, RunningBuild {
+                owner: build.owner,
+                cancel: cancel.clone(),
+            });
+
+            info!("Dispatching job {} ({} running)", build.job_id, state.running.len());
+            let scheduler = self.clone();
+            tokio::spawn(async move {
+                let _slot = WorkerSlot { scheduler: scheduler.clone(), job_id: build.job_id };
+                scheduler.builder.run_build(build.job_id, build.config, cancel).await;
+            });
+        }
+
+        self.update_positions(state).await;
+    }
+
+    async fn update_positions(&self, state: &SchedulerState) {
+        for (index, job_id) in state.dispatch_order().into_iter().enumerate() {
+            let position = Some(index + 1);
+            let result = self.jobs.update(job_id, |job| {
+                if job.queue_position != position {
+                    job.queue_position = position;
+                    self.events.publish(job_id, MessageType::StatusUpdate, serde_json::json!({
+                        "status": job.status,
+                        "queue_position": position,
+                    }));
+                }
+            }).await;
+            if let Err(e) = result {
+                warn!("Failed to store queue position of job {}: {}", job_id, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> IsoConfig {
+        serde_json::from_value(serde_json::json!({
+            "id": Uuid::nil(),
+            "name": "test",
+            "distro": {
+                "id": "debian", "name": "Debian", "description": "", "icon": "",
+                "category": "Debian", "default_packages": [], "desktop_environment": "",
+                "base_image": "debian:bookworm",
+            },
+            "packages": [],
+            "custom_scripts": [],
+            "desktop_environment": null,
+            "theme": {
+                "wallpaper": null, "gtk_theme": null, "icon_theme": null,
+                "colors": { "primary": "", "secondary": "", "background": "", "text": "" },
+            },
+            "created_at": "2024-01-01T00:00:00Z",
+        }))
+        .unwrap()
+    }
+
+    /// Queues a build for `owner` and returns its job id.
+    fn queue(state: &mut SchedulerState, owner: &str, priority: BuildPriority) -> Uuid {
+        let job_id = Uuid::new_v4();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.queue.push(QueuedBuild { job_id, config: config(), owner: owner.to_string(), priority, seq });
+        job_id
+    }
+
+    fn run(state: &mut SchedulerState, owner: &str) {
+        state.running.insert(Uuid::new_v4(), RunningBuild {
+            owner: owner.to_string(),
+            cancel: CancellationToken::new(),
+        });
+    }
+
+    #[test]
+    fn higher_lanes_go_first() {
+        let mut state = SchedulerState::default();
+        let low = queue(&mut state, "alice", BuildPriority::Low);
+        let normal = queue(&mut state, "alice", BuildPriority::Normal);
+        let high = queue(&mut state, "bob", BuildPriority::High);
+        assert_eq!(state.dispatch_order(), vec![high, normal, low]);
+    }
+
+    #[test]
+    fn users_take_turns_within_a_lane() {
+        let mut state = SchedulerState::default();
+        let alice_1 = queue(&mut state, "alice", BuildPriority::Normal);
+        let alice_2 = queue(&mut state, "alice", BuildPriority::Normal);
+        let alice_3 = queue(&mut state, "alice", BuildPriority::Normal);
+        let bob_1 = queue(&mut state, "bob", BuildPriority::Normal);
+        let bob_2 = queue(&mut state, "bob", BuildPriority::Normal);
+        assert_eq!(state.dispatch_order(), vec![alice_1, bob_1, alice_2, bob_2, alice_3]);
+    }
+
+    #[test]
+    fn running_builds_count_against_their_owner() {
+        let mut state = SchedulerState::default();
+        run(&mut state, "alice");
+        run(&mut state, "alice");
+        let alice = queue(&mut state, "alice", BuildPriority::Normal);
+        let bob = queue(&mut state, "bob", BuildPriority::Normal);
+        assert_eq!(state.dispatch_order(), vec![bob, alice]);
+        assert_eq!(state.pick_next(), Some(1));
+    }
+
+    #[test]
+    fn lanes_outrank_fairness() {
+        let mut state = SchedulerState::default();
+        run(&mut state, "alice");
+        let bob = queue(&mut state, "bob", BuildPriority::Normal);
+        let alice = queue(&mut state, "alice", BuildPriority::High);
+        assert_eq!(state.dispatch_order(), vec![alice, bob]);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::models::{CustomScript, ScriptPhase};
+use anyhow::{Context, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tokio::fs;
+
+/// Directory in the image holding first-boot scripts.
+const FIRST_BOOT_DIR: &str = "/usr/local/lib/iso-builder/first-boot";
+
+/// Directory on the installed system recording which first-boot scripts
+/// completed; created by systemd from the units' `StateDirectory=`.
+const FIRST_BOOT_STATE_DIR: &str = "/var/lib/iso-builder/first-boot";
+
+/// A custom script scheduled to run in its phase.
+pub struct PlannedScript<'a> {
+    pub script: &'a CustomScript,
+    /// `<position>-<name>`, unique within the phase and sorting in run order.
+    pub file_name: String,
+    /// Name of the script in the build log.
+    pub label: String,
+}
+
+impl PlannedScript<'_> {
+    /// Command line that runs the script stored at `path`.
+    pub fn argv(&self, path: &str) -> Vec<String> {
+        match self.script.interpreter {
+            Some(interpreter) => vec![interpreter.path().to_string(), path.to_string()],
+            None if self.script.content.starts_with("#!") => vec![path.to_string()],
+            // Scripts without a shebang are run by the shell, as `chroot` did.
+            None => vec!["/bin/sh".to_string(), path.to_string()],
+        }
+    }
+
+    fn unit_name(&self) -> String {
+        format!("iso-builder-first-boot-{}.service", self.file_name)
+    }
+}
+
+/// The scripts of `phase` in the order they run.
+pub fn planned(scripts: &[CustomScript], phase: ScriptPhase) -> Vec<PlannedScript<'_>> {
+    let mut selected: Vec<(usize, &CustomScript)> = scripts.iter()
+        .enumerate()
+        .filter(|(_, script)| script.phase == phase)
+        .collect();
+    // Stable, so scripts with the same key keep their list order.
+    selected.sort_by_key(|(_, script)| script.order);
+
+    selected.into_iter()
+        .enumerate()
+        .map(|(position, (index, script))| {
+            let slug = script.name.as_deref().map(slug).filter(|slug| !slug.is_empty());
+            PlannedScript {
+                script,
+                file_name: format!("{:02}-{}", position + 1, slug.as_deref().unwrap_or("script")),
+                label: match &script.name {
+                    Some(name) => format!("script {}", name),
+                    None => format!("script {}", index + 1),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Lowercase letters, digits and dashes of `name`, for file and unit names.
+fn slug(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(40);
+    slug.trim_end_matches('-').to_string()
+}
+
+/// `label` with control characters dropped so it stays on its line, and
+/// `%` escaped so systemd does not expand it as a specifier.
+fn unit_description(label: &str) -> String {
+    label.chars().filter(|c| !c.is_control()).collect::<String>().replace('%', "%%")
+}
+
+/// Installs `scripts` into the image together with a systemd oneshot unit
+/// each, enabled for `multi-user.target`. Every unit runs after the previous
+/// one and only until its script succeeds once. Returns the unit names.
+pub async fn install_first_boot(chroot_dir: &Path, scripts: &[PlannedScript<'_>]) -> Result<Vec<String>> {
+    let script_dir = chroot_dir.join(FIRST_BOOT_DIR.trim_start_matches('/'));
+    let unit_dir = chroot_dir.join("etc/systemd/system");
+    let wants_dir = unit_dir.join("multi-user.target.wants");
+    fs::create_dir_all(&script_dir).await?;
+    fs::create_dir_all(&wants_dir).await?;
+
+    let mut units: Vec<String> = Vec::new();
+    for planned in scripts {
+        let script_path = script_dir.join(&planned.file_name);
+        fs::write(&script_path, &planned.script.content).await
+            .with_context(|| format!("Failed to write {}", script_path.display()))?;
+        fs::set_permissions(&script_path, std::fs::Permissions::from_mode(0o755)).await?;
+
+        let unit_name = planned.unit_name();
+        let done = format!("{}/{}.done", FIRST_BOOT_STATE_DIR, planned.file_name);
+        let after = units.last().map(|previous| format!(" {}", previous)).unwrap_or_default();
+        let unit = format!(
+            "[Unit]\n\
+             Description=First-boot {}\n\
+             Wants=network-online.target\n\
+             After=network-online.target{}\n\
+             ConditionPathExists=!{}\n\
+             \n\
+             [Service]\n\
+             Type=oneshot\n\
+             RemainAfterExit=yes\n\
+             StateDirectory=iso-builder/first-boot\n\
+             ExecStart={}\n\
+             ExecStartPost=/bin/touch {}\n\
+             StandardOutput=journal+console\n\
+             StandardError=journal+console\n\
+             \n\
+             [Install]\n\
+             WantedBy=multi-user.target\n",
+            unit_description(&planned.label),
+            after,
+            done,
+            planned.argv(&format!("{}/{}", FIRST_BOOT_DIR, planned.file_name)).join(" "),
+            done,
+        );
+        fs::write(unit_dir.join(&unit_name), unit).await?;
+
+        let link = wants_dir.join(&unit_name);
+        if fs::symlink_metadata(&link).await.is_ok() {
+            fs::remove_file(&link).await?;
+        }
+        fs::symlink(format!("/etc/systemd/system/{}", unit_name), &link).await?;
+
+        units.push(unit_name);
+    }
+    Ok(units)
+}
@@ -0,0 +1,201 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use blake2::{digest::consts::U32, Blake2b, Blake2b512, Digest};
+use ed25519_dalek::{Signer, SigningKey};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+const SIGNATURE_ALGORITHM: &[u8; 2] = b"Ed";
+/// Signature algorithm id for signatures over the BLAKE2b-512 of the data.
+const PREHASHED_SIGNATURE_ALGORITHM: &[u8; 2] = b"ED";
+/// Key derivation id for secret keys stored without a password.
+const NO_KDF: &[u8; 2] = b"\0\0";
+const CHECKSUM_ALGORITHM: &[u8; 2] = b"B2";
+
+/// Ed25519 key that signs build manifests in minisign's format.
+///
+/// The secret key is kept as an unencrypted minisign key file (as written by
+/// `minisign -G -W`), so it can also be used with the `minisign` tool, and
+/// signatures verify with `minisign -Vm checksums.sha256 -p <public key>`.
+pub struct ManifestSigner {
+    key_id: [u8; 8],
+    signing_key: SigningKey,
+}
+
+impl ManifestSigner {
+    /// Loads the key at `path`, generating and saving a new one if the file
+    /// does not exist yet.
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => Self::parse_secret_key(&contents)
+                .with_context(|| format!("Invalid signing key {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let signer = Self::generate()?;
+                let mut file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+                file.write_all(signer.secret_key_file().as_bytes())?;
+                Ok(signer)
+            }
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    fn generate() -> Result<Self> {
+        let mut random = [0u8; 40];
+        std::fs::File::open("/dev/urandom")?.read_exact(&mut random)?;
+
+        let mut key_id = [0u8; 8];
+        let mut seed = [0u8; 32];
+        key_id.copy_from_slice(&random[..8]);
+        seed.copy_from_slice(&random[8..]);
+
+        Ok(Self {
+            key_id,
+            signing_key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    /// Key id as minisign prints it.
+    pub fn key_id(&self) -> String {
+        format!("{:016X}", u64::from_le_bytes(self.key_id))
+    }
+
+    /// Contents of the minisign public key file.
+    pub fn public_key_file(&self) -> String {
+        let mut key = Vec::with_capacity(42);
+        key.extend_from_slice(SIGNATURE_ALGORITHM);
+        key.extend_from_slice(&self.key_id);
+        key.extend_from_slice(self.signing_key.verifying_key().as_bytes());
+
+        format!("untrusted comment: minisign public key {}\n{}\n", self.key_id(), BASE64.encode(key))
+    }
+
+    /// Contents of a `.minisig` file for `data`, which is hashed with
+    /// BLAKE2b-512 before signing as current minisign versions do.
+    pub fn sign(&self, data: &[u8], trusted_comment: &str) -> String {
+        let signature = self.signing_key.sign(&Blake2b512::digest(data));
+
+        let mut signature_line = Vec::with_capacity(74);
+        signature_line.extend_from_slice(PREHASHED_SIGNATURE_ALGORITHM);
+        signature_line.extend_from_slice(&self.key_id);
+        signature_line.extend_from_slice(&signature.to_bytes());
+
+        // The global signature binds the trusted comment to the signature.
+        let mut global = signature.to_bytes().to_vec();
+        global.extend_from_slice(trusted_comment.as_bytes());
+        let global_signature = self.signing_key.sign(&global);
+
+        format!(
+            "untrusted comment: signature from iso-creator key {}\n{}\ntrusted comment: {}\n{}\n",
+            self.key_id(),
+            BASE64.encode(signature_line),
+            trusted_comment,
+            BASE64.encode(global_signature.to_bytes()),
+        )
+    }
+
+    fn secret_key_file(&self) -> String {
+        let mut secret = Vec::with_capacity(72);
+        secret.extend_from_slice(&self.key_id);
+        secret.extend_from_slice(&self.signing_key.to_keypair_bytes());
+
+        let mut key = Vec::with_capacity(158);
+        key.extend_from_slice(SIGNATURE_ALGORITHM);
+        key.extend_from_slice(NO_KDF);
+        key.extend_from_slice(CHECKSUM_ALGORITHM);
+        // Salt and scrypt limits are unused without a password.
+        key.extend_from_slice(&[0u8; 48]);
+        key.extend_from_slice(&secret);
+        key.extend_from_slice(&Self::checksum(&secret));
+
+        format!(
+            "untrusted comment: iso-creator secret key {}\n{}\n",
+            self.key_id(),
+            BASE64.encode(key),
+        )
+    }
+
+    fn parse_secret_key(contents: &str) -> Result<Self> {
+        let encoded = contents
+            .lines()
+            .find(|line| !line.is_empty() && !line.starts_with("untrusted comment:"))
+            .ok_or_else(|| anyhow::anyhow!("Missing key data"))?;
+        let key = BASE64.decode(encoded.trim()).context("Key data is not valid base64")?;
+        if key.len() != 158 {
+            return Err(anyhow::anyhow!("Unexpected key length {}", key.len()));
+        }
+        if &key[..2] != SIGNATURE_ALGORITHM || &key[4..6] != CHECKSUM_ALGORITHM {
+            return Err(anyhow::anyhow!("Not a minisign Ed25519 secret key"));
+        }
+        if &key[2..4] != NO_KDF {
+            return Err(anyhow::anyhow!("Password-protected keys are not supported; create the key with minisign -G -W"));
+        }
+
+        let secret = &key[54..126];
+        if key[126..] != Self::checksum(secret) {
+            return Err(anyhow::anyhow!("Key checksum mismatch"));
+        }
+
+        let mut key_id = [0u8; 8];
+        key_id.copy_from_slice(&secret[..8]);
+        let mut keypair = [0u8; 64];
+        keypair.copy_from_slice(&secret[8..]);
+        let signing_key = SigningKey::from_keypair_bytes(&keypair)
+            .map_err(|e| anyhow::anyhow!("Invalid Ed25519 key pair: {}", e))?;
+
+        Ok(Self { key_id, signing_key })
+    }
+
+    /// BLAKE2b-256 over the algorithm id, key id and key pair.
+    fn checksum(secret: &[u8]) -> [u8; 32] {
+        let mut hasher = Blake2b::<U32>::new();
+        hasher.update(SIGNATURE_ALGORITHM);
+        hasher.update(secret);
+        hasher.finalize().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use minisign_verify::{PublicKey, Signature};
+
+    #[test]
+    fn signatures_verify_with_minisign() {
+        let signer = ManifestSigner::generate().unwrap();
+        let data = b"0123abcd  image.iso\n";
+        let signature = signer.sign(data, "timestamp:0\tfile:checksums.sha256");
+
+        let public_key = PublicKey::decode(&signer.public_key_file()).unwrap();
+        let signature = Signature::decode(&signature).unwrap();
+        assert_eq!(signature.trusted_comment(), "timestamp:0\tfile:checksums.sha256");
+        public_key.verify(data, &signature, false).unwrap();
+        assert!(public_key.verify(b"tampered", &signature, false).is_err());
+    }
+
+    #[test]
+    fn reloads_the_saved_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("signing.key");
+        let generated = ManifestSigner::load_or_generate(&path).unwrap();
+        let loaded = ManifestSigner::load_or_generate(&path).unwrap();
+        assert_eq!(loaded.key_id(), generated.key_id());
+        assert_eq!(loaded.public_key_file(), generated.public_key_file());
+    }
+
+    #[test]
+    fn rejects_a_corrupted_key() {
+        let signer = ManifestSigner::generate().unwrap();
+        let file = signer.secret_key_file();
+        let encoded = file.lines().nth(1).unwrap();
+        let mut key = BASE64.decode(encoded).unwrap();
+        key[100] ^= 1;
+        let corrupted = format!("untrusted comment: test\n{}\n", BASE64.encode(key));
+        assert!(ManifestSigner::parse_secret_key(&corrupted).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use crate::sbom::Sbom;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Entries listed per directory in the path tree; the rest are summed up
+/// in `other_size`.
+const MAX_CHILDREN: usize = 10;
+
+/// Levels of directories below `/` the path tree goes into.
+const MAX_DEPTH: usize = 4;
+
+/// What takes up the space in a root filesystem: the installed size of
+/// every package and the largest paths.
+#[derive(Debug, Serialize)]
+pub struct SizeBreakdown {
+    /// Bytes in the files of the root filesystem.
+    pub total_size: u64,
+    pub package_manager: String,
+    /// Installed packages, largest first.
+    pub packages: Vec<PackageSize>,
+    /// The root directory and, down to `MAX_DEPTH` levels, its largest entries.
+    pub paths: PathSize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PackageSize {
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+    /// `None` when the package database does not record a size.
+    pub installed_size: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PathSize {
+    /// Absolute path inside the image.
+    pub path: String,
+    /// Bytes in the file, or in every file under the directory.
+    pub size: u64,
+    /// Largest entries of a directory, largest first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<PathSize>,
+    /// Bytes in the entries left out of `children`.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub other_size: u64,
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+impl SizeBreakdown {
+    /// Measures `chroot_dir`, whose packages `sbom` lists. Hard-linked files
+    /// are counted once and other filesystems mounted inside are skipped.
+    pub async fn collect(chroot_dir: &Path, sbom: &Sbom) -> Result<Self> {
+        let mut packages: Vec<PackageSize> = sbom.packages.iter()
+            .map(|package| PackageSize {
+                name: package.name.clone(),
+                version: package.version.clone(),
+                arch: package.arch.clone(),
+                installed_size: package.installed_size,
+            })
+            .collect();
+        packages.sort_by(|a, b| b.installed_size.cmp(&a.installed_size).then_with(|| a.name.cmp(&b.name)));
+
+        let root = chroot_dir.to_path_buf();
+        let paths = tokio::task::spawn_blocking(move || {
+            let device = std::fs::metadata(&root)?.dev();
+            measure(&root, "/".to_string(), device, 0, &mut HashSet::new())
+        })
+        .await??;
+
+        Ok(Self {
+            total_size: paths.size,
+            package_manager: sbom.package_manager.to_string(),
+            packages,
+            paths,
+        })
+    }
+
+    /// The `count` largest packages with a known size.
+    pub fn largest_packages(&self, count: usize) -> impl Iterator<Item = (&str, u64)> {
+        self.packages.iter()
+            .filter_map(|package| Some((package.name.as_str(), package.installed_size?)))
+            .take(count)
+    }
+}
+
+/// Size of the directory `dir`, shown as `path`, with its largest entries.
+fn measure(dir: &Path, path: String, device: u64, depth: usize, seen: &mut HashSet<(u64, u64)>) -> Result<PathSize> {
+    let mut children = Vec::new();
+    let entries = std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        let metadata = std::fs::symlink_metadata(entry.path())?;
+        let child_path = format!("{}/{}", path.trim_end_matches('/'), entry.file_name().to_string_lossy());
+        if metadata.is_dir() {
+            if metadata.dev() == device {
+                children.push(measure(&entry.path(), child_path, device, depth + 1, seen)?);
+            }
+        } else if metadata.nlink() == 1 || seen.insert((metadata.dev(), metadata.ino())) {
+            children.push(PathSize { path: child_path, size: metadata.len(), children: Vec::new(), other_size: 0 });
+        }
+    }
+
+    let size = children.iter().map(|child| child.size).sum();
+    if depth >= MAX_DEPTH {
+        return Ok(PathSize { path, size, children: Vec::new(), other_size: 0 });
+    }
+    children.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    let other_size = children.iter().skip(MAX_CHILDREN).map(|child| child.size).sum();
+    children.truncate(MAX_CHILDREN);
+    Ok(PathSize { path, size, children, other_size })
+}
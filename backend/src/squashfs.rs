@@ -0,0 +1,95 @@
+use crate::models::SquashfsConfig;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tokio::process::Command as AsyncCommand;
+
+/// Block sizes `mksquashfs` accepts, in bytes.
+const MIN_BLOCK_SIZE: u32 = 4 * 1024;
+const MAX_BLOCK_SIZE: u32 = 1024 * 1024;
+
+/// Checks the compression settings before a build is queued.
+pub fn validate(config: &SquashfsConfig) -> Result<()> {
+    if let Some(level) = config.level {
+        let compressor = config.compressor.name();
+        match config.compressor.levels() {
+            Some(levels) if levels.contains(&level) => {}
+            Some(levels) => {
+                return Err(anyhow::anyhow!(
+                    "{} compression levels go from {} to {}", compressor, levels.start(), levels.end(),
+                ));
+            }
+            None => return Err(anyhow::anyhow!("{} has no compression levels", compressor)),
+        }
+    }
+    if let Some(block_size) = config.block_size {
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            return Err(anyhow::anyhow!(
+                "Squashfs block size must be a power of two from {} to {} bytes", MIN_BLOCK_SIZE, MAX_BLOCK_SIZE,
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// `mksquashfs` invocation compressing `chroot_dir`, without its top-level
+/// `exclude` directories, into `output`.
+pub fn command(
+    chroot_dir: &Path,
+    output: &Path,
+    exclude: &[&str],
+    config: &SquashfsConfig,
+    source_date_epoch: Option<u64>,
+) -> AsyncCommand {
+    let mut cmd = AsyncCommand::new("mksquashfs");
+    cmd.arg(chroot_dir)
+        .arg(output)
+        .arg("-noappend")
+        .args(["-comp", config.compressor.name()]);
+    if let Some(level) = config.level {
+        cmd.arg("-Xcompression-level").arg(level.to_string());
+    }
+    if let Some(block_size) = config.block_size {
+        cmd.arg("-b").arg(block_size.to_string());
+    }
+    if let Some(epoch) = source_date_epoch {
+        // mksquashfs sorts directory entries itself; pin every timestamp.
+        cmd.arg("-mkfs-time").arg(epoch.to_string())
+            .arg("-all-time").arg(epoch.to_string());
+    }
+    // Every argument after -e is taken as a path to exclude, so it goes last.
+    if !exclude.is_empty() {
+        cmd.arg("-e").args(exclude);
+    }
+    cmd
+}
+
+/// Bytes in the files under `root`, without its top-level `exclude`
+/// directories, counting hard-linked files once.
+pub async fn uncompressed_size(root: &Path, exclude: &[&str]) -> Result<u64> {
+    let root = root.to_path_buf();
+    let excluded: Vec<PathBuf> = exclude.iter().map(|name| root.join(name)).collect();
+    tokio::task::spawn_blocking(move || {
+        let mut total = 0;
+        let mut seen = HashSet::new();
+        let mut pending = vec![root];
+        while let Some(dir) = pending.pop() {
+            let entries = std::fs::read_dir(&dir)
+                .with_context(|| format!("Failed to read {}", dir.display()))?;
+            for entry in entries {
+                let path = entry?.path();
+                let metadata = std::fs::symlink_metadata(&path)?;
+                if metadata.is_dir() {
+                    if !excluded.contains(&path) {
+                        pending.push(path);
+                    }
+                } else if metadata.nlink() == 1 || seen.insert((metadata.dev(), metadata.ino())) {
+                    total += metadata.len();
+                }
+            }
+        }
+        Ok(total)
+    })
+    .await?
+}
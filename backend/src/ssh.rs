@@ -0,0 +1,301 @@
+use crate::bootstrap::{chroot_command, PackageManager};
+use crate::models::{SshConfig, UserConfig};
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tokio::fs;
+use tokio::process::Command as AsyncCommand;
+
+/// Ciphers sshd may be restricted to.
+const KNOWN_CIPHERS: &[&str] = &[
+    "chacha20-poly1305@openssh.com",
+    "aes256-gcm@openssh.com",
+    "aes128-gcm@openssh.com",
+    "aes256-ctr",
+    "aes192-ctr",
+    "aes128-ctr",
+    "aes256-cbc",
+    "aes192-cbc",
+    "aes128-cbc",
+    "3des-cbc",
+];
+
+/// Ciphers allowed when the policy does not list any: the AEAD and CTR
+/// modes, without CBC.
+const DEFAULT_CIPHERS: &[&str] = &[
+    "chacha20-poly1305@openssh.com",
+    "aes256-gcm@openssh.com",
+    "aes128-gcm@openssh.com",
+    "aes256-ctr",
+    "aes192-ctr",
+    "aes128-ctr",
+];
+
+/// Smallest RSA modulus accepted in an authorized key.
+const MIN_RSA_BITS: usize = 2048;
+
+/// sshd settings written by the builder.
+const SSHD_DROP_IN: &str = "etc/ssh/sshd_config.d/50-iso-builder.conf";
+
+/// Checks that `line` is an OpenSSH public key, `<type> <base64> [comment]`,
+/// whose encoded key is well-formed and of the type it claims.
+pub fn validate_public_key(line: &str) -> Result<()> {
+    if line.contains(['\n', '\r']) {
+        return Err(anyhow::anyhow!("An SSH key must be a single line"));
+    }
+    let mut fields = line.split_whitespace();
+    let (Some(key_type), Some(encoded)) = (fields.next(), fields.next()) else {
+        return Err(anyhow::anyhow!("Expected an SSH public key such as \"ssh-ed25519 AAAA... user@host\""));
+    };
+    let blob = BASE64.decode(encoded).context("The key is not valid base64")?;
+
+    let mut reader = SshReader(&blob);
+    let encoded_type = reader.string()?;
+    if encoded_type != key_type.as_bytes() {
+        return Err(anyhow::anyhow!("The key data is not a {} key", key_type));
+    }
+    match key_type {
+        "ssh-ed25519" => {
+            if reader.string()?.len() != 32 {
+                return Err(anyhow::anyhow!("Invalid Ed25519 key length"));
+            }
+        }
+        "sk-ssh-ed25519@openssh.com" => {
+            if reader.string()?.len() != 32 {
+                return Err(anyhow::anyhow!("Invalid Ed25519 key length"));
+            }
+            reader.string()?;
+        }
+        "ssh-rsa" => {
+            let _exponent = reader.string()?;
+            let modulus = reader.string()?;
+            let significant = modulus.iter().skip_while(|byte| **byte == 0).count();
+            if significant * 8 < MIN_RSA_BITS {
+                return Err(anyhow::anyhow!("RSA keys must have at least {} bits", MIN_RSA_BITS));
+            }
+        }
+        "ecdsa-sha2-nistp256" | "ecdsa-sha2-nistp384" | "ecdsa-sha2-nistp521" => {
+            let curve = reader.string()?;
+            if !key_type.ends_with(std::str::from_utf8(curve).unwrap_or("")) {
+                return Err(anyhow::anyhow!("The key's curve does not match {}", key_type));
+            }
+            reader.string()?;
+        }
+        "sk-ecdsa-sha2-nistp256@openssh.com" => {
+            if reader.string()? != b"nistp256" {
+                return Err(anyhow::anyhow!("The key's curve does not match {}", key_type));
+            }
+            reader.string()?;
+            reader.string()?;
+        }
+        "ssh-dss" => return Err(anyhow::anyhow!("DSA keys are no longer supported by OpenSSH")),
+        _ => return Err(anyhow::anyhow!("Unsupported SSH key type {}", key_type)),
+    }
+    if !reader.0.is_empty() {
+        return Err(anyhow::anyhow!("Trailing data after the {} key", key_type));
+    }
+    Ok(())
+}
+
+/// Reads the length-prefixed strings of the SSH wire format.
+struct SshReader<'a>(&'a [u8]);
+
+impl<'a> SshReader<'a> {
+    fn string(&mut self) -> Result<&'a [u8]> {
+        let truncated = || anyhow::anyhow!("The key data is truncated");
+        let length: [u8; 4] = self.0.get(..4).ok_or_else(truncated)?.try_into()?;
+        let length = u32::from_be_bytes(length) as usize;
+        let value = self.0.get(4..4 + length).ok_or_else(truncated)?;
+        self.0 = &self.0[4 + length..];
+        Ok(value)
+    }
+}
+
+/// Checks the sshd policy before a build is queued.
+pub fn validate_policy(ssh: &SshConfig) -> Result<()> {
+    for cipher in &ssh.ciphers {
+        if !KNOWN_CIPHERS.contains(&cipher.as_str()) {
+            return Err(anyhow::anyhow!("Unknown SSH cipher {:?}", cipher));
+        }
+    }
+    Ok(())
+}
+
+/// Package providing sshd.
+pub fn server_package(package_manager: PackageManager) -> &'static str {
+    match package_manager {
+        PackageManager::Pacman => "openssh",
+        PackageManager::Apt | PackageManager::Dnf => "openssh-server",
+    }
+}
+
+/// systemd unit running sshd.
+fn service_name(package_manager: PackageManager) -> &'static str {
+    match package_manager {
+        PackageManager::Apt => "ssh.service",
+        PackageManager::Dnf | PackageManager::Pacman => "sshd.service",
+    }
+}
+
+/// Writes `~/.ssh/authorized_keys` for every user with keys, owned by the
+/// user and readable only by them.
+pub async fn write_authorized_keys(chroot_dir: &Path, users: &[UserConfig]) -> Result<()> {
+    let passwd = fs::read_to_string(chroot_dir.join("etc/passwd")).await
+        .context("Failed to read /etc/passwd")?;
+
+    for user in users.iter().filter(|user| !user.authorized_keys.is_empty()) {
+        let fields: Vec<&str> = passwd.lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .find(|fields| fields.len() > 5 && fields[0] == user.name)
+            .ok_or_else(|| anyhow::anyhow!("User {} does not exist in the image", user.name))?;
+        let uid: u32 = fields[2].parse()?;
+        let gid: u32 = fields[3].parse()?;
+        let home = fields[5].trim_start_matches('/');
+        if home.is_empty() || home.split('/').any(|part| part == "..") {
+            return Err(anyhow::anyhow!("User {} has no usable home directory", user.name));
+        }
+
+        let ssh_dir = chroot_dir.join(home).join(".ssh");
+        fs::create_dir_all(&ssh_dir).await?;
+        fs::set_permissions(&ssh_dir, std::fs::Permissions::from_mode(0o700)).await?;
+        std::os::unix::fs::lchown(&ssh_dir, Some(uid), Some(gid))?;
+
+        let keys_path = ssh_dir.join("authorized_keys");
+        let keys: String = user.authorized_keys.iter().map(|key| format!("{}\n", key.trim())).collect();
+        fs::write(&keys_path, keys).await?;
+        fs::set_permissions(&keys_path, std::fs::Permissions::from_mode(0o600)).await?;
+        std::os::unix::fs::lchown(&keys_path, Some(uid), Some(gid))?;
+    }
+    Ok(())
+}
+
+/// Applies `policy` to sshd, makes every machine generate its own host keys
+/// on first start, and returns the command that enables the service.
+pub async fn configure_server(
+    chroot_dir: &Path,
+    policy: &SshConfig,
+    package_manager: PackageManager,
+) -> Result<AsyncCommand> {
+    let ciphers = if policy.ciphers.is_empty() {
+        DEFAULT_CIPHERS.join(",")
+    } else {
+        policy.ciphers.join(",")
+    };
+    let yes_no = |value: bool| if value { "yes" } else { "no" };
+    let settings = format!(
+        "# Written by the ISO builder\n\
+         PubkeyAuthentication yes\n\
+         PasswordAuthentication {}\n\
+         KbdInteractiveAuthentication no\n\
+         PermitRootLogin {}\n\
+         PermitEmptyPasswords no\n\
+         Ciphers {}\n",
+        yes_no(policy.password_authentication),
+        yes_no(policy.permit_root_login),
+        ciphers,
+    );
+
+    // sshd uses the first value it reads for a setting, so the settings
+    // must come before the distro's defaults.
+    let main_config_path = chroot_dir.join("etc/ssh/sshd_config");
+    let main_config = fs::read_to_string(&main_config_path).await
+        .context("Failed to read /etc/ssh/sshd_config")?;
+    let includes_drop_ins = main_config.lines()
+        .take_while(|line| line.trim().is_empty() || line.trim_start().starts_with('#') || line.trim_start().starts_with("Include"))
+        .any(|line| line.trim_start().starts_with("Include") && line.contains("sshd_config.d"));
+    if includes_drop_ins {
+        let drop_in = chroot_dir.join(SSHD_DROP_IN);
+        fs::create_dir_all(drop_in.parent().unwrap()).await?;
+        fs::write(&drop_in, settings).await?;
+    } else {
+        // Older releases such as Rocky 8 do not read sshd_config.d.
+        fs::write(&main_config_path, format!("{}\n{}", settings, main_config)).await?;
+    }
+
+    // Host keys generated while installing the package would be shared by
+    // every machine booted from the image.
+    let mut entries = fs::read_dir(chroot_dir.join("etc/ssh")).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_string_lossy().starts_with("ssh_host_") {
+            fs::remove_file(entry.path()).await?;
+        }
+    }
+    let service = service_name(package_manager);
+    let service_drop_in = chroot_dir.join(format!("etc/systemd/system/{}.d", service));
+    fs::create_dir_all(&service_drop_in).await?;
+    // Debian's unit checks the configuration with `sshd -t` before this
+    // would run, so the list is reset and the keys generated first.
+    fs::write(
+        service_drop_in.join("10-iso-builder-host-keys.conf"),
+        "[Service]\nExecStartPre=\nExecStartPre=/usr/bin/ssh-keygen -A\nExecStartPre=/usr/sbin/sshd -t\n",
+    ).await?;
+
+    Ok(chroot_command(chroot_dir, &["systemctl", "enable", service]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ED25519: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHmXWmTlWGoXCovMke8+RDh9jwjlPUBoaME3yEUwHz/o alice@laptop";
+    const ECDSA: &str = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBG+AZck2lHsEdms4AAknpOw8dLt1gZXa8Y7OdizKrjbtL1sHq/DZYx7DlDlevPl4uT0JjIYGZ2gnc1eBWf6u0P4=";
+
+    /// Public key line of a key made of the wire-format `strings`.
+    fn key_line(key_type: &str, strings: &[&[u8]]) -> String {
+        let mut blob = Vec::new();
+        for string in std::iter::once(key_type.as_bytes()).chain(strings.iter().copied()) {
+            blob.extend_from_slice(&(string.len() as u32).to_be_bytes());
+            blob.extend_from_slice(string);
+        }
+        format!("{} {}", key_type, BASE64.encode(blob))
+    }
+
+    /// RSA modulus of `bits` bits.
+    fn modulus(bits: usize) -> Vec<u8> {
+        let mut modulus = vec![0xff; bits / 8];
+        modulus.insert(0, 0);
+        modulus
+    }
+
+    #[test]
+    fn accepts_keys_generated_by_ssh_keygen() {
+        validate_public_key(ED25519).unwrap();
+        validate_public_key(ECDSA).unwrap();
+        validate_public_key(ED25519.trim_end_matches(" alice@laptop")).unwrap();
+    }
+
+    #[test]
+    fn checks_rsa_key_sizes() {
+        validate_public_key(&key_line("ssh-rsa", &[&[1, 0, 1], &modulus(2048)])).unwrap();
+        assert!(validate_public_key(&key_line("ssh-rsa", &[&[1, 0, 1], &modulus(1024)])).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        let (key_type, rest) = ED25519.split_once(' ').unwrap();
+        let cases = [
+            String::new(),
+            "ssh-ed25519".to_string(),
+            "ssh-ed25519 not-base64!".to_string(),
+            format!("ssh-rsa {}", rest),
+            format!("{}\nssh-ed25519 {}", ED25519, rest),
+            key_line(key_type, &[&[0; 31]]),
+            key_line(key_type, &[&[0; 32], b"extra"]),
+            key_line("ecdsa-sha2-nistp384", &[b"nistp256", &[4; 65]]),
+            key_line("ssh-dss", &[&[1], &[2], &[3], &[4]]),
+        ];
+        for line in cases {
+            assert!(validate_public_key(&line).is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_keys() {
+        let line = key_line("ssh-ed25519", &[&[0; 32]]);
+        let (key_type, encoded) = line.split_once(' ').unwrap();
+        let blob = BASE64.decode(encoded).unwrap();
+        let truncated = format!("{} {}", key_type, BASE64.encode(&blob[..blob.len() - 1]));
+        assert!(validate_public_key(&truncated).is_err());
+    }
+}
@@ -0,0 +1,378 @@
+use crate::models::*;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+/// Durable storage for build jobs, their configs and their logs.
+#[async_trait]
+pub trait JobStorage: Send + Sync {
+    /// Inserts or replaces everything about a job except its logs.
+    async fn save_job(&self, job: &BuildJob) -> Result<()>;
+
+    /// Appends log entries starting at position `first_index` in `BuildJob.logs`.
+    async fn append_logs(&self, job_id: Uuid, first_index: usize, logs: &[BuildLog]) -> Result<()>;
+
+    async fn get_job(&self, job_id: Uuid) -> Result<Option<BuildJob>>;
+
+    /// Jobs in any of `statuses`, newest first, without their logs.
+    async fn list_jobs(&self, statuses: &[BuildStatus]) -> Result<Vec<BuildJob>>;
+}
+
+/// `JobStorage` backed by a single SQLite database file.
+///
+/// The job itself is stored as JSON next to a few indexed columns, so new
+/// `BuildJob` fields don't need a schema migration. Logs live in their own
+/// table because they are appended line by line while a build runs.
+pub struct SqliteJobStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteJobStorage {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open job database {}", path.display()))?;
+
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = NORMAL;
+             PRAGMA foreign_keys = ON;
+
+             CREATE TABLE IF NOT EXISTS jobs (
+                 id TEXT PRIMARY KEY,
+                 owner TEXT NOT NULL,
+                 status TEXT NOT NULL,
+                 started_at TEXT NOT NULL,
+                 data TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS jobs_status ON jobs (status, started_at);
+
+             CREATE TABLE IF NOT EXISTS job_logs (
+                 job_id TEXT NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+                 idx INTEGER NOT NULL,
+                 timestamp TEXT NOT NULL,
+                 level TEXT NOT NULL,
+                 message TEXT NOT NULL,
+                 PRIMARY KEY (job_id, idx)
+             );",
+        )
+        .context("Failed to initialise job database")?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` against the connection on the blocking thread pool.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            f(&mut conn)
+        })
+        .await?
+    }
+}
+
+#[async_trait]
+impl JobStorage for SqliteJobStorage {
+    async fn save_job(&self, job: &BuildJob) -> Result<()> {
+        let mut row = job.clone();
+        row.logs.clear();
+        let data = serde_json::to_string(&row)?;
+        let id = job.id.to_string();
+        let owner = job.owner.clone();
+        let status = job.status.to_string();
+        let started_at = job.started_at.to_rfc3339();
+
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO jobs (id, owner, status, started_at, data) VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (id) DO UPDATE SET owner = ?2, status = ?3, started_at = ?4, data = ?5",
+                params![id, owner, status, started_at, data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn append_logs(&self, job_id: Uuid, first_index: usize, logs: &[BuildLog]) -> Result<()> {
+        let job_id = job_id.to_string();
+        let logs = logs.to_vec();
+
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(
+                    "INSERT OR REPLACE INTO job_logs (job_id, idx, timestamp, level, message)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                )?;
+                for (offset, log) in logs.iter().enumerate() {
+                    stmt.execute(params![
+                        job_id,
+                        (first_index + offset) as i64,
+                        log.timestamp.to_rfc3339(),
+                        level_name(&log.level)?,
+                        log.message,
+                    ])?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_job(&self, job_id: Uuid) -> Result<Option<BuildJob>> {
+        let id = job_id.to_string();
+
+        self.with_conn(move |conn| {
+            let data: Option<String> = conn
+                .query_row("SELECT data FROM jobs WHERE id = ?1", params![id], |row| row.get(0))
+                .optional()?;
+            let Some(data) = data else {
+                return Ok(None);
+            };
+            let mut job: BuildJob = serde_json::from_str(&data)?;
+
+            let mut stmt = conn.prepare_cached(
+                "SELECT timestamp, level, message FROM job_logs WHERE job_id = ?1 ORDER BY idx",
+            )?;
+            let rows = stmt.query_map(params![id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+            })?;
+            for row in rows {
+                let (timestamp, level, message) = row?;
+                job.logs.push(BuildLog {
+                    timestamp: chrono::DateTime::parse_from_rfc3339(&timestamp)?.with_timezone(&Utc),
+                    level: serde_json::from_value(serde_json::Value::String(level))?,
+                    message,
+                });
+            }
+
+            Ok(Some(job))
+        })
+        .await
+    }
+
+    async fn list_jobs(&self, statuses: &[BuildStatus]) -> Result<Vec<BuildJob>> {
+        let statuses: Vec<String> = statuses.iter().map(|status| status.to_string()).collect();
+
+        self.with_conn(move |conn| {
+            let placeholders = vec!["?"; statuses.len()].join(", ");
+            let mut stmt = conn.prepare(&format!(
+                "SELECT data FROM jobs WHERE status IN ({}) ORDER BY started_at DESC",
+                placeholders,
+            ))?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(statuses.iter()), |row| {
+                row.get::<_, String>(0)
+            })?;
+
+            let mut jobs = Vec::new();
+            for data in rows {
+                jobs.push(serde_json::from_str(&data?)?);
+            }
+            Ok(jobs)
+        })
+        .await
+    }
+}
+
+/// Stores a log level under its serialized name, e.g. `Warning`.
+fn level_name(level: &LogLevel) -> Result<String> {
+    match serde_json::to_value(level)? {
+        serde_json::Value::String(name) => Ok(name),
+        other => Err(anyhow::anyhow!("Unexpected log level encoding: {}", other)),
+    }
+}
+
+/// Log entries buffered in memory before they are written to storage.
+const LOG_BATCH_SIZE: usize = 100;
+
+/// Age at which buffered log entries are written with the next one appended;
+/// any `update` writes them too.
+const LOG_BATCH_AGE: Duration = Duration::from_secs(1);
+
+/// Job state shared by the API, the scheduler and the builder.
+///
+/// Jobs that are still queued or running are kept in memory so progress
+/// updates don't have to round-trip through storage. Changes are written
+/// through to `JobStorage`, which is the source of truth for anything that
+/// has finished; log entries are written in batches.
+#[derive(Clone)]
+pub struct JobStore {
+    live: Arc<RwLock<HashMap<Uuid, LiveJob>>>,
+    storage: Arc<dyn JobStorage>,
+    /// Held while writing to storage, so writes land in the order the
+    /// changes were made without blocking readers of `live`.
+    flush: Arc<tokio::sync::Mutex<()>>,
+}
+
+struct LiveJob {
+    job: BuildJob,
+    /// Number of `job.logs` already written to storage.
+    stored_logs: usize,
+    /// When the oldest log entry not yet in storage was added.
+    oldest_unstored: Option<Instant>,
+    /// Whether storage has the job in a finished status.
+    stored_finished: bool,
+}
+
+impl JobStore {
+    pub fn new(storage: Arc<dyn JobStorage>) -> Self {
+        Self {
+            live: Arc::new(RwLock::new(HashMap::new())),
+            storage,
+            flush: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    pub async fn insert(&self, job: BuildJob) -> Result<()> {
+        self.storage.save_job(&job).await?;
+        self.storage.append_logs(job.id, 0, &job.logs).await?;
+        let stored_logs = job.logs.len();
+        self.live.write().await.insert(job.id, LiveJob { job, stored_logs, oldest_unstored: None, stored_finished: false });
+        Ok(())
+    }
+
+    pub async fn get(&self, job_id: Uuid) -> Result<Option<BuildJob>> {
+        if let Some(live) = self.live.read().await.get(&job_id) {
+            return Ok(Some(live.job.clone()));
+        }
+        self.storage.get_job(job_id).await
+    }
+
+    /// Applies `f` to a live job and writes the job, and any log entries
+    /// not yet stored, through to storage. Returns `None` if the job is not
+    /// queued or running.
+    ///
+    /// `f` runs with the in-memory lock held, so changes (and any events it
+    /// publishes) are observed in the order they were made.
+    pub async fn update<R>(&self, job_id: Uuid, f: impl FnOnce(&mut BuildJob) -> R) -> Result<Option<R>> {
+        let result = {
+            let mut live = self.live.write().await;
+            let Some(entry) = live.get_mut(&job_id) else {
+                return Ok(None);
+            };
+            let logged = entry.job.logs.len();
+            let result = f(&mut entry.job);
+            if entry.job.logs.len() > logged && entry.oldest_unstored.is_none() {
+                entry.oldest_unstored = Some(Instant::now());
+            }
+            result
+        };
+
+        self.flush(job_id, true).await?;
+        Ok(Some(result))
+    }
+
+    /// Appends `log` to a live job without rewriting the job itself. The
+    /// entry is stored with the next batch. `on_append` gets its index and
+    /// runs with the in-memory lock held, e.g. to publish it in order.
+    /// Returns `false` if the job is not queued or running.
+    pub async fn append_log(&self, job_id: Uuid, log: BuildLog, on_append: impl FnOnce(usize, &BuildLog)) -> Result<bool> {
+        let flush = {
+            let mut live = self.live.write().await;
+            let Some(entry) = live.get_mut(&job_id) else {
+                return Ok(false);
+            };
+            on_append(entry.job.logs.len(), &log);
+            entry.job.logs.push(log);
+            let oldest = *entry.oldest_unstored.get_or_insert_with(Instant::now);
+            entry.job.logs.len() - entry.stored_logs >= LOG_BATCH_SIZE
+                || oldest.elapsed() >= LOG_BATCH_AGE
+                || entry.job.is_finished()
+        };
+
+        if flush {
+            self.flush(job_id, false).await?;
+        }
+        Ok(true)
+    }
+
+    /// Writes the log entries of a live job not yet in storage and, with
+    /// `save_job`, the job itself. Always writes the job's current state,
+    /// so concurrent flushes cannot store an older one last. Finished jobs
+    /// leave memory once everything is stored.
+    async fn flush(&self, job_id: Uuid, save_job: bool) -> Result<()> {
+        let _flush = self.flush.lock().await;
+
+        let (row, first_index, logs) = {
+            let mut live = self.live.write().await;
+            let Some(entry) = live.get_mut(&job_id) else {
+                return Ok(());
+            };
+            let row = save_job.then(|| {
+                let logs = std::mem::take(&mut entry.job.logs);
+                let row = entry.job.clone();
+                entry.job.logs = logs;
+                row
+            });
+            (row, entry.stored_logs, entry.job.logs[entry.stored_logs..].to_vec())
+        };
+
+        if let Some(row) = &row {
+            self.storage.save_job(row).await?;
+        }
+        if !logs.is_empty() {
+            self.storage.append_logs(job_id, first_index, &logs).await?;
+        }
+
+        let mut live = self.live.write().await;
+        if let Some(entry) = live.get_mut(&job_id) {
+            entry.stored_logs = first_index + logs.len();
+            entry.stored_finished |= row.as_ref().is_some_and(BuildJob::is_finished);
+            if entry.stored_logs == entry.job.logs.len() {
+                entry.oldest_unstored = None;
+                if entry.stored_finished {
+                    live.remove(&job_id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn list(&self, statuses: &[BuildStatus]) -> Result<Vec<BuildJob>> {
+        self.storage.list_jobs(statuses).await
+    }
+
+    /// Marks every job left unfinished by a previous run as failed, since
+    /// its build process no longer exists. Returns how many were updated.
+    pub async fn fail_interrupted(&self) -> Result<usize> {
+        let interrupted = self.storage.list_jobs(&[
+            BuildStatus::Queued,
+            BuildStatus::Building,
+            BuildStatus::Packaging,
+            BuildStatus::Uploading,
+        ]).await?;
+
+        for mut job in interrupted.iter().cloned() {
+            let first_index = self.storage.get_job(job.id).await?
+                .map(|stored| stored.logs.len())
+                .unwrap_or(0);
+            let log = BuildLog {
+                timestamp: Utc::now(),
+                level: LogLevel::Error,
+                message: "Build failed: interrupted by a server restart".to_string(),
+            };
+
+            job.status = BuildStatus::Failed;
+            job.queue_position = None;
+            job.completed_at = Some(Utc::now());
+            self.storage.save_job(&job).await?;
+            self.storage.append_logs(job.id, first_index, &[log]).await?;
+        }
+
+        Ok(interrupted.len())
+    }
+}
@@ -0,0 +1,1034 @@
+use crate::bootstrap::PackageManager;
+use crate::models::*;
+use crate::sbom::{InstalledPackage, Sbom};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use serde::Serialize;
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Debian and Ubuntu release numbers by codename, as OSV ecosystems use them.
+const RELEASE_NUMBERS: &[(&str, &str)] = &[
+    ("buster", "10"),
+    ("bullseye", "11"),
+    ("bookworm", "12"),
+    ("trixie", "13"),
+    ("forky", "14"),
+    ("focal", "20.04"),
+    ("jammy", "22.04"),
+    ("noble", "24.04"),
+    ("oracular", "24.10"),
+    ("plucky", "25.04"),
+    ("questing", "25.10"),
+];
+
+/// The system a scan looks for vulnerabilities in.
+pub struct ScanTarget {
+    pub package_manager: PackageManager,
+    pub category: DistroCategory,
+    /// Release the image was built from, e.g. `bookworm` or `9`.
+    pub release: String,
+}
+
+impl ScanTarget {
+    /// Whether an OSV ecosystem such as `Debian:12` or `Ubuntu:22.04:LTS`
+    /// covers this target.
+    fn matches_ecosystem(&self, ecosystem: &str) -> bool {
+        let expected = match self.category {
+            DistroCategory::Debian => "Debian",
+            DistroCategory::Ubuntu => "Ubuntu",
+            DistroCategory::Rocky => "Rocky Linux",
+            _ => return false,
+        };
+        let mut parts = ecosystem.split(':');
+        if parts.next() != Some(expected) {
+            return false;
+        }
+
+        let release = RELEASE_NUMBERS
+            .iter()
+            .find(|(codename, _)| codename.eq_ignore_ascii_case(&self.release))
+            .map(|(_, number)| *number)
+            .unwrap_or(&self.release);
+        match parts.find(|part| part.starts_with(|c: char| c.is_ascii_digit())) {
+            Some(version) => version == release || version.starts_with(&format!("{}.", release)),
+            // Records without a release apply to all of them.
+            None => true,
+        }
+    }
+
+    fn compare_versions(&self, a: &str, b: &str) -> Ordering {
+        match self.package_manager {
+            PackageManager::Apt => compare_dpkg_versions(a, b),
+            // pacman's vercmp follows rpm's rules.
+            PackageManager::Dnf | PackageManager::Pacman => compare_rpm_versions(a, b),
+        }
+    }
+}
+
+/// A vulnerability affecting an installed package.
+#[derive(Debug, Clone, Serialize)]
+pub struct VulnerabilityFinding {
+    /// CVE id where known, otherwise the database's own id.
+    pub id: String,
+    /// Other ids of the same vulnerability.
+    pub aliases: Vec<String>,
+    pub package: String,
+    pub installed_version: String,
+    /// First version that fixes the vulnerability, if one is available.
+    pub fixed_version: Option<String>,
+    pub severity: VulnerabilitySeverity,
+    /// CVSS v3 base score, if the database gives a vector.
+    pub cvss_score: Option<f64>,
+    pub summary: Option<String>,
+    /// Database file the finding came from, relative to the database directory.
+    pub source: String,
+}
+
+/// A database file the scan read.
+#[derive(Debug, Clone, Serialize)]
+pub struct DatabaseFile {
+    pub path: String,
+    pub format: &'static str,
+    /// Vulnerability records read from the file.
+    pub records: usize,
+}
+
+/// Contents of the vulnerability report artifact.
+#[derive(Debug, Serialize)]
+pub struct VulnerabilityReport {
+    pub generated_at: DateTime<Utc>,
+    pub databases: Vec<DatabaseFile>,
+    pub summary: VulnerabilitySummary,
+    /// Most severe first.
+    pub findings: Vec<VulnerabilityFinding>,
+}
+
+/// Matches the packages of an SBOM against the vulnerability databases in a
+/// directory: OSV JSON records (single files, arrays or zipped dumps), the
+/// Debian security tracker's JSON export, the Arch Linux security tracker's
+/// JSON and OVAL XML definitions. Files may be gzip-compressed.
+pub fn scan(database_dir: &Path, sbom: &Sbom, target: &ScanTarget) -> Result<VulnerabilityReport> {
+    let mut scanner = Scanner::new(sbom, target);
+    let mut databases = Vec::new();
+
+    let mut files = Vec::new();
+    collect_files(database_dir, &mut files)?;
+    files.sort();
+    for path in files {
+        let relative = path.strip_prefix(database_dir).unwrap_or(&path).display().to_string();
+        let records_before = scanner.records;
+        scanner.source = relative.clone();
+        let format = scanner.scan_file(&path)
+            .with_context(|| format!("Failed to read vulnerability database {}", path.display()))?;
+        if let Some(format) = format {
+            databases.push(DatabaseFile { path: relative, format, records: scanner.records - records_before });
+        }
+    }
+    if databases.is_empty() {
+        return Err(anyhow::anyhow!("No vulnerability databases found in {}", database_dir.display()));
+    }
+
+    let mut findings: Vec<VulnerabilityFinding> = scanner.findings.into_values().collect();
+    findings.sort_by(|a, b| {
+        b.severity.cmp(&a.severity)
+            .then_with(|| b.cvss_score.partial_cmp(&a.cvss_score).unwrap_or(Ordering::Equal))
+            .then_with(|| (&a.id, &a.package).cmp(&(&b.id, &b.package)))
+    });
+
+    let mut by_severity = BTreeMap::new();
+    for finding in &findings {
+        *by_severity.entry(finding.severity).or_insert(0) += 1;
+    }
+    let summary = VulnerabilitySummary {
+        total: findings.len(),
+        highest: findings.first().map(|finding| finding.severity),
+        by_severity,
+        databases: databases.len(),
+    };
+
+    Ok(VulnerabilityReport {
+        generated_at: Utc::now(),
+        databases,
+        summary,
+        findings,
+    })
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+struct Scanner<'a> {
+    target: &'a ScanTarget,
+    /// Installed packages by binary and by source package name.
+    packages: HashMap<&'a str, Vec<&'a InstalledPackage>>,
+    /// Keyed by vulnerability id and package, so databases that list the
+    /// same vulnerability do not report it twice.
+    findings: HashMap<(String, String), VulnerabilityFinding>,
+    records: usize,
+    /// Database file being read.
+    source: String,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(sbom: &'a Sbom, target: &'a ScanTarget) -> Self {
+        let mut packages: HashMap<&str, Vec<&InstalledPackage>> = HashMap::new();
+        for package in &sbom.packages {
+            packages.entry(package.name.as_str()).or_default().push(package);
+            if let Some(source) = package.source.as_deref().filter(|source| *source != package.name) {
+                packages.entry(source).or_default().push(package);
+            }
+        }
+
+        Self {
+            target,
+            packages,
+            findings: HashMap::new(),
+            records: 0,
+            source: String::new(),
+        }
+    }
+
+    /// Reads one database file; returns its format, or `None` for files
+    /// that are not a vulnerability database.
+    fn scan_file(&mut self, path: &Path) -> Result<Option<&'static str>> {
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_ascii_lowercase();
+        let (name, compressed) = match name.strip_suffix(".gz") {
+            Some(name) => (name.to_string(), true),
+            None => (name, false),
+        };
+
+        let read = |path: &Path| -> Result<Vec<u8>> {
+            let mut data = Vec::new();
+            if compressed {
+                GzDecoder::new(std::fs::File::open(path)?).read_to_end(&mut data)?;
+            } else {
+                std::fs::File::open(path)?.read_to_end(&mut data)?;
+            }
+            Ok(data)
+        };
+
+        if name.ends_with(".zip") {
+            let mut archive = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+            for index in 0..archive.len() {
+                let mut entry = archive.by_index(index)?;
+                if !entry.name().ends_with(".json") {
+                    continue;
+                }
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                self.scan_osv(&serde_json::from_slice(&data)?);
+            }
+            Ok(Some("OSV"))
+        } else if name.ends_with(".json") {
+            let json: Value = serde_json::from_slice(&read(path)?)?;
+            Ok(Some(self.scan_json(&json)))
+        } else if name.ends_with(".xml") {
+            self.scan_oval(&String::from_utf8_lossy(&read(path)?))?;
+            Ok(Some("OVAL"))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn scan_json(&mut self, json: &Value) -> &'static str {
+        match json {
+            Value::Array(items) if items.iter().any(|item| item.get("packages").is_some()) => {
+                self.scan_arch_tracker(items);
+                "Arch Linux security tracker"
+            }
+            Value::Array(items) => {
+                for item in items {
+                    self.scan_osv(item);
+                }
+                "OSV"
+            }
+            Value::Object(fields) if fields.contains_key("affected") => {
+                self.scan_osv(json);
+                "OSV"
+            }
+            _ => {
+                self.scan_debian_tracker(json);
+                "Debian security tracker"
+            }
+        }
+    }
+
+    /// Installed packages called `name`, or built from a source package called `name`.
+    fn installed(&self, name: &str) -> Vec<&'a InstalledPackage> {
+        self.packages.get(name).cloned().unwrap_or_default()
+    }
+
+    fn record(&mut self, finding: VulnerabilityFinding) {
+        let key = (finding.id.clone(), finding.package.clone());
+        match self.findings.get(&key) {
+            Some(existing) if existing.severity >= finding.severity => {}
+            _ => {
+                self.findings.insert(key, finding);
+            }
+        }
+    }
+
+    /// An OSV record: https://ossf.github.io/osv-schema/
+    fn scan_osv(&mut self, record: &Value) {
+        let Some(osv_id) = record["id"].as_str() else {
+            return;
+        };
+        self.records += 1;
+
+        let mut aliases: Vec<String> = record["aliases"].as_array().into_iter().flatten()
+            .filter_map(|alias| alias.as_str().map(str::to_string))
+            .collect();
+        // Report the CVE id where there is one, as the other databases do.
+        let id = match aliases.iter().position(|alias| alias.starts_with("CVE-")) {
+            Some(index) if !osv_id.starts_with("CVE-") => {
+                let cve = aliases.remove(index);
+                aliases.push(osv_id.to_string());
+                cve
+            }
+            _ => osv_id.to_string(),
+        };
+        let summary = record["summary"].as_str().or(record["details"].as_str()).map(str::to_string);
+        let cvss_score = record["severity"].as_array().into_iter().flatten()
+            .filter(|severity| severity["type"].as_str() == Some("CVSS_V3"))
+            .filter_map(|severity| cvss3_base_score(severity["score"].as_str()?))
+            .fold(None, |max: Option<f64>, score| Some(max.map_or(score, |max| max.max(score))));
+
+        for affected in record["affected"].as_array().into_iter().flatten() {
+            let package = &affected["package"];
+            let (Some(ecosystem), Some(name)) = (package["ecosystem"].as_str(), package["name"].as_str()) else {
+                continue;
+            };
+            if !self.target.matches_ecosystem(ecosystem) {
+                continue;
+            }
+
+            let severity = cvss_score.map(VulnerabilitySeverity::from_cvss)
+                .or_else(|| text_severity(&affected["ecosystem_specific"]["urgency"]))
+                .or_else(|| text_severity(&affected["ecosystem_specific"]["severity"]))
+                .or_else(|| text_severity(&affected["database_specific"]["severity"]))
+                .or_else(|| record["severity"].as_array().into_iter().flatten()
+                    .find_map(|severity| text_severity(&severity["score"])))
+                .or_else(|| text_severity(&record["database_specific"]["severity"]))
+                .unwrap_or(VulnerabilitySeverity::Unknown);
+
+            for installed in self.installed(name) {
+                let Some(fixed_version) = self.osv_affects(affected, &installed.version) else {
+                    continue;
+                };
+                self.record(VulnerabilityFinding {
+                    id: id.clone(),
+                    aliases: aliases.clone(),
+                    package: installed.name.clone(),
+                    installed_version: installed.version.clone(),
+                    fixed_version,
+                    severity,
+                    cvss_score,
+                    summary: summary.clone(),
+                    source: self.source.clone(),
+                });
+            }
+        }
+    }
+
+    /// Whether `version` is affected according to an OSV `affected` entry;
+    /// `Some` holds the fixed version, if there is one.
+    fn osv_affects(&self, affected: &Value, version: &str) -> Option<Option<String>> {
+        let listed = affected["versions"].as_array().into_iter().flatten()
+            .any(|listed| listed.as_str() == Some(version));
+
+        let mut fixed_version = None;
+        let mut in_range = false;
+        for range in affected["ranges"].as_array().into_iter().flatten() {
+            if range["type"].as_str() != Some("ECOSYSTEM") {
+                continue;
+            }
+            // Walk the events in version order; `0` sorts before everything.
+            let mut events: Vec<(&str, &str)> = range["events"].as_array().into_iter().flatten()
+                .filter_map(|event| event.as_object()?.iter().next())
+                .filter_map(|(kind, value)| Some((kind.as_str(), value.as_str()?)))
+                .collect();
+            let event_order = |a: &str, b: &str| match (a, b) {
+                ("0", "0") => Ordering::Equal,
+                ("0", _) => Ordering::Less,
+                (_, "0") => Ordering::Greater,
+                _ => self.target.compare_versions(a, b),
+            };
+            events.sort_by(|a, b| event_order(a.1, b.1));
+
+            let mut affected_here = false;
+            for (kind, value) in events {
+                match kind {
+                    "introduced" if value == "0" || self.target.compare_versions(version, value) != Ordering::Less => {
+                        affected_here = true;
+                    }
+                    "fixed" if self.target.compare_versions(version, value) != Ordering::Less => {
+                        affected_here = false;
+                    }
+                    "fixed" if affected_here && fixed_version.is_none() => {
+                        fixed_version = Some(value.to_string());
+                    }
+                    "last_affected" if self.target.compare_versions(version, value) == Ordering::Greater => {
+                        affected_here = false;
+                    }
+                    _ => {}
+                }
+            }
+            in_range |= affected_here;
+        }
+
+        (listed || in_range).then_some(fixed_version)
+    }
+
+    /// The Debian security tracker's export: source package → CVE → release → status.
+    fn scan_debian_tracker(&mut self, tracker: &Value) {
+        let Some(sources) = tracker.as_object() else {
+            return;
+        };
+        for (source, issues) in sources {
+            let Some(issues) = issues.as_object() else {
+                continue;
+            };
+            self.records += issues.len();
+            if self.target.category != DistroCategory::Debian {
+                continue;
+            }
+            let installed = self.installed(source);
+            if installed.is_empty() {
+                continue;
+            }
+
+            for (id, issue) in issues {
+                let release = &issue["releases"][self.target.release.as_str()];
+                let fixed_version = match release["status"].as_str() {
+                    Some("open") | Some("undetermined") => None,
+                    Some("resolved") => match release["fixed_version"].as_str() {
+                        // Fixed before the release, or never affected it.
+                        Some("0") | None => continue,
+                        Some(fixed) => Some(fixed.to_string()),
+                    },
+                    _ => continue,
+                };
+                let severity = text_severity(&release["urgency"]).unwrap_or(VulnerabilitySeverity::Unknown);
+
+                for package in &installed {
+                    if let Some(fixed) = &fixed_version {
+                        if self.target.compare_versions(&package.version, fixed) != Ordering::Less {
+                            continue;
+                        }
+                    }
+                    self.record(VulnerabilityFinding {
+                        id: id.clone(),
+                        aliases: Vec::new(),
+                        package: package.name.clone(),
+                        installed_version: package.version.clone(),
+                        fixed_version: fixed_version.clone(),
+                        severity,
+                        cvss_score: None,
+                        summary: issue["description"].as_str().map(str::to_string),
+                        source: self.source.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// The Arch Linux security tracker's `issues/all.json`: one entry per
+    /// advisory group with the affected packages and versions.
+    fn scan_arch_tracker(&mut self, groups: &[Value]) {
+        for group in groups {
+            self.records += 1;
+            if self.target.package_manager != PackageManager::Pacman
+                || group["status"].as_str() == Some("Not affected")
+            {
+                continue;
+            }
+            let Some(affected) = group["affected"].as_str() else {
+                continue;
+            };
+            let fixed = group["fixed"].as_str();
+            let issues: Vec<String> = group["issues"].as_array().into_iter().flatten()
+                .filter_map(|issue| issue.as_str().map(str::to_string))
+                .collect();
+            let group_id = group["name"].as_str().unwrap_or_default().to_string();
+            let severity = text_severity(&group["severity"]).unwrap_or(VulnerabilitySeverity::Unknown);
+
+            for name in group["packages"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+                for package in self.installed(name) {
+                    let vulnerable = self.target.compare_versions(&package.version, affected) != Ordering::Less
+                        && fixed.is_none_or(|fixed| self.target.compare_versions(&package.version, fixed) == Ordering::Less);
+                    if !vulnerable {
+                        continue;
+                    }
+                    for id in issues.iter().chain(issues.is_empty().then_some(&group_id)) {
+                        self.record(VulnerabilityFinding {
+                            id: id.clone(),
+                            aliases: vec![group_id.clone()],
+                            package: package.name.clone(),
+                            installed_version: package.version.clone(),
+                            fixed_version: fixed.map(str::to_string),
+                            severity,
+                            cvss_score: None,
+                            summary: group["type"].as_str().map(str::to_string),
+                            source: self.source.clone(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// OVAL definitions as published by Red Hat, Ubuntu and Debian.
+    ///
+    /// A definition matches when one of its package version tests ("name is
+    /// earlier than version") matches an installed package; the other
+    /// criteria, such as which release is installed, are not evaluated, so
+    /// only OVAL files for the image's release should be supplied.
+    fn scan_oval(&mut self, xml: &str) -> Result<()> {
+        let oval = OvalDocument::parse(xml)?;
+        let test_kind = match self.target.package_manager {
+            PackageManager::Apt => "dpkginfo_test",
+            PackageManager::Dnf => "rpminfo_test",
+            PackageManager::Pacman => return Ok(()),
+        };
+
+        for definition in &oval.definitions {
+            self.records += 1;
+            let id = definition.references.iter()
+                .find(|reference| reference.starts_with("CVE-"))
+                .cloned()
+                .unwrap_or_else(|| definition.id.clone());
+            let aliases: Vec<String> = definition.references.iter()
+                .filter(|reference| **reference != id)
+                .cloned()
+                .collect();
+            let severity = definition.severity.as_deref()
+                .and_then(|severity| text_severity(&Value::from(severity)))
+                .unwrap_or(VulnerabilitySeverity::Unknown);
+
+            for test_ref in &definition.test_refs {
+                let Some(test) = oval.tests.get(test_ref).filter(|test| test.kind == test_kind) else {
+                    continue;
+                };
+                let Some(fixed) = test.state_ref.as_ref().and_then(|state| oval.states.get(state)) else {
+                    continue;
+                };
+                for name in oval.object_names(&test.object_ref) {
+                    for package in self.installed(name) {
+                        if package.name != name
+                            || self.target.compare_versions(&package.version, fixed) != Ordering::Less
+                        {
+                            continue;
+                        }
+                        self.record(VulnerabilityFinding {
+                            id: id.clone(),
+                            aliases: aliases.clone(),
+                            package: package.name.clone(),
+                            installed_version: package.version.clone(),
+                            fixed_version: Some(fixed.trim_start_matches("0:").to_string()),
+                            severity,
+                            cvss_score: None,
+                            summary: definition.title.clone(),
+                            source: self.source.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Maps the severity names databases use to a `VulnerabilitySeverity`.
+fn text_severity(value: &Value) -> Option<VulnerabilitySeverity> {
+    let text = value.as_str()?.trim().trim_end_matches('*').to_ascii_lowercase();
+    match text.as_str() {
+        "critical" => Some(VulnerabilitySeverity::Critical),
+        "high" | "important" => Some(VulnerabilitySeverity::High),
+        "medium" | "moderate" => Some(VulnerabilitySeverity::Medium),
+        "low" => Some(VulnerabilitySeverity::Low),
+        "negligible" | "unimportant" => Some(VulnerabilitySeverity::Negligible),
+        _ => None,
+    }
+}
+
+/// Base score of a CVSS v3.x vector such as `CVSS:3.1/AV:N/AC:L/...`.
+fn cvss3_base_score(vector: &str) -> Option<f64> {
+    let metrics: HashMap<&str, &str> = vector
+        .strip_prefix("CVSS:3.")?
+        .split('/')
+        .skip(1)
+        .filter_map(|metric| metric.split_once(':'))
+        .collect();
+    let changed = match *metrics.get("S")? {
+        "U" => false,
+        "C" => true,
+        _ => return None,
+    };
+
+    let attack_vector = match *metrics.get("AV")? { "N" => 0.85, "A" => 0.62, "L" => 0.55, "P" => 0.2, _ => return None };
+    let attack_complexity = match *metrics.get("AC")? { "L" => 0.77, "H" => 0.44, _ => return None };
+    let privileges = match (*metrics.get("PR")?, changed) {
+        ("N", _) => 0.85,
+        ("L", false) => 0.62,
+        ("L", true) => 0.68,
+        ("H", false) => 0.27,
+        ("H", true) => 0.5,
+        _ => return None,
+    };
+    let user_interaction = match *metrics.get("UI")? { "N" => 0.85, "R" => 0.62, _ => return None };
+    let impact_metric = |name: &str| match *metrics.get(name)? { "H" => Some(0.56), "L" => Some(0.22), "N" => Some(0.0), _ => None };
+    let (confidentiality, integrity, availability) = (impact_metric("C")?, impact_metric("I")?, impact_metric("A")?);
+
+    let base_impact = 1.0 - (1.0 - confidentiality) * (1.0 - integrity) * (1.0 - availability);
+    let impact = if changed {
+        7.52 * (base_impact - 0.029) - 3.25 * (base_impact - 0.02f64).powi(15)
+    } else {
+        6.42 * base_impact
+    };
+    if impact <= 0.0 {
+        return Some(0.0);
+    }
+    let exploitability = 8.22 * attack_vector * attack_complexity * privileges * user_interaction;
+    let score = if changed { 1.08 * (impact + exploitability) } else { impact + exploitability };
+
+    // CVSS rounds up to one decimal, avoiding floating point artefacts.
+    let scaled = (score.min(10.0) * 100_000.0).round() as u64;
+    Some(if scaled.is_multiple_of(10_000) { scaled as f64 / 100_000.0 } else { (scaled / 10_000 + 1) as f64 / 10.0 })
+}
+
+/// Splits `[epoch:]version[-release]`.
+fn split_evr(version: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) if epoch.chars().all(|c| c.is_ascii_digit()) => (epoch.parse().unwrap_or(0), rest),
+        _ => (0, version),
+    };
+    match rest.rsplit_once('-') {
+        Some((upstream, release)) => (epoch, upstream, release),
+        None => (epoch, rest, ""),
+    }
+}
+
+/// Compares Debian package versions like `dpkg --compare-versions`.
+fn compare_dpkg_versions(a: &str, b: &str) -> Ordering {
+    let (a_epoch, a_upstream, a_revision) = split_evr(a);
+    let (b_epoch, b_upstream, b_revision) = split_evr(b);
+    a_epoch.cmp(&b_epoch)
+        .then_with(|| dpkg_verrevcmp(a_upstream, b_upstream))
+        .then_with(|| dpkg_verrevcmp(a_revision, b_revision))
+}
+
+fn dpkg_verrevcmp(a: &str, b: &str) -> Ordering {
+    // `~` sorts before everything, even the end of the string; letters sort
+    // before other characters.
+    fn order(c: Option<u8>) -> i32 {
+        match c {
+            None => 0,
+            Some(b'~') => -1,
+            Some(c) if c.is_ascii_digit() => 0,
+            Some(c) if c.is_ascii_alphabetic() => c as i32,
+            Some(c) => c as i32 + 256,
+        }
+    }
+
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !a[i].is_ascii_digit()) || (j < b.len() && !b[j].is_ascii_digit()) {
+            let (ac, bc) = (order(a.get(i).copied()), order(b.get(j).copied()));
+            if ac != bc {
+                return ac.cmp(&bc);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        let digits = |s: &[u8], mut k: usize| {
+            while k < s.len() && s[k] == b'0' {
+                k += 1;
+            }
+            let start = k;
+            while k < s.len() && s[k].is_ascii_digit() {
+                k += 1;
+            }
+            (start, k)
+        };
+        let (a_start, a_end) = digits(a, i);
+        let (b_start, b_end) = digits(b, j);
+        let ordering = (a_end - a_start).cmp(&(b_end - b_start)).then_with(|| a[a_start..a_end].cmp(&b[b_start..b_end]));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        i = a_end;
+        j = b_end;
+    }
+    Ordering::Equal
+}
+
+/// Compares rpm (and pacman) versions like `rpmvercmp`, including epochs
+/// and releases.
+fn compare_rpm_versions(a: &str, b: &str) -> Ordering {
+    let (a_epoch, a_version, a_release) = split_evr(a);
+    let (b_epoch, b_version, b_release) = split_evr(b);
+    let release = if a_release.is_empty() || b_release.is_empty() {
+        // A version without a release matches every release of it.
+        Ordering::Equal
+    } else {
+        rpmvercmp(a_release, b_release)
+    };
+    a_epoch.cmp(&b_epoch)
+        .then_with(|| rpmvercmp(a_version, b_version))
+        .then(release)
+}
+
+fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    let separator = |c: char| !c.is_ascii_alphanumeric() && c != '~' && c != '^';
+    let (mut a, mut b) = (a, b);
+
+    loop {
+        a = a.trim_start_matches(separator);
+        b = b.trim_start_matches(separator);
+
+        // `~` sorts before everything, `^` after the end of the string.
+        for marker in ['~', '^'] {
+            match (a.starts_with(marker), b.starts_with(marker)) {
+                (true, true) => {
+                    a = &a[1..];
+                    b = &b[1..];
+                }
+                (true, false) if marker == '~' => return Ordering::Less,
+                (false, true) if marker == '~' => return Ordering::Greater,
+                (true, false) => return if b.is_empty() { Ordering::Greater } else { Ordering::Less },
+                (false, true) => return if a.is_empty() { Ordering::Less } else { Ordering::Greater },
+                (false, false) => {}
+            }
+        }
+        if a.is_empty() || b.is_empty() {
+            break;
+        }
+
+        let numeric = a.starts_with(|c: char| c.is_ascii_digit());
+        let in_segment = |c: char| if numeric { c.is_ascii_digit() } else { c.is_ascii_alphabetic() };
+        let a_end = a.find(|c: char| !in_segment(c)).unwrap_or(a.len());
+        let b_end = b.find(|c: char| !in_segment(c)).unwrap_or(b.len());
+        let (a_segment, b_segment) = (&a[..a_end], &b[..b_end]);
+
+        // A numeric segment is newer than an alphabetic one.
+        if b_segment.is_empty() {
+            return if numeric { Ordering::Greater } else { Ordering::Less };
+        }
+        let ordering = if numeric {
+            let (a_digits, b_digits) = (a_segment.trim_start_matches('0'), b_segment.trim_start_matches('0'));
+            a_digits.len().cmp(&b_digits.len()).then_with(|| a_digits.cmp(b_digits))
+        } else {
+            a_segment.cmp(b_segment)
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        a = &a[a_end..];
+        b = &b[b_end..];
+    }
+
+    match (a.is_empty(), b.is_empty()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        _ => Ordering::Greater,
+    }
+}
+
+#[derive(Debug, Default)]
+struct OvalDefinition {
+    id: String,
+    title: Option<String>,
+    severity: Option<String>,
+    references: Vec<String>,
+    test_refs: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct OvalTest {
+    /// Element name without namespace, e.g. `rpminfo_test`.
+    kind: String,
+    object_ref: String,
+    state_ref: Option<String>,
+}
+
+/// Package name of an OVAL object, given directly or through a variable.
+#[derive(Debug)]
+enum OvalName {
+    Literal(String),
+    Variable(String),
+}
+
+/// The parts of an OVAL document needed to match package versions.
+#[derive(Debug, Default)]
+struct OvalDocument {
+    definitions: Vec<OvalDefinition>,
+    tests: HashMap<String, OvalTest>,
+    objects: HashMap<String, OvalName>,
+    /// Versions from states that match when the package is `less than` them.
+    states: HashMap<String, String>,
+    variables: HashMap<String, Vec<String>>,
+}
+
+/// Element whose text the parser is collecting.
+enum OvalText {
+    Title,
+    Severity,
+    ObjectName(String),
+    StateEvr(String),
+    VariableValue(String),
+}
+
+impl OvalDocument {
+    fn parse(xml: &str) -> Result<Self> {
+        let mut document = Self::default();
+        let mut reader = Reader::from_str(xml);
+        reader.config_mut().trim_text(true);
+
+        let mut definition: Option<OvalDefinition> = None;
+        let mut test: Option<(String, OvalTest)> = None;
+        let mut object: Option<String> = None;
+        let mut state: Option<String> = None;
+        let mut variable: Option<String> = None;
+        let mut text: Option<OvalText> = None;
+
+        loop {
+            let event = reader.read_event()?;
+            let (element, is_empty) = match &event {
+                Event::Start(element) => (element, false),
+                Event::Empty(element) => (element, true),
+                Event::Text(content) => {
+                    let content = content.unescape()?;
+                    match (&text, definition.as_mut()) {
+                        (Some(OvalText::Title), Some(definition)) => definition.title = Some(content.into_owned()),
+                        (Some(OvalText::Severity), Some(definition)) => definition.severity = Some(content.into_owned()),
+                        (Some(OvalText::ObjectName(id)), _) => {
+                            document.objects.insert(id.clone(), OvalName::Literal(content.into_owned()));
+                        }
+                        (Some(OvalText::StateEvr(id)), _) => {
+                            document.states.insert(id.clone(), content.into_owned());
+                        }
+                        (Some(OvalText::VariableValue(id)), _) => {
+                            document.variables.entry(id.clone()).or_default().push(content.into_owned());
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+                Event::End(element) => {
+                    text = None;
+                    let name = String::from_utf8_lossy(element.local_name().as_ref()).into_owned();
+                    match name.as_str() {
+                        "definition" => document.definitions.extend(definition.take()),
+                        _ if name.ends_with("_test") => {
+                            if let Some((id, test)) = test.take() {
+                                document.tests.insert(id, test);
+                            }
+                        }
+                        _ if name.ends_with("_object") => object = None,
+                        _ if name.ends_with("_state") => state = None,
+                        "constant_variable" => variable = None,
+                        _ => {}
+                    }
+                    continue;
+                }
+                Event::Eof => break,
+                _ => continue,
+            };
+
+            let name = String::from_utf8_lossy(element.local_name().as_ref()).into_owned();
+            let attribute = |key: &str| attribute(element, key);
+            let mut collect = |target: OvalText| {
+                if !is_empty {
+                    text = Some(target);
+                }
+            };
+
+            match name.as_str() {
+                "definition" => {
+                    definition = Some(OvalDefinition { id: attribute("id").unwrap_or_default(), ..Default::default() });
+                }
+                "title" if definition.is_some() => collect(OvalText::Title),
+                "severity" if definition.is_some() => collect(OvalText::Severity),
+                "reference" => {
+                    if let (Some(definition), Some(reference)) = (definition.as_mut(), attribute("ref_id")) {
+                        definition.references.push(reference);
+                    }
+                }
+                "criterion" => {
+                    if let (Some(definition), Some(test_ref)) = (definition.as_mut(), attribute("test_ref")) {
+                        definition.test_refs.push(test_ref);
+                    }
+                }
+                "object" => {
+                    if let (Some((_, test)), Some(object_ref)) = (test.as_mut(), attribute("object_ref")) {
+                        test.object_ref = object_ref;
+                    }
+                }
+                "state" => {
+                    if let Some((_, test)) = test.as_mut() {
+                        test.state_ref = attribute("state_ref");
+                    }
+                }
+                "name" => {
+                    if let Some(id) = &object {
+                        match attribute("var_ref") {
+                            Some(var_ref) => {
+                                document.objects.insert(id.clone(), OvalName::Variable(var_ref));
+                            }
+                            None => collect(OvalText::ObjectName(id.clone())),
+                        }
+                    }
+                }
+                "evr" | "version" => {
+                    if let Some(id) = &state {
+                        if attribute("operation").as_deref() == Some("less than") {
+                            collect(OvalText::StateEvr(id.clone()));
+                        }
+                    }
+                }
+                "constant_variable" => variable = attribute("id"),
+                "value" => {
+                    if let Some(id) = &variable {
+                        collect(OvalText::VariableValue(id.clone()));
+                    }
+                }
+                _ if name.ends_with("_test") && !is_empty => {
+                    let id = attribute("id").unwrap_or_default();
+                    test = Some((id, OvalTest { kind: name.clone(), ..Default::default() }));
+                }
+                _ if name.ends_with("_object") && !is_empty => object = attribute("id"),
+                _ if name.ends_with("_state") && !is_empty => state = attribute("id"),
+                _ => {}
+            }
+        }
+
+        Ok(document)
+    }
+
+    fn object_names(&self, object_ref: &str) -> Vec<&str> {
+        match self.objects.get(object_ref) {
+            Some(OvalName::Literal(name)) => vec![name.as_str()],
+            Some(OvalName::Variable(var_ref)) => self.variables.get(var_ref)
+                .map(|values| values.iter().map(String::as_str).collect())
+                .unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+}
+
+fn attribute(element: &BytesStart, key: &str) -> Option<String> {
+    element.attributes()
+        .filter_map(|attribute| attribute.ok())
+        .find(|attribute| attribute.key.local_name().as_ref() == key.as_bytes())
+        .and_then(|attribute| attribute.unescape_value().ok().map(|value| value.into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_dpkg_versions() {
+        let less = [
+            ("1.0", "1.0.1"),
+            ("1.9", "1.10"),
+            ("2.3-1", "2.30-1"),
+            ("1.0~rc1", "1.0"),
+            ("1.0~~", "1.0~"),
+            ("1.0", "1.0a"),
+            ("1.0", "1.0+dfsg"),
+            ("1.0-1", "1.0-1ubuntu1"),
+            ("1.0-1~bpo12+1", "1.0-1"),
+            ("2.0", "1:0.1"),
+        ];
+        for (a, b) in less {
+            assert_eq!(compare_dpkg_versions(a, b), Ordering::Less, "{} < {}", a, b);
+            assert_eq!(compare_dpkg_versions(b, a), Ordering::Greater, "{} > {}", b, a);
+        }
+        assert_eq!(compare_dpkg_versions("1.0-0", "1.0"), Ordering::Equal);
+        assert_eq!(compare_dpkg_versions("0:1.01", "1.1"), Ordering::Equal);
+    }
+
+    #[test]
+    fn compares_like_rpmvercmp() {
+        // Cases from rpm's own rpmvercmp test suite.
+        let cases = [
+            ("1.0", "1.0", Ordering::Equal),
+            ("1.0", "2.0", Ordering::Less),
+            ("2.0.1", "2.0", Ordering::Greater),
+            ("2.0.1a", "2.0.1", Ordering::Greater),
+            ("5.5p1", "5.5p2", Ordering::Less),
+            ("5.5p10", "5.5p2", Ordering::Greater),
+            ("10xyz", "10.1xyz", Ordering::Less),
+            ("xyz10", "xyz10.1", Ordering::Less),
+            ("xyz.4", "8", Ordering::Less),
+            ("8", "xyz.4", Ordering::Greater),
+            ("5.5p1", "5.5.p1", Ordering::Equal),
+            ("2.0", "2_0", Ordering::Equal),
+            ("1.0~rc1", "1.0", Ordering::Less),
+            ("1.0~rc1", "1.0~rc2", Ordering::Less),
+            ("1.0~rc1~git123", "1.0~rc1", Ordering::Less),
+            ("1.0~rc1", "1.0arc1", Ordering::Less),
+            ("1.0^", "1.0", Ordering::Greater),
+            ("1.0^git1", "1.0", Ordering::Greater),
+            ("1.0^git1", "1.01", Ordering::Less),
+            ("1.0^git1", "1.0.1", Ordering::Less),
+            ("1.0^git1~pre", "1.0^git1", Ordering::Less),
+            ("1.0~rc1^git1", "1.0~rc1", Ordering::Greater),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(rpmvercmp(a, b), expected, "rpmvercmp({}, {})", a, b);
+        }
+    }
+
+    #[test]
+    fn compares_rpm_epochs_and_releases() {
+        assert_eq!(compare_rpm_versions("1:1.0-1", "2.0-1"), Ordering::Greater);
+        assert_eq!(compare_rpm_versions("1.0-2.el9", "1.0-10.el9"), Ordering::Less);
+        // A fixed version without a release covers every release of it.
+        assert_eq!(compare_rpm_versions("1.0-3.el9", "1.0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn scores_cvss3_vectors() {
+        let cases = [
+            ("CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:H/I:H/A:H", 9.8),
+            ("CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:C/C:H/I:H/A:H", 10.0),
+            ("CVSS:3.0/AV:N/AC:L/PR:N/UI:R/S:C/C:L/I:L/A:N", 6.1),
+            ("CVSS:3.1/AV:L/AC:L/PR:L/UI:N/S:U/C:H/I:H/A:H", 7.8),
+            ("CVSS:3.1/AV:N/AC:H/PR:N/UI:N/S:U/C:H/I:N/A:N", 5.9),
+            ("CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:N/I:N/A:N", 0.0),
+        ];
+        for (vector, score) in cases {
+            assert_eq!(cvss3_base_score(vector), Some(score), "{}", vector);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_cvss3_vectors() {
+        assert_eq!(cvss3_base_score("AV:N/AC:L/Au:N/C:P/I:P/A:P"), None);
+        assert_eq!(cvss3_base_score("CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:H/I:H"), None);
+        assert_eq!(cvss3_base_score("CVSS:3.1/AV:X/AC:L/PR:N/UI:N/S:U/C:H/I:H/A:H"), None);
+    }
+}
@@ -1,197 +1,212 @@
+use crate::events::log_payload;
 use crate::models::*;
 use crate::AppState;
 use axum::{
-    extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
-    },
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
     response::Response,
 };
-use futures::{sink::SinkExt, stream::StreamExt};
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use futures::{
+    sink::SinkExt,
+    stream::{SplitSink, StreamExt},
+};
+use tokio::sync::broadcast::error::RecvError;
 use tracing::{info, error, warn};
 use uuid::Uuid;
 
 pub async fn handle_websocket(
+    ws: WebSocketUpgrade,
     state: AppState,
     job_id: Uuid,
+    log_offset: usize,
 ) -> Result<Response, axum::http::StatusCode> {
-    info!("WebSocket connection requested for job {}", job_id);
-
-    let handler = WebSocketUpgrade::from_request(())
-        .protocol_callback(|protocols| {
-            protocols
-                .iter()
-                .find(|p| p == "iso-creator")
-                .map(String::from)
-        })
-        .on_upgrade(move |socket| handle_socket(socket, state, job_id));
-
-    Ok(handler.into_response())
+    info!("WebSocket connection requested for job {} (log offset {})", job_id, log_offset);
+
+    let handler = ws
+        .protocols(["iso-creator"])
+        .on_upgrade(move |socket| handle_socket(socket, state, job_id, log_offset));
+
+    Ok(handler)
 }
 
-async fn handle_socket(socket: WebSocket, state: AppState, job_id: Uuid) {
+async fn handle_socket(socket: WebSocket, state: AppState, job_id: Uuid, log_offset: usize) {
     info!("WebSocket connection established for job {}", job_id);
 
     let (mut sender, mut receiver) = socket.split();
-    let jobs = state.jobs.clone();
 
-    // Send initial job status
-    if let Ok(job) = jobs.read().await.get(&job_id).cloned() {
-        if let Ok(message) = serde_json::to_string(&WebSocketMessage {
-            job_id,
-            type_: MessageType::StatusUpdate,
-            data: serde_json::json!({
-                "status": job.status,
-                "progress": job.progress,
-            }),
-        }) {
-            if let Err(e) = sender.send(Message::Text(message)).await {
-                error!("Failed to send initial status: {}", e);
-                return;
-            }
+    // Subscribe before reading the job so nothing published in between is lost;
+    // duplicated log lines are filtered out by their index below.
+    let mut events = state.events.subscribe(job_id);
+    let mut next_log = log_offset;
+
+    match replay(&mut sender, &state, job_id, &mut next_log).await {
+        Ok(false) => {}
+        Ok(true) => {
+            // The job already finished, so no further events will arrive.
+            state.events.close(job_id);
+            let _ = sender.close().await;
+            return;
+        }
+        Err(e) => {
+            error!("Failed to send initial job state: {}", e);
+            return;
         }
     }
 
-    // Spawn a task to monitor job updates
-    let job_id_clone = job_id;
-    let jobs_clone = jobs.clone();
-    let sender_clone = sender.clone();
-    
-    tokio::spawn(async move {
-        let mut last_status = None;
-        let mut last_progress = 0;
-        
-        loop {
-            tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-            
-            let job = {
-                let jobs = jobs_clone.read().await;
-                jobs.get(&job_id_clone).cloned()
-            };
-            
-            if let Some(job) = job {
-                // Check if status changed
-                if last_status.as_ref() != Some(&job.status) {
-                    if let Ok(message) = serde_json::to_string(&WebSocketMessage {
-                        job_id: job_id_clone,
-                        type_: MessageType::StatusUpdate,
-                        data: serde_json::json!({
-                            "status": job.status,
-                        }),
-                    }) {
-                        if let Err(e) = sender_clone.send(Message::Text(message)).await {
-                            error!("Failed to send status update: {}", e);
-                            break;
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(message) => {
+                    if matches!(message.type_, MessageType::LogMessage) {
+                        let index = message.data["index"].as_u64().unwrap_or(0) as usize;
+                        if index < next_log {
+                            continue;
                         }
+                        next_log = index + 1;
                     }
-                    last_status = Some(job.status.clone());
-                }
-                
-                // Check if progress changed
-                if last_progress != job.progress {
-                    if let Ok(message) = serde_json::to_string(&WebSocketMessage {
-                        job_id: job_id_clone,
-                        type_: MessageType::ProgressUpdate,
-                        data: serde_json::json!({
-                            "progress": job.progress,
-                        }),
-                    }) {
-                        if let Err(e) = sender_clone.send(Message::Text(message)).await {
-                            error!("Failed to send progress update: {}", e);
-                            break;
-                        }
+
+                    if let Err(e) = send_message(&mut sender, &message).await {
+                        error!("Failed to send job event: {}", e);
+                        break;
                     }
-                    last_progress = job.progress;
                 }
-                
-                // Send new log messages
-                if let Some(last_log) = job.logs.last() {
-                    if let Ok(message) = serde_json::to_string(&WebSocketMessage {
-                        job_id: job_id_clone,
-                        type_: MessageType::LogMessage,
-                        data: serde_json::json!({
-                            "timestamp": last_log.timestamp,
-                            "level": last_log.level,
-                            "message": last_log.message,
-                        }),
-                    }) {
-                        if let Err(e) = sender_clone.send(Message::Text(message)).await {
-                            error!("Failed to send log message: {}", e);
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("WebSocket for job {} lagged by {} events, resyncing", job_id, skipped);
+                    match replay(&mut sender, &state, job_id, &mut next_log).await {
+                        Ok(false) => {}
+                        Ok(true) => break,
+                        Err(e) => {
+                            error!("Failed to resync job state: {}", e);
                             break;
                         }
                     }
                 }
-                
-                // Send completion message
-                if matches!(job.status, BuildStatus::Completed) {
-                    if let Ok(message) = serde_json::to_string(&WebSocketMessage {
-                        job_id: job_id_clone,
-                        type_: MessageType::Completed,
-                        data: serde_json::json!({
-                            "download_url": job.download_url,
-                        }),
-                    }) {
-                        if let Err(e) = sender_clone.send(Message::Text(message)).await {
-                            error!("Failed to send completion message: {}", e);
+                Err(RecvError::Closed) => break,
+            },
+            msg = receiver.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(ClientMessage::Cancel) => {
+                            info!("Cancellation requested over WebSocket for job {}", job_id);
+                            if !state.scheduler.cancel(job_id).await {
+                                warn!("Job {} is not running, ignoring cancel", job_id);
+                            }
                         }
+                        Err(_) => info!("Received text message: {}", text),
                     }
-                    break;
                 }
-                
-                // Send error message
-                if matches!(job.status, BuildStatus::Failed) {
-                    if let Ok(message) = serde_json::to_string(&WebSocketMessage {
-                        job_id: job_id_clone,
-                        type_: MessageType::Error,
-                        data: serde_json::json!({
-                            "error": "Build failed",
-                        }),
-                    }) {
-                        if let Err(e) = sender_clone.send(Message::Text(message)).await {
-                            error!("Failed to send error message: {}", e);
-                        }
+                Some(Ok(Message::Binary(bin))) => {
+                    info!("Received binary message: {} bytes", bin.len());
+                }
+                Some(Ok(Message::Ping(ping))) => {
+                    if let Err(e) = sender.send(Message::Pong(ping)).await {
+                        error!("Failed to send pong: {}", e);
+                        break;
                     }
+                }
+                Some(Ok(Message::Pong(_))) => {
+                    // Handle pong response
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    info!("WebSocket connection closed by client");
                     break;
                 }
-            } else {
-                // Job no longer exists
-                warn!("Job {} no longer exists, closing WebSocket", job_id_clone);
-                break;
-            }
-        }
-    });
-
-    // Handle incoming messages (ping/pong, etc.)
-    while let Some(msg) = receiver.next().await {
-        match msg {
-            Ok(Message::Text(text)) => {
-                info!("Received text message: {}", text);
-                // Handle client messages if needed
-            }
-            Ok(Message::Binary(bin)) => {
-                info!("Received binary message: {} bytes", bin.len());
-            }
-            Ok(Message::Ping(ping)) => {
-                if let Err(e) = sender.send(Message::Pong(ping)).await {
-                    error!("Failed to send pong: {}", e);
+                Some(Err(e)) => {
+                    error!("WebSocket error: {}", e);
                     break;
                 }
+            },
+        }
+    }
+
+    let _ = sender.close().await;
+    info!("WebSocket connection closed for job {}", job_id);
+}
+
+/// Sends the job's current status and every log entry from `next_log` onwards.
+///
+/// Returns `true` when the job has already finished, after sending the
+/// matching `Completed` or `Error` message (cancelled jobs only get the
+/// status update).
+async fn replay(
+    sender: &mut SplitSink<WebSocket, Message>,
+    state: &AppState,
+    job_id: Uuid,
+    next_log: &mut usize,
+) -> Result<bool, axum::Error> {
+    let mut messages = Vec::new();
+    let finished;
+
+    {
+        let job = match state.jobs.get(job_id).await {
+            Ok(Some(job)) => job,
+            Ok(None) => {
+                warn!("Job {} no longer exists, closing WebSocket", job_id);
+                return Ok(true);
             }
-            Ok(Message::Pong(_)) => {
-                // Handle pong response
-            }
-            Ok(Message::Close(_)) => {
-                info!("WebSocket connection closed by client");
-                break;
+            Err(e) => return Err(axum::Error::new(e)),
+        };
+
+        messages.push(WebSocketMessage {
+            job_id,
+            type_: MessageType::StatusUpdate,
+            data: serde_json::json!({
+                "status": job.status,
+                "progress": job.progress,
+                "queue_position": job.queue_position,
+            }),
+        });
+
+        for (index, log) in job.logs.iter().enumerate().skip(*next_log) {
+            messages.push(WebSocketMessage {
+                job_id,
+                type_: MessageType::LogMessage,
+                data: log_payload(index, log),
+            });
+        }
+        *next_log = (*next_log).max(job.logs.len());
+
+        finished = match job.status {
+            BuildStatus::Completed => {
+                messages.push(WebSocketMessage {
+                    job_id,
+                    type_: MessageType::Completed,
+                    data: serde_json::json!({
+                        "download_url": job.download_url,
+                    }),
+                });
+                true
             }
-            Err(e) => {
-                error!("WebSocket error: {}", e);
-                break;
+            BuildStatus::Failed => {
+                let error = job.logs.iter()
+                    .rev()
+                    .find(|log| matches!(log.level, LogLevel::Error))
+                    .map(|log| log.message.clone())
+                    .unwrap_or_else(|| "Build failed".to_string());
+                messages.push(WebSocketMessage {
+                    job_id,
+                    type_: MessageType::Error,
+                    data: serde_json::json!({
+                        "error": error,
+                    }),
+                });
+                true
             }
-        }
+            BuildStatus::Cancelled => true,
+            _ => false,
+        };
     }
 
-    info!("WebSocket connection closed for job {}", job_id);
+    for message in &messages {
+        send_message(sender, message).await?;
+    }
+
+    Ok(finished)
+}
+
+async fn send_message(
+    sender: &mut SplitSink<WebSocket, Message>,
+    message: &WebSocketMessage,
+) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(message).map_err(axum::Error::new)?;
+    sender.send(Message::Text(text)).await
 }
@@ -101,7 +101,8 @@ pub fn BuildProgress(
                                              class:bg-green-500=move || matches!(status, BuildStatus::Completed)
                                              class:bg-yellow-500=move || matches!(status, BuildStatus::Building | BuildStatus::Packaging | BuildStatus::Uploading)
                                              class:bg-blue-500=move || matches!(status, BuildStatus::Queued)
-                                             class:bg-red-500=move || matches!(status, BuildStatus::Failed)>
+                                             class:bg-red-500=move || matches!(status, BuildStatus::Failed)
+                                             class:bg-gray-500=move || matches!(status, BuildStatus::Cancelled)>
                                         </div>
                                         <span class="text-white">
                                             {match status {
@@ -111,6 +112,7 @@ pub fn BuildProgress(
                                                 BuildStatus::Uploading => "Uploading",
                                                 BuildStatus::Completed => "Completed",
                                                 BuildStatus::Failed => "Failed",
+                                                BuildStatus::Cancelled => "Cancelled",
                                             }}
                                         </span>
                                     </div>
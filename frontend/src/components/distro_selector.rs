@@ -77,6 +77,7 @@ pub fn DistroSelector(
                                                     DistroCategory::Debian => "Debian-based", 
                                                     DistroCategory::Arch => "Arch-based",
                                                     DistroCategory::Fedora => "Fedora-based",
+                                                    DistroCategory::Rocky => "Rocky-based",
                                                     DistroCategory::Custom => "Custom",
                                                 }}
                                             </span>
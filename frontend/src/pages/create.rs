@@ -17,7 +17,7 @@ pub fn CreatePage() -> impl IntoView {
         },
     });
     let (packages, set_packages) = create_signal(Vec::<String>::new());
-    let (custom_scripts, set_custom_scripts) = create_signal(Vec::<String>::new());
+    let (custom_scripts, set_custom_scripts) = create_signal(Vec::<CustomScript>::new());
     let (current_step, set_current_step) = create_signal(1);
     let (is_building, set_is_building) = create_signal(false);
     let (build_job, set_build_job) = create_signal(None::<BuildJob>);
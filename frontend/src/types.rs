@@ -20,6 +20,7 @@ pub enum DistroCategory {
     Debian,
     Arch,
     Fedora,
+    Rocky,
     Custom,
 }
 
@@ -29,12 +30,35 @@ pub struct IsoConfig {
     pub name: String,
     pub distro: DistroTemplate,
     pub packages: Vec<String>,
-    pub custom_scripts: Vec<String>,
+    pub custom_scripts: Vec<CustomScript>,
     pub desktop_environment: Option<String>,
     pub theme: ThemeConfig,
     pub created_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CustomScript {
+    pub name: Option<String>,
+    pub phase: ScriptPhase,
+    pub interpreter: Option<ScriptInterpreter>,
+    pub order: i32,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ScriptPhase {
+    PreInstall,
+    PostInstall,
+    FirstBoot,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ScriptInterpreter {
+    Sh,
+    Bash,
+    Python3,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ThemeConfig {
     pub wallpaper: Option<String>,
@@ -71,6 +95,7 @@ pub enum BuildStatus {
     Uploading,
     Completed,
     Failed,
+    Cancelled,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]